@@ -0,0 +1,203 @@
+//! Probe handling latency under heavy interface polling.
+//!
+//! Compares storage operations made by receiver on every probe
+//! while several interface clients keep requesting the full map:
+//! - `global mutex`: whole storage behind single lock, as it was before;
+//! - `concurrent`: sharded storage with snapshots for readers.
+//!
+//! Run with `cargo bench`.
+
+extern crate netloc;
+extern crate rand;
+
+use std::collections::HashSet;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rand::{seq, Isaac64Rng};
+
+use netloc::agent::{NodeCoordinates, NodeInfo, NodeList, GOSSIP_MAX_NEIGHBOURS_IN_MSG};
+use netloc::storage::{now_sec, Node, Storage};
+
+const NUM_NODES: usize = 5000;
+const NUM_READERS: usize = 4;
+const NUM_PROBES: usize = 20000;
+
+fn node_info(i: usize) -> NodeInfo {
+    let ip = IpAddr::V4(Ipv4Addr::new(10, (i >> 16) as u8, (i >> 8) as u8, i as u8));
+    let mut info = NodeInfo::new(ip, 5001, format!("node-{}", i));
+    info.set_coordinates(&NodeCoordinates {
+        x1: i as f32,
+        iteration: 1,
+        ..NodeCoordinates::empty()
+    });
+    info
+}
+
+/// Storage as it was before sharding: single table,
+/// the whole of it is locked by every reader and writer.
+struct GlobalLockStorage {
+    location: NodeCoordinates,
+    nodes: HashSet<Node>,
+    rng: Isaac64Rng,
+}
+
+impl GlobalLockStorage {
+    fn new() -> Self {
+        GlobalLockStorage {
+            location: NodeCoordinates::empty(),
+            nodes: HashSet::new(),
+            rng: Isaac64Rng::new_unseeded(),
+        }
+    }
+
+    fn add_node(&mut self, info: NodeInfo) {
+        let iteration = info.location.iteration;
+        let record = Node {
+            info,
+            last_updated_sec: now_sec(),
+        };
+
+        if let Some(saved) = self.nodes.get(&record) {
+            if saved.info.location.iteration >= iteration {
+                return;
+            }
+        }
+        self.nodes.replace(record);
+    }
+
+    fn get_random_nodes(&mut self, max_nodes: usize, ignore: &[SocketAddr]) -> NodeList {
+        let candidates: Vec<&Node> = self
+            .nodes
+            .iter()
+            .filter(|n| !ignore.contains(&n.addr()))
+            .collect();
+        let amount = max_nodes.min(candidates.len());
+
+        seq::sample_slice_ref(&mut self.rng, &candidates, amount)
+            .iter()
+            .map(|n| n.info.clone())
+            .collect()
+    }
+
+    fn get_all_nodes(&self) -> NodeList {
+        self.nodes.iter().map(|n| n.info.clone()).collect()
+    }
+}
+
+fn filled_storage() -> Storage {
+    let store = Storage::new();
+    (0..NUM_NODES).for_each(|i| store.add_node(node_info(i)));
+    store
+}
+
+fn filled_global_lock_storage() -> GlobalLockStorage {
+    let mut store = GlobalLockStorage::new();
+    (0..NUM_NODES).for_each(|i| store.add_node(node_info(i)));
+    store
+}
+
+fn probe_info(i: usize) -> (SocketAddr, NodeInfo) {
+    let mut info = node_info(i % NUM_NODES);
+    info.location.iteration = i as u64 + 2;
+    (SocketAddr::new(info.ip, info.port), info)
+}
+
+/// Emulate receiver's work on the incoming probe request
+fn handle_probe(store: &Storage, i: usize) {
+    let (sender, info) = probe_info(i);
+    let _location = store.get_location();
+    let _neighbours = store.get_random_nodes(GOSSIP_MAX_NEIGHBOURS_IN_MSG, &[sender]);
+    store.add_node(info);
+}
+
+/// The same work, holding the global lock for all of it
+fn handle_probe_global_lock(store: &Mutex<GlobalLockStorage>, i: usize) {
+    let (sender, info) = probe_info(i);
+    let mut store = store.lock().unwrap();
+    let _location = store.location.clone();
+    let _neighbours = store.get_random_nodes(GOSSIP_MAX_NEIGHBOURS_IN_MSG, &[sender]);
+    store.add_node(info);
+}
+
+fn report(name: &str, mut latencies: Vec<Duration>) {
+    latencies.sort();
+    let pct = |p: usize| latencies[(latencies.len() - 1) * p / 1000];
+    println!(
+        "{:>14}: p50 {:>10?} | p99 {:>10?} | p99.9 {:>10?} | max {:>10?}",
+        name,
+        pct(500),
+        pct(990),
+        pct(999),
+        latencies[latencies.len() - 1]
+    );
+}
+
+fn run<R, P>(name: &str, read_full_map: R, probe: P)
+where
+    R: Fn() + Send + Sync + 'static,
+    P: Fn(usize),
+{
+    let read_full_map = Arc::new(read_full_map);
+    let stop = Arc::new(AtomicBool::new(false));
+
+    let readers: Vec<_> = (0..NUM_READERS)
+        .map(|_| {
+            let read_full_map = read_full_map.clone();
+            let stop = stop.clone();
+            thread::spawn(move || {
+                while !stop.load(Ordering::Relaxed) {
+                    read_full_map();
+                }
+            })
+        })
+        .collect();
+
+    let latencies = (0..NUM_PROBES)
+        .map(|i| {
+            let started = Instant::now();
+            probe(i);
+            started.elapsed()
+        })
+        .collect();
+
+    stop.store(true, Ordering::Relaxed);
+    readers.into_iter().for_each(|r| r.join().unwrap());
+
+    report(name, latencies);
+}
+
+fn main() {
+    println!(
+        "probe handling latency: {} nodes, {} full map readers, {} probes",
+        NUM_NODES, NUM_READERS, NUM_PROBES
+    );
+
+    // whole table cloned under the single lock
+    let locked = Arc::new(Mutex::new(filled_global_lock_storage()));
+    {
+        let reader_store = locked.clone();
+        run(
+            "global mutex",
+            move || {
+                let _nodes = reader_store.lock().unwrap().get_all_nodes();
+            },
+            |i| handle_probe_global_lock(&locked, i),
+        );
+    }
+
+    let shared = Arc::new(filled_storage());
+    {
+        let reader_store = shared.clone();
+        run(
+            "concurrent",
+            move || {
+                let _nodes = reader_store.get_all_nodes();
+            },
+            |i| handle_probe(&shared, i),
+        );
+    }
+}
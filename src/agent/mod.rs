@@ -11,131 +11,97 @@
 //!
 //! NB: there must be ONLY ONE landmark agent in the network!!!
 //!
+mod node;
 mod receiver;
+mod reload;
+mod signals;
 mod transmitter;
 mod proto;
+pub mod selector;
 pub mod vivaldi;
 
 pub use self::proto::*;
+pub use self::node::{Agent, AgentBuilder, AgentHandle, ShutdownHook};
+pub use self::node::{PROBE_PERIOD_DEFAULT_SEC, SHUTDOWN_TIMEOUT_DEFAULT_SEC};
+pub use self::reload::{ReloadHook, ReloadReport, Reloader, SharedReloader};
+pub use self::transmitter::Control;
 
-use super::storage::Storage;
+use super::config::{ConfigError, ConfigErrors};
+use super::error::{Error, Result};
 use super::interface;
-use self::transmitter::Transmitter;
-use self::receiver::Receiver;
+use super::persist::PersistConfig;
+use self::selector::PeerSelection;
 
 use log;
-use std::io;
-use std::thread;
-use std::sync::{Arc, Mutex};
-use std::net::{IpAddr, SocketAddr, UdpSocket};
+use std::net::{IpAddr, SocketAddr};
 use std::time::Duration;
 
+/// Default number of random neighbours shared in probes
 pub const GOSSIP_MAX_NEIGHBOURS_IN_MSG: usize = 4;
+/// Probe with neighbours of short names should fit into typical MTU
+pub const GOSSIP_SIZE_MAX: usize = 16;
 pub const LANDMARK_NODE_NAME: &str = "landmark";
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum NodeType {
     Regular,
     Landmark,
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct NodeConfig {
     pub node_addr: IpAddr,
     pub node_port: u16,
     pub node_name: String,
     pub probe_period: Option<Duration>,
     pub interface_addr: Option<SocketAddr>,
+    pub http_addr: Option<SocketAddr>,
+    pub admin_addr: Option<SocketAddr>,
+    pub unix_socket: Option<interface::UnixSocketConfig>,
+    pub auth_token: Option<interface::Token>,
+    pub tls: Option<interface::TlsConfig>,
     pub landmark_addr: Option<SocketAddr>,
+    pub peer_selection: PeerSelection,
+    pub gossip_size: usize,
+    pub vivaldi: vivaldi::Params,
+    pub persist: Option<PersistConfig>,
+    pub shutdown_timeout: Duration,
     pub log_level: log::Level,
 }
 
-pub fn run_agent(config: &NodeConfig) -> io::Result<()> {
+/// Run agent until failure or SIGINT/SIGTERM, reloading config on SIGHUP or admin request
+pub fn run_agent<F>(config: &NodeConfig, reload: F) -> Result<()>
+where
+    F: FnMut() -> Result<NodeConfig> + Send + 'static,
+{
     check_interface_addr(config)?;
-
-    let node_name = config.node_name.clone();
-
-    // shared parameters
-    let sock = UdpSocket::bind((config.node_addr, config.node_port))?;
-    let store = Arc::new(Mutex::new(Storage::new()));
-
-    // run transmitter in separate thread
-    let _tx_thread = {
-        let node_name = node_name.clone();
-        let store = store.clone();
-        let sock = sock.try_clone().expect("cannot clone socket");
-        let period = config
-            .probe_period
-            .expect("probe period not specified")
-            .clone();
-        let landmark_addr = config.landmark_addr.unwrap().clone();
-
-        thread::spawn(move || {
-            let t = Transmitter::new(node_name, landmark_addr, store, sock, period);
-
-            if let Err(e) = t.run() {
-                panic!("agent-transmitter failure: {}", e);
-            }
-        })
-    };
-
-    // run receiver in separate thread
-    let rx_thread = {
-        let node_name = node_name.clone();
-        let store = store.clone();
-        let sock = sock.try_clone().expect("cannot clone socket");
-        let landmark = config.landmark_addr.clone();
-
-        thread::spawn(move || {
-            let r = Receiver::new(NodeType::Regular, node_name, store, sock, landmark);
-            if let Err(e) = r.run() {
-                panic!("agent-receiver failure: {}", e);
-            }
-        })
-    };
-
-    // run interface server
-    interface::run_server(config.interface_addr.unwrap(), store);
-
-    Ok(())
+    AgentBuilder::from_config(config.clone(), NodeType::Regular)
+        .handle_signals()
+        .on_reload(reload)
+        .spawn()?
+        .wait()
 }
 
-pub fn run_landmark(config: &NodeConfig) -> io::Result<()> {
+/// Run landmark until failure or SIGINT/SIGTERM, reloading config on SIGHUP or admin request
+pub fn run_landmark<F>(config: &NodeConfig, reload: F) -> Result<()>
+where
+    F: FnMut() -> Result<NodeConfig> + Send + 'static,
+{
     check_interface_addr(config)?;
-
-    let mut store = Storage::new();
-    store.set_location(NodeCoordinates {
-        pos_err: 0.0,
-        ..Default::default()
-    });
-    let store = Arc::new(Mutex::new(store));
-
-    // run receiver in separate thread
-    let rx_thread = {
-        let store = store.clone();
-        let sock = UdpSocket::bind((config.node_addr, config.node_port))?;
-        let name = config.node_name.clone();
-
-        thread::spawn(move || {
-            let r = Receiver::new(NodeType::Landmark, name, store, sock, None);
-            if let Err(e) = r.run() {
-                panic!("node failure: {}", e);
-            }
-        })
-    };
-
-    // run interface server
-    interface::run_server(config.interface_addr.unwrap(), store);
-
-    Ok(())
+    AgentBuilder::from_config(config.clone(), NodeType::Landmark)
+        .handle_signals()
+        .on_reload(reload)
+        .spawn()?
+        .wait()
 }
 
 
-fn check_interface_addr(config: &NodeConfig) -> io::Result<()> {
+fn check_interface_addr(config: &NodeConfig) -> Result<()> {
     match config.interface_addr {
         Some(_) => Ok(()),
-        None => Err(io::Error::new(
-            io::ErrorKind::Other,
-            "bad interface address provided",
-        )),
+        None => Err(Error::Config(ConfigErrors(vec![ConfigError {
+            path: "interface.addr".to_string(),
+            reason: "interface address is required".to_string(),
+        }]))),
     }
 }
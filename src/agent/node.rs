@@ -0,0 +1,638 @@
+/// Embeddable node
+///
+/// Node is configured with `AgentBuilder` and runs in a background thread
+/// until `AgentHandle` is shut down. Receiver, transmitter, interface
+/// listeners and state saver timer are tasks of a single async runtime,
+/// talking to each other through channels. State itself is written by
+/// a separate thread, so disk I/O doesn't delay probes. Failures are
+/// returned as errors, either on spawn or when the thread is joined.
+///
+/// Stopping the node ends probing first, then agent announces its leave
+/// to the landmark and some known nodes, interface connections are
+/// drained within the shutdown timeout, state is saved and shutdown hooks
+/// are run. Optionally the node stops on SIGINT/SIGTERM by itself and
+/// reloads configuration on SIGHUP.
+///
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::{Arc, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use futures::future::{self, Either};
+use futures::sync::{mpsc, oneshot};
+use tokio::prelude::*;
+use tokio::reactor::Handle;
+use tokio::runtime::current_thread::Runtime;
+use tokio::timer::Delay;
+
+use config::check_intervals;
+use error::{Error, Result};
+use interface::{self, AdminConfig, InterfaceConfig, Server, SharedToken};
+use persist::{self, PersistConfig};
+use status::{self, NodeKind, StatusReport};
+use storage::{SharedStorage, Storage};
+use super::proto::{MsgType, NodeCoordinates, NodeList};
+use super::receiver::Receiver;
+use super::reload::{ReloadHook, ReloadReport, Reloader, SharedReloader};
+use super::selector::PeerSelection;
+use super::signals::{self, Signal};
+use super::transmitter::{Control, Transmitter};
+use super::vivaldi;
+use super::{NodeConfig, NodeType, GOSSIP_MAX_NEIGHBOURS_IN_MSG, LANDMARK_NODE_NAME};
+
+pub const PROBE_PERIOD_DEFAULT_SEC: u64 = 20;
+pub const SHUTDOWN_TIMEOUT_DEFAULT_SEC: u64 = 5;
+/// Known nodes told about leave besides landmark, others forget node by gossip
+const LEAVE_NOTICE_MAX_NODES: usize = 32;
+
+const ERR_NO_LANDMARK: &str = "landmark address not specified";
+const ERR_NOT_SUPPORTED: &str = "not supported by landmark";
+const ERR_THREAD_PANICKED: &str = "node thread panicked";
+const ERR_NODE_STOPPED: &str = "node stopped";
+
+/// Called after node is stopped and its state is saved
+pub type ShutdownHook = Box<dyn FnOnce() + Send>;
+
+/// Entry point of the embedding API
+pub struct Agent;
+
+impl Agent {
+    /// Builder of the regular agent
+    pub fn builder() -> AgentBuilder {
+        AgentBuilder::from_config(NodeConfig::default(), NodeType::Regular)
+    }
+
+    /// Builder of the landmark, which is the only node with fixed zero coordinates
+    pub fn landmark_builder() -> AgentBuilder {
+        let config = NodeConfig {
+            node_name: LANDMARK_NODE_NAME.to_string(),
+            ..NodeConfig::default()
+        };
+        AgentBuilder::from_config(config, NodeType::Landmark)
+    }
+}
+
+/// Node configuration, interface listeners are disabled unless set
+pub struct AgentBuilder {
+    config: NodeConfig,
+    node_type: NodeType,
+    shutdown_hooks: Vec<ShutdownHook>,
+    handle_signals: bool,
+    reload: Option<ReloadHook>,
+}
+
+impl AgentBuilder {
+    /// Builder with all settings taken from config
+    pub fn from_config(config: NodeConfig, node_type: NodeType) -> Self {
+        AgentBuilder {
+            config,
+            node_type,
+            shutdown_hooks: Vec::new(),
+            handle_signals: false,
+            reload: None,
+        }
+    }
+
+    /// UDP address of the node, random port of all interfaces by default
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.config.node_addr = addr.ip();
+        self.config.node_port = addr.port();
+        self
+    }
+
+    pub fn name(mut self, name: &str) -> Self {
+        self.config.node_name = name.to_string();
+        self
+    }
+
+    /// Landmark to bootstrap from, required for agents
+    pub fn landmark(mut self, addr: SocketAddr) -> Self {
+        self.config.landmark_addr = Some(addr);
+        self
+    }
+
+    pub fn probe_period(mut self, period: Duration) -> Self {
+        self.config.probe_period = Some(period);
+        self
+    }
+
+    pub fn peer_selection(mut self, selection: PeerSelection) -> Self {
+        self.config.peer_selection = selection;
+        self
+    }
+
+    /// Number of random neighbours shared in probes
+    pub fn gossip_size(mut self, size: usize) -> Self {
+        self.config.gossip_size = size;
+        self
+    }
+
+    pub fn vivaldi(mut self, params: vivaldi::Params) -> Self {
+        self.config.vivaldi = params;
+        self
+    }
+
+    pub fn persist(mut self, config: PersistConfig) -> Self {
+        self.config.persist = Some(config);
+        self
+    }
+
+    pub fn interface(mut self, addr: SocketAddr) -> Self {
+        self.config.interface_addr = Some(addr);
+        self
+    }
+
+    pub fn http(mut self, addr: SocketAddr) -> Self {
+        self.config.http_addr = Some(addr);
+        self
+    }
+
+    pub fn admin(mut self, addr: SocketAddr) -> Self {
+        self.config.admin_addr = Some(addr);
+        self
+    }
+
+    pub fn unix_socket(mut self, config: interface::UnixSocketConfig) -> Self {
+        self.config.unix_socket = Some(config);
+        self
+    }
+
+    pub fn auth_token(mut self, token: interface::Token) -> Self {
+        self.config.auth_token = Some(token);
+        self
+    }
+
+    pub fn tls(mut self, config: interface::TlsConfig) -> Self {
+        self.config.tls = Some(config);
+        self
+    }
+
+    /// Time given to interface clients to receive pending responses on shutdown
+    pub fn shutdown_timeout(mut self, timeout: Duration) -> Self {
+        self.config.shutdown_timeout = timeout;
+        self
+    }
+
+    /// Register hook run on shutdown, hooks are run in order of registration
+    pub fn on_shutdown<F>(mut self, hook: F) -> Self
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.shutdown_hooks.push(Box::new(hook));
+        self
+    }
+
+    /// Stop node on SIGINT and SIGTERM
+    pub fn handle_signals(mut self) -> Self {
+        self.handle_signals = true;
+        self
+    }
+
+    /// Source of configuration applied on `AgentHandle::reload`, admin
+    /// `reload_config` action and SIGHUP if signals are handled
+    pub fn on_reload<F>(mut self, reload: F) -> Self
+    where
+        F: FnMut() -> Result<NodeConfig> + Send + 'static,
+    {
+        self.reload = Some(Box::new(reload));
+        self
+    }
+
+    /// Bind sockets and start node thread
+    pub fn spawn(self) -> Result<AgentHandle> {
+        let AgentBuilder {
+            config,
+            node_type,
+            shutdown_hooks,
+            handle_signals,
+            reload,
+        } = self;
+        check_intervals(&config)?;
+
+        let landmark_addr = match node_type {
+            NodeType::Regular => Some(config.landmark_addr.ok_or(Error::Node(ERR_NO_LANDMARK))?),
+            NodeType::Landmark => None,
+        };
+
+        let sock = UdpSocket::bind((config.node_addr, config.node_port)).map_err(|source| Error::Bind {
+            addr: SocketAddr::new(config.node_addr, config.node_port).to_string(),
+            source,
+        })?;
+        let udp_addr = sock.local_addr()?;
+        // receiver owning the socket is gone when leave is announced
+        let leave_sock = sock.try_clone()?;
+        let sock = ::tokio::net::UdpSocket::from_std(sock, &Handle::default())?;
+
+        let store = match (node_type, &config.persist) {
+            (NodeType::Regular, Some(persist_config)) => persist::restore_or_empty(persist_config),
+            _ => Storage::new(),
+        };
+        match node_type {
+            NodeType::Regular => store.status().set_node(NodeKind::Agent, udp_addr, landmark_addr),
+            NodeType::Landmark => {
+                store.set_location(NodeCoordinates {
+                    pos_err: 0.0,
+                    ..Default::default()
+                });
+                store.status().set_node(NodeKind::Landmark, udp_addr, None);
+            }
+        }
+        store.set_gossip_size(config.gossip_size);
+        store.set_vivaldi_params(config.vivaldi);
+        let store = Arc::new(store);
+
+        // commands from admin interface, reloader and handle to transmitter
+        let (control_tx, control_rx) = mpsc::unbounded();
+        let control = landmark_addr.map(|_| control_tx);
+        let token = SharedToken::new(config.auth_token.clone());
+        let reloader = Reloader::new(config.clone(), reload, store.clone(), control.clone(), token.clone()).shared();
+        let admin = config.admin_addr.map(|addr| AdminConfig {
+            addr,
+            control: control.clone(),
+            reload: reloader.clone(),
+        });
+
+        // listeners are bound here, so that bind errors are reported by spawn
+        let server = Server::bind(&interface_config(&config, token), admin, store.clone())?;
+
+        // core tasks, node stops when any of them exits
+        let mut tasks: Vec<Box<dyn Future<Item = (), Error = io::Error> + Send>> = Vec::new();
+
+        // transmitter, only agents probe other nodes
+        let probes = match landmark_addr {
+            Some(landmark_addr) => {
+                let period = config
+                    .probe_period
+                    .unwrap_or_else(|| Duration::from_secs(PROBE_PERIOD_DEFAULT_SEC));
+                store.status().set_probe_period(period);
+
+                let (probes_tx, probes_rx) = mpsc::unbounded();
+                tasks.push(Box::new(Transmitter::new(
+                    config.node_name.clone(),
+                    landmark_addr,
+                    store.clone(),
+                    config.peer_selection.build(),
+                    udp_addr,
+                    period,
+                    control_rx,
+                    probes_tx,
+                )));
+                Some(probes_rx)
+            }
+            None => None,
+        };
+
+        // receiver, also sends probes of transmitter
+        tasks.push(Box::new(Receiver::new(
+            node_type,
+            config.node_name.clone(),
+            store.clone(),
+            sock,
+            landmark_addr,
+            probes,
+        )?));
+
+        // periodically save state
+        let saver = match (node_type, &config.persist) {
+            (NodeType::Regular, Some(persist_config)) => {
+                Some(persist::saver(store.clone(), persist_config.clone())?)
+            }
+            _ => None,
+        };
+
+        let signal_reloader = reloader.clone();
+        let shutdown_timeout = config.shutdown_timeout;
+        let leave_store = store.clone();
+
+        let (stop_tx, stop_rx) = oneshot::channel();
+        let thread = thread::Builder::new()
+            .name("netloc-node".to_string())
+            .spawn(move || {
+                let mut runtime = Runtime::new()?;
+                let drain = server.spawn(&mut runtime);
+                if let Some(saver) = saver {
+                    runtime.spawn(saver);
+                }
+
+                // node runs until any core task exits, stop is requested or signal is received
+                let core = future::select_all(tasks)
+                    .map(|_| info!("node task finished, shutting down"))
+                    .map_err(|(e, _, _)| e);
+                let mut events: Vec<Box<dyn Future<Item = (), Error = io::Error>>> =
+                    vec![Box::new(core), Box::new(stop_rx.then(|_| Ok(())))];
+                if handle_signals {
+                    let signals = signals::listen()
+                        .take_while(|signal| Ok(*signal == Signal::Reload))
+                        .for_each(move |_| {
+                            let result = signal_reloader.lock().unwrap_or_else(PoisonError::into_inner).reload();
+                            if let Err(e) = result {
+                                error!("cannot reload configuration: {}", e);
+                            }
+                            Ok(())
+                        });
+                    events.push(Box::new(signals));
+                }
+
+                // probing stops as soon as core tasks are dropped
+                let result = runtime
+                    .block_on(future::select_all(events))
+                    .map(|_| ())
+                    .map_err(|(e, _, _)| e);
+
+                if let Some(landmark_addr) = landmark_addr {
+                    announce_leave(&leave_sock, landmark_addr, &leave_store);
+                }
+
+                // let interface clients receive pending responses
+                let deadline = Delay::new(Instant::now() + shutdown_timeout);
+                match runtime.block_on(drain.start().select2(deadline)) {
+                    Ok(Either::A(_)) => debug!("interface connections drained"),
+                    _ => warn!("interface connections closed after {:?} timeout", shutdown_timeout),
+                }
+
+                result
+            })?;
+
+        Ok(AgentHandle {
+            store,
+            udp_addr,
+            control,
+            reloader,
+            stop: Some(stop_tx),
+            thread: Some(thread),
+            persist: config.persist.clone(),
+            shutdown_hooks,
+        })
+    }
+}
+
+/// Tell landmark and some known nodes that agent leaves, best effort
+fn announce_leave(sock: &UdpSocket, landmark_addr: SocketAddr, store: &Storage) {
+    let notice = [MsgType::Leave.to_code()];
+    let mut ignore = vec![landmark_addr];
+    ignore.extend(sock.local_addr().ok());
+    let peers = store
+        .get_random_nodes(LEAVE_NOTICE_MAX_NODES, &ignore)
+        .unwrap_or_default();
+
+    let receivers = peers.iter().map(|n| SocketAddr::new(n.ip, n.port));
+    let mut announced = 0;
+    for receiver in Some(landmark_addr).into_iter().chain(receivers) {
+        match sock.send_to(&notice, receiver) {
+            Ok(_) => announced += 1,
+            Err(e) => debug!("cannot announce leave to {}: {}", receiver, e),
+        }
+    }
+    info!("leave announced to {} nodes", announced);
+}
+
+fn interface_config(config: &NodeConfig, token: SharedToken) -> InterfaceConfig {
+    InterfaceConfig {
+        addr: config.interface_addr,
+        http_addr: config.http_addr,
+        unix_socket: config.unix_socket.clone(),
+        auth_token: token,
+        tls: config.tls.clone(),
+    }
+}
+
+/// Running node
+///
+/// Dropping handle signals node to stop without waiting for it.
+pub struct AgentHandle {
+    store: SharedStorage,
+    udp_addr: SocketAddr,
+    // absent for landmark
+    control: Option<mpsc::UnboundedSender<Control>>,
+    reloader: SharedReloader,
+    stop: Option<oneshot::Sender<()>>,
+    thread: Option<JoinHandle<io::Result<()>>>,
+    persist: Option<PersistConfig>,
+    shutdown_hooks: Vec<ShutdownHook>,
+}
+
+impl AgentHandle {
+    /// Bound UDP address of the node
+    pub fn udp_addr(&self) -> SocketAddr {
+        self.udp_addr
+    }
+
+    pub fn location(&self) -> NodeCoordinates {
+        self.store.get_location()
+    }
+
+    /// All nodes known to the node
+    pub fn nodes(&self) -> NodeList {
+        self.store.get_all_nodes()
+    }
+
+    pub fn status(&self) -> StatusReport {
+        status::report(self.store.as_ref())
+    }
+
+    /// Storage of the node for queries not covered by the handle
+    pub fn storage(&self) -> &SharedStorage {
+        &self.store
+    }
+
+    /// Send command to transmitter, e.g. to probe some node now
+    pub fn control(&self, command: Control) -> Result<()> {
+        match self.control {
+            Some(ref control) => control
+                .unbounded_send(command)
+                .map_err(|_| Error::Node(ERR_NODE_STOPPED)),
+            None => Err(Error::Node(ERR_NOT_SUPPORTED)),
+        }
+    }
+
+    /// Apply configuration produced by the reload hook, see `AgentBuilder::on_reload`
+    pub fn reload(&self) -> Result<ReloadReport> {
+        self.reloader.lock().unwrap_or_else(PoisonError::into_inner).reload()
+    }
+
+    /// Block until node stops by itself, i.e. on failure or signal
+    pub fn wait(mut self) -> Result<()> {
+        self.join()
+    }
+
+    /// Stop node and wait for it, saving state if persistence is enabled
+    /// and running shutdown hooks. Returns failure of the node if any.
+    pub fn shutdown(mut self) -> Result<()> {
+        self.stop();
+        self.join()
+    }
+
+    fn stop(&mut self) {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(());
+        }
+    }
+
+    fn join(&mut self) -> Result<()> {
+        let result = match self.thread.take() {
+            Some(thread) => match thread.join() {
+                Ok(result) => result.map_err(Error::from),
+                Err(_) => Err(Error::Node(ERR_THREAD_PANICKED)),
+            },
+            None => Ok(()),
+        };
+        if let Err(ref e) = result {
+            error!("node failure: {}", e);
+        }
+
+        // save final state
+        let saved = match self.persist {
+            Some(ref persist_config) => persist::save(&self.store, &persist_config.path),
+            None => Ok(()),
+        };
+
+        for hook in self.shutdown_hooks.drain(..) {
+            hook();
+        }
+
+        result.and(saved)
+    }
+}
+
+impl Drop for AgentHandle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            node_addr: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+            node_port: 0,
+            node_name: String::new(),
+            probe_period: None,
+            interface_addr: None,
+            http_addr: None,
+            admin_addr: None,
+            unix_socket: None,
+            auth_token: None,
+            tls: None,
+            landmark_addr: None,
+            peer_selection: PeerSelection::default(),
+            gossip_size: GOSSIP_MAX_NEIGHBOURS_IN_MSG,
+            vivaldi: vivaldi::Params::default(),
+            persist: None,
+            shutdown_timeout: Duration::from_secs(SHUTDOWN_TIMEOUT_DEFAULT_SEC),
+            log_level: ::log::Level::Info,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+    use std::io::{BufRead, BufReader, Write};
+    use std::os::unix::net::UnixStream;
+    use std::process;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    fn localhost() -> SocketAddr {
+        "127.0.0.1:0".parse().unwrap()
+    }
+
+    #[test]
+    fn agent_joins_landmark() {
+        let landmark = Agent::landmark_builder().bind(localhost()).spawn().unwrap();
+        let state_path = env::temp_dir().join(format!("netloc-embedded-{}.json", landmark.udp_addr().port()));
+
+        let agent = Agent::builder()
+            .bind(localhost())
+            .name("embedded")
+            .landmark(landmark.udp_addr())
+            .probe_period(Duration::from_millis(50))
+            .persist(PersistConfig {
+                path: state_path.clone(),
+                save_period: Duration::from_secs(60),
+                max_age: Duration::from_secs(60),
+            })
+            .spawn()
+            .unwrap();
+
+        let started = Instant::now();
+        while landmark.nodes().is_empty() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(landmark.nodes()[0].name, "embedded");
+        assert_eq!(agent.status().landmark_addr, Some(landmark.udp_addr()));
+        assert!(landmark.control(Control::ProbeNow(None)).is_err());
+
+        // node is joined and final state is saved
+        agent.shutdown().unwrap();
+
+        // landmark forgets agent on its leave notice
+        let started = Instant::now();
+        while !landmark.nodes().is_empty() && started.elapsed() < Duration::from_secs(5) {
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert!(landmark.nodes().is_empty());
+        landmark.shutdown().unwrap();
+        assert!(state_path.exists());
+        let _ = ::std::fs::remove_file(state_path);
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        let path = env::temp_dir().join(format!("netloc-shutdown-{}.sock", process::id()));
+        let hook_run = Arc::new(AtomicBool::new(false));
+        let flag = hook_run.clone();
+
+        let landmark = Agent::landmark_builder()
+            .bind(localhost())
+            .unix_socket(interface::UnixSocketConfig {
+                path: path.clone(),
+                mode: None,
+                owner: None,
+                group: None,
+            })
+            .on_shutdown(move || flag.store(true, Ordering::SeqCst))
+            .spawn()
+            .unwrap();
+
+        let mut client = UnixStream::connect(&path).unwrap();
+        client.write_all(b"{\"action\": \"get_location\"}\n").unwrap();
+        let mut reader = BufReader::new(client);
+        let mut line = String::new();
+        reader.read_line(&mut line).unwrap();
+        assert!(line.contains("location"));
+
+        // connected client doesn't hold shutdown until timeout
+        let started = Instant::now();
+        landmark.shutdown().unwrap();
+        assert!(started.elapsed() < Duration::from_secs(SHUTDOWN_TIMEOUT_DEFAULT_SEC));
+        assert!(hook_run.load(Ordering::SeqCst));
+        assert!(!path.exists());
+
+        line.clear();
+        assert_eq!(reader.read_line(&mut line).unwrap(), 0);
+    }
+
+    #[test]
+    fn spawn_errors() {
+        match Agent::builder().bind(localhost()).spawn() {
+            Err(e) => assert_eq!(e.to_string(), ERR_NO_LANDMARK),
+            Ok(_) => panic!("agent spawned without landmark"),
+        }
+
+        // interface address in use
+        let landmark = Agent::landmark_builder().bind(localhost()).spawn().unwrap();
+        let busy = ::std::net::TcpListener::bind(localhost()).unwrap();
+        let result = Agent::builder()
+            .bind(localhost())
+            .landmark(landmark.udp_addr())
+            .interface(busy.local_addr().unwrap())
+            .spawn();
+        match result {
+            Err(Error::Bind { addr, .. }) => assert_eq!(addr, busy.local_addr().unwrap().to_string()),
+            Err(e) => panic!("unexpected error: {}", e),
+            Ok(_) => panic!("agent spawned on busy interface address"),
+        }
+    }
+}
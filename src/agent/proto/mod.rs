@@ -9,13 +9,18 @@ pub use self::types::*;
 
 use std::str::from_utf8;
 
+use error::Result;
+
+const ERR_NAME_TOO_LONG: &str = "node name is longer than 254 bytes";
+const ERR_BAD_NAME: &str = "bad node name";
+const ERR_TRUNCATED: &str = "message is truncated";
+
 pub trait BinarySerializable<'de> {
     type Item;
 
-    // fixme mb change to Result<Vec<u8>, ? SomeErr ? >
-    fn serialize(&self) -> Option<Vec<u8>>;
+    fn serialize(&self) -> Result<Vec<u8>>;
     // data == msg w/o first 'message_type' byte!
-    fn deserialize(data: &'de [u8]) -> Option<Self::Item>;
+    fn deserialize(data: &'de [u8]) -> Result<Self::Item>;
 }
 
 /* Strings */
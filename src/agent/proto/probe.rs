@@ -7,6 +7,12 @@ use std::time::{SystemTime, UNIX_EPOCH};
 
 use super::byteorder::{BigEndian, ByteOrder};
 use agent::proto::BinarySerializable;
+use error::{Error, Result};
+
+/// Bytes of sending time preceding the name
+const TIME_LEN: usize = 12;
+/// Bytes of 4 x f32 coordinates and u64 iteration
+const COORDINATES_LEN: usize = 24;
 
 /// Periodic request sent to random neighbour in order
 /// to measure its RTT.
@@ -55,7 +61,7 @@ impl ProbeRequest {
 impl<'a> BinarySerializable<'a> for ProbeRequest {
     type Item = Self;
 
-    fn serialize(&self) -> Option<Vec<u8>> {
+    fn serialize(&self) -> Result<Vec<u8>> {
         let mut msg_buff: Vec<u8> = Vec::new();
         let mut buff_4b: [u8; 4] = [0; 4];
         let mut buff_8b: [u8; 8] = [0; 8];
@@ -70,7 +76,7 @@ impl<'a> BinarySerializable<'a> for ProbeRequest {
         msg_buff.extend(buff_4b.iter());
 
         // probe initiator's name
-        msg_buff.extend(serialize_str(&self.sender_name)?);
+        msg_buff.extend(serialize_str(&self.sender_name).ok_or(Error::Encode(ERR_NAME_TOO_LONG))?);
 
         // neighbours
         if let Some(ref neighbours) = self.neighbours {
@@ -79,19 +85,22 @@ impl<'a> BinarySerializable<'a> for ProbeRequest {
             );
         }
 
-        Some(msg_buff)
+        Ok(msg_buff)
     }
 
-    fn deserialize(data: &'a [u8]) -> Option<Self> {
+    fn deserialize(data: &'a [u8]) -> Result<Self> {
         let mut unparsed = &data[..];
+        if unparsed.len() < TIME_LEN {
+            return Err(Error::Decode(ERR_TRUNCATED));
+        }
 
         // time
         let secs = BigEndian::read_u64(&unparsed[..8]);
-        let nsecs = BigEndian::read_u32(&unparsed[8..12]);
-        unparsed = &unparsed[12..];
+        let nsecs = BigEndian::read_u32(&unparsed[8..TIME_LEN]);
+        unparsed = &unparsed[TIME_LEN..];
 
         // transmitter name
-        let (transmitter_name, mut unparsed) = deserialize_str(unparsed)?;
+        let (transmitter_name, mut unparsed) = deserialize_str(unparsed).ok_or(Error::Decode(ERR_BAD_NAME))?;
 
         // create message
         let mut msg = ProbeRequest::new(transmitter_name.to_string());
@@ -108,7 +117,7 @@ impl<'a> BinarySerializable<'a> for ProbeRequest {
             unparsed = rest;
         }
 
-        Some(msg)
+        Ok(msg)
     }
 }
 
@@ -157,7 +166,7 @@ impl ProbeResponse {
 impl<'de> BinarySerializable<'de> for ProbeResponse {
     type Item = ProbeResponse;
 
-    fn serialize(&self) -> Option<Vec<u8>> {
+    fn serialize(&self) -> Result<Vec<u8>> {
         let mut msg_buff: Vec<u8> = Vec::new();
         let mut buff_4b: [u8; 4] = [0; 4];
         let mut buff_8b: [u8; 8] = [0; 8];
@@ -172,7 +181,7 @@ impl<'de> BinarySerializable<'de> for ProbeResponse {
         msg_buff.extend(buff_4b.iter());
 
         // probe respondent's name
-        msg_buff.extend(serialize_str(&self.respondent_name)?);
+        msg_buff.extend(serialize_str(&self.respondent_name).ok_or(Error::Encode(ERR_NAME_TOO_LONG))?);
 
         // coordinates
         [
@@ -196,23 +205,26 @@ impl<'de> BinarySerializable<'de> for ProbeResponse {
             );
         }
 
-        Some(msg_buff)
+        Ok(msg_buff)
     }
 
-    fn deserialize(data: &'de [u8]) -> Option<Self> {
+    fn deserialize(data: &'de [u8]) -> Result<Self> {
         let mut unparsed = &data[..];
+        if unparsed.len() < TIME_LEN {
+            return Err(Error::Decode(ERR_TRUNCATED));
+        }
 
         // time
         let secs = BigEndian::read_u64(&unparsed[..8]);
-        let nsecs = BigEndian::read_u32(&unparsed[8..12]);
-        unparsed = &unparsed[12..];
+        let nsecs = BigEndian::read_u32(&unparsed[8..TIME_LEN]);
+        unparsed = &unparsed[TIME_LEN..];
 
         // transmitter name
-        let (respondent_name, mut unparsed) = deserialize_str(unparsed)?;
+        let (respondent_name, mut unparsed) = deserialize_str(unparsed).ok_or(Error::Decode(ERR_BAD_NAME))?;
 
         // bytes required to decode coordinates
-        if unparsed.len() < 24 {
-            return None;
+        if unparsed.len() < COORDINATES_LEN {
+            return Err(Error::Decode(ERR_TRUNCATED));
         }
 
         // parse coordinates
@@ -224,7 +236,7 @@ impl<'de> BinarySerializable<'de> for ProbeResponse {
             iteration: BigEndian::read_u64(&unparsed[16..24]),
         };
 
-        unparsed = &unparsed[24..];
+        unparsed = &unparsed[COORDINATES_LEN..];
 
         // create message
         let mut msg = ProbeResponse::new(respondent_name.to_string(), respondent_location);
@@ -242,7 +254,7 @@ impl<'de> BinarySerializable<'de> for ProbeResponse {
             unparsed = rest;
         }
 
-        Some(msg)
+        Ok(msg)
     }
 }
 
@@ -282,4 +294,31 @@ mod tests {
 
         assert_eq!(resp, decoded);
     }
+
+    #[test]
+    fn malformed_messages() {
+        let mut req = ProbeRequest::new("test_node".to_string());
+        req.set_current_time();
+        let encoded = req.serialize().unwrap();
+
+        // cut within time, name and coordinates
+        for len in &[1, 8, 16] {
+            match ProbeRequest::deserialize(&encoded[1..*len]) {
+                Err(Error::Decode(_)) => {}
+                other => panic!("unexpected result: {:?}", other),
+            }
+        }
+        let resp = ProbeResponse::new("respondent_node".to_string(), NodeCoordinates::empty());
+        let encoded = resp.serialize().unwrap();
+        match ProbeResponse::deserialize(&encoded[1..encoded.len() - 4]) {
+            Err(Error::Decode(reason)) => assert_eq!(reason, ERR_TRUNCATED),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        let long_name: String = ['x'; 300].iter().collect();
+        match ProbeRequest::new(long_name).serialize() {
+            Err(Error::Encode(reason)) => assert_eq!(reason, ERR_NAME_TOO_LONG),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
 }
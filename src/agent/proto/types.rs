@@ -8,6 +8,8 @@ use super::*;
 pub enum MsgType {
     ProbeRequest,
     ProbeResponse,
+    /// Sender is shutting down, message has no body
+    Leave,
 }
 
 impl MsgType {
@@ -15,6 +17,7 @@ impl MsgType {
         match *self {
             MsgType::ProbeRequest => 1,
             MsgType::ProbeResponse => 2,
+            MsgType::Leave => 3,
         }
     }
 
@@ -22,6 +25,7 @@ impl MsgType {
         match code {
             1 => Some(MsgType::ProbeRequest),
             2 => Some(MsgType::ProbeResponse),
+            3 => Some(MsgType::Leave),
 
             _ => None,
         }
@@ -32,7 +36,7 @@ impl MsgType {
 
 pub type NodeList = Vec<NodeInfo>;
 
-#[derive(Debug, PartialOrd, PartialEq, Clone, Serialize)]
+#[derive(Debug, PartialOrd, PartialEq, Clone, Serialize, Deserialize)]
 pub struct NodeFlags {
     is_addr_ipv6: bool,
 }
@@ -54,7 +58,7 @@ impl NodeFlags {
     }
 }
 
-#[derive(Debug, Default, PartialOrd, PartialEq, Clone, Serialize)]
+#[derive(Debug, Default, PartialOrd, PartialEq, Clone, Serialize, Deserialize)]
 pub struct NodeCoordinates {
     pub x1: f32,
     pub x2: f32,
@@ -72,7 +76,7 @@ impl NodeCoordinates {
     }
 }
 
-#[derive(Debug, PartialOrd, PartialEq, Clone, Serialize)]
+#[derive(Debug, PartialOrd, PartialEq, Clone, Serialize, Deserialize)]
 pub struct NodeInfo {
     pub flags: NodeFlags,
     pub ip: IpAddr,
@@ -2,17 +2,32 @@
 ///
 /// Possible messages:
 /// - Location request (foreign);
-/// - Location response (for the local request).
+/// - Location response (for the local request);
+/// - Leave notice of the stopping node.
+///
+/// Receiver owns UDP socket of the node, so it also sends probes
+/// queued by transmitter, setting their time right before sending.
 
+use std::collections::VecDeque;
 use std::io;
 use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use std::net::{SocketAddr, UdpSocket};
+use std::net::SocketAddr;
+
+use futures::sync::mpsc::UnboundedReceiver;
+use tokio::net::UdpSocket;
+use tokio::prelude::*;
 
+use error::Error;
 use storage::SharedStorage;
-use agent::{NodeType, BinarySerializable, MsgType, NodeInfo, GOSSIP_MAX_NEIGHBOURS_IN_MSG};
+use agent::{NodeType, BinarySerializable, MsgType, NodeInfo};
 use agent::probe::{ProbeRequest, ProbeResponse};
+use super::transmitter::Probe;
 
 const RCV_BUFF_SIZE: usize = 1500;
+/// Yield to other tasks after processing this many messages
+const MAX_MESSAGES_PER_POLL: usize = 64;
+/// Datagrams waiting for socket to become writable, the rest are dropped
+const MAX_QUEUED_DATAGRAMS: usize = 1024;
 
 pub struct Receiver {
     node_type: NodeType,
@@ -21,6 +36,10 @@ pub struct Receiver {
     sock: UdpSocket,
     local_addr: SocketAddr,
     landmark: Option<SocketAddr>,
+    // probes of transmitter, absent for landmark
+    probes: Option<UnboundedReceiver<Probe>>,
+    queue: VecDeque<(Vec<u8>, SocketAddr)>,
+    buff: Vec<u8>,
 }
 
 impl Receiver {
@@ -30,197 +49,308 @@ impl Receiver {
         store: SharedStorage,
         sock: UdpSocket,
         landmark: Option<SocketAddr>,
-    ) -> Self {
-        let local_addr = sock.local_addr().expect("couldn't obtain socket address");
+        probes: Option<UnboundedReceiver<Probe>>,
+    ) -> io::Result<Self> {
+        let local_addr = sock.local_addr()?;
 
-        Receiver {
+        Ok(Receiver {
             node_type,
             name,
             store,
             sock,
             local_addr,
             landmark,
-        }
+            probes,
+            queue: VecDeque::new(),
+            buff: vec![0; RCV_BUFF_SIZE],
+        })
     }
 
-    pub fn run(&self) -> io::Result<()> {
+    /// Process message, return encoded reply if any
+    fn process(&self, msg: &[u8], sender: SocketAddr) -> io::Result<Option<Vec<u8>>> {
+        if msg.is_empty() {
+            self.store.metrics().decode_error();
+            return Ok(None);
+        }
+
         match self.node_type {
-            NodeType::Regular => self.run_regular(),
-            NodeType::Landmark => self.run_landmark(),
+            NodeType::Regular => self.process_regular(msg, sender),
+            NodeType::Landmark => self.process_landmark(msg, sender),
         }
     }
 
     /// Fully functional agent responder
-    fn run_regular(&self) -> io::Result<()> {
-        let mut buff: [u8; RCV_BUFF_SIZE] = [0; RCV_BUFF_SIZE];
+    fn process_regular(&self, msg: &[u8], sender: SocketAddr) -> io::Result<Option<Vec<u8>>> {
+        let msg_data = &msg[1..];
+
+        match MsgType::from_code(msg[0]) {
+            Some(MsgType::ProbeRequest) => {
+                // respond to foreign request
+                self.store.metrics().request_received();
+                let response = ProbeRequest::deserialize(msg_data).map(|request| {
+                    debug!(
+                        "detected probe from {}:{} (aka {})",
+                        sender.ip(),
+                        sender.port(),
+                        &request.sender_name
+                    );
+
+                    // storage
+                    let s = &self.store;
+
+                    // form response
+                    let mut response = ProbeResponse::new(self.name.clone(), s.get_location());
+
+                    // send back initial transmission time
+                    response.copy_time(&request);
+
+                    // add some neighbour's info
+                    let mut ignore = vec![sender, self.local_addr];
+                    ignore.extend(self.landmark);
+                    if let Some(neighbours) = s.get_random_nodes(s.gossip_size(), &ignore) {
+                        response.set_neighbours(neighbours);
+                    }
 
-        loop {
-            let (msg_len, sender) = self.sock.recv_from(&mut buff)?;
-            let msg_data = &buff[1..msg_len];
-
-            match MsgType::from_code(buff[0]) {
-                Some(MsgType::ProbeRequest) => {
-                    // respond to foreign request
-                    let response = ProbeRequest::deserialize(msg_data).and_then(|request| {
-                        debug!(
-                            "detected probe from {}:{} (aka {})",
-                            sender.ip(),
-                            sender.port(),
-                            &request.sender_name
-                        );
-
-                        // storage
-                        let mut s = self.store.lock().unwrap();
-
-                        // form response
-                        let mut response = ProbeResponse::new(self.name.clone(), s.get_location());
-
-                        // send back initial transmission time
-                        response.copy_time(&request);
-
-                        // add some neighbour's info
-                        if let Some(neighbours) =
-                            s.get_random_nodes(
-                                GOSSIP_MAX_NEIGHBOURS_IN_MSG,
-                                &[sender, self.local_addr, self.landmark.unwrap()],
-                            ).and_then(|nodes| Some(nodes.iter().map(|&n| n.clone()).collect()))
-                        {
-                            response.set_neighbours(neighbours);
-                        }
-
-                        // store information about sender
-                        s.add_node(NodeInfo::new(
-                            sender.ip(),
-                            sender.port(),
-                            request.sender_name,
-                        ));
-
-                        // save received information about nodes
-                        if let Some(neighbours) = request.neighbours {
-                            neighbours.into_iter().for_each(|n| s.add_node(n));
-                        }
-
-                        Some(response)
-                    });
-
-                    // send back response
-                    if let Some(encoded) = response.and_then(|r| r.serialize()) {
-                        self.sock.send_to(&encoded, sender)?;
-                    } else {
-                        error!("response serialization failed");
+                    // store information about sender
+                    s.add_node(NodeInfo::new(
+                        sender.ip(),
+                        sender.port(),
+                        request.sender_name,
+                    ));
+
+                    // save received information about nodes
+                    if let Some(neighbours) = request.neighbours {
+                        neighbours.into_iter().for_each(|n| s.add_node(n));
                     }
-                }
 
-                Some(MsgType::ProbeResponse) => {
-                    // message reception time
-                    let received_at = SystemTime::now().duration_since(UNIX_EPOCH).map_err(|e| {
-                        io::Error::new(io::ErrorKind::Other, e)
-                    })?;
-
-                    // decode and process
-                    ProbeResponse::deserialize(msg_data).and_then(|response| {
-                        debug!(
-                            "probe response from {}:{} (aka {})",
-                            sender.ip(),
-                            sender.port(),
-                            &response.respondent_name
-                        );
-
-                        // storage access
-                        let mut s = self.store.lock().unwrap();
-
-                        // recompute own location based on response's RTT
-                        if let Some(rtt) = received_at.checked_sub(Duration::new(
-                            response.sent_at_sec,
-                            response.sent_at_nsec,
-                        ))
-                        {
-                            s.update_location(&response.location, rtt);
-                        }
-
-                        // store information about respondent
-                        if sender != self.landmark.unwrap() {
-                            let mut respondent_info =
-                                NodeInfo::new(sender.ip(), sender.port(), response.respondent_name);
-                            respondent_info.set_coordinates(&response.location);
-                            s.add_node(respondent_info);
-                        }
-
-                        // store info about its neighbours
-                        if let Some(neighbours) = response.neighbours {
-                            neighbours.into_iter().for_each(|n| s.add_node(n));
-                        }
-
-                        Some(())
-                    });
+                    response
+                });
+
+                // send back response
+                match response.and_then(|response| response.serialize()) {
+                    Ok(encoded) => return Ok(Some(encoded)),
+                    Err(e) => self.failed(&e, sender),
                 }
+            }
+
+            Some(MsgType::ProbeResponse) => {
+                // message reception time
+                let received_at = SystemTime::now().duration_since(UNIX_EPOCH).map_err(io::Error::other)?;
+
+                // decode and process
+                let processed = ProbeResponse::deserialize(msg_data).map(|response| {
+                    debug!(
+                        "probe response from {}:{} (aka {})",
+                        sender.ip(),
+                        sender.port(),
+                        &response.respondent_name
+                    );
+
+                    // storage access
+                    let s = &self.store;
+
+                    // recompute own location based on response's RTT
+                    let rtt = received_at.checked_sub(Duration::new(
+                        response.sent_at_sec,
+                        response.sent_at_nsec,
+                    ));
+                    if let Some(rtt) = rtt {
+                        s.update_location(&response.location, rtt);
+                    }
+                    s.metrics().response_received(sender, rtt);
+                    s.status().response_received(sender);
+
+                    // store information about respondent
+                    if Some(sender) != self.landmark {
+                        let mut respondent_info =
+                            NodeInfo::new(sender.ip(), sender.port(), response.respondent_name);
+                        respondent_info.set_coordinates(&response.location);
+                        s.add_node(respondent_info);
+                    }
+
+                    // store info about its neighbours
+                    if let Some(neighbours) = response.neighbours {
+                        neighbours.into_iter().for_each(|n| s.add_node(n));
+                    }
+                });
 
-                _ => {
-                    debug!("unexpected message: {:?}", msg_data);
+                if let Err(e) = processed {
+                    self.failed(&e, sender);
                 }
             }
+
+            Some(MsgType::Leave) => self.forget(sender),
+
+            _ => {
+                debug!("unexpected message: {:?}", msg_data);
+                self.store.metrics().decode_error();
+            }
         }
+
+        Ok(None)
     }
 
     /// Only respond on initial requests
-    fn run_landmark(&self) -> io::Result<()> {
-        let mut buff: [u8; RCV_BUFF_SIZE] = [0; RCV_BUFF_SIZE];
+    fn process_landmark(&self, msg: &[u8], sender: SocketAddr) -> io::Result<Option<Vec<u8>>> {
+        let msg_data = &msg[1..];
+
+        match MsgType::from_code(msg[0]) {
+            Some(MsgType::ProbeRequest) => {
+                // respond to foreign request
+                self.store.metrics().request_received();
+                let response = ProbeRequest::deserialize(msg_data).map(|request| {
+                    debug!(
+                        "detected probe from {}:{} (aka {})",
+                        sender.ip(),
+                        sender.port(),
+                        &request.sender_name
+                    );
+
+                    // storage access
+                    let s = &self.store;
+
+                    // form response
+                    let mut response = ProbeResponse::new(self.name.clone(), s.get_location());
+
+                    // send back original transmission time
+                    response.copy_time(&request);
+
+                    // add info about known nodes
+                    if let Some(neighbours) =
+                        s.get_random_nodes(s.gossip_size(), &[sender, self.local_addr])
+                    {
+                        response.set_neighbours(neighbours);
+                    }
 
+                    // store information about sender
+                    let sender_info =
+                        NodeInfo::new(sender.ip(), sender.port(), request.sender_name);
+                    s.add_node(sender_info);
+
+                    // save received information about nodes
+                    if let Some(neighbours) = request.neighbours {
+                        neighbours.into_iter().for_each(|n| s.add_node(n));
+                    }
+
+                    response
+                });
+
+                // send back response
+                match response.and_then(|response| response.serialize()) {
+                    Ok(encoded) => return Ok(Some(encoded)),
+                    Err(e) => self.failed(&e, sender),
+                }
+            }
+
+            Some(MsgType::Leave) => self.forget(sender),
+
+            _ => {
+                debug!("unexpected message: {:?}", msg_data);
+                self.store.metrics().decode_error();
+            }
+        }
+
+        Ok(None)
+    }
+
+    /// Sender announced its leave
+    fn forget(&self, sender: SocketAddr) {
+        if self.store.remove_node(sender) {
+            info!("node {} left", sender);
+        }
+    }
+
+    /// Message is dropped, the node keeps running
+    fn failed(&self, e: &Error, sender: SocketAddr) {
+        match *e {
+            Error::Decode(_) => {
+                debug!("{} from {}", e, sender);
+                self.store.metrics().decode_error();
+            }
+            _ => error!("cannot respond to {}: {}", sender, e),
+        }
+    }
+
+    /// Send datagram now or queue it until socket is writable
+    fn send(&mut self, data: Vec<u8>, target: SocketAddr) -> io::Result<()> {
+        if self.queue.is_empty() {
+            if let Async::Ready(_) = self.sock.poll_send_to(&data, &target)? {
+                return Ok(());
+            }
+        }
+
+        if self.queue.len() < MAX_QUEUED_DATAGRAMS {
+            self.queue.push_back((data, target));
+        } else {
+            warn!("send queue is full, datagram to {} dropped", target);
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        while let Some((data, target)) = self.queue.pop_front() {
+            if let Async::NotReady = self.sock.poll_send_to(&data, &target)? {
+                self.queue.push_front((data, target));
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Stamp and send probes of transmitter
+    fn send_probes(&mut self) -> io::Result<()> {
         loop {
-            let (msg_len, sender) = self.sock.recv_from(&mut buff)?;
-            let msg_data = &buff[1..msg_len];
-
-            match MsgType::from_code(buff[0]) {
-                Some(MsgType::ProbeRequest) => {
-                    // respond to foreign request
-                    let response = ProbeRequest::deserialize(msg_data).and_then(|request| {
-                        debug!(
-                            "detected probe from {}:{} (aka {})",
-                            sender.ip(),
-                            sender.port(),
-                            &request.sender_name
-                        );
-
-                        // storage access
-                        let mut s = self.store.lock().unwrap();
-
-                        // form response
-                        let mut response = ProbeResponse::new(self.name.clone(), s.get_location());
-
-                        // send back original transmission time
-                        response.copy_time(&request);
-
-                        // add info about known nodes
-                        if let Some(neighbours) =
-                            s.get_random_nodes(
-                                GOSSIP_MAX_NEIGHBOURS_IN_MSG,
-                                &[sender, self.local_addr],
-                            ).and_then(|nodes| Some(nodes.iter().map(|&n| n.clone()).collect()))
-                        {
-                            response.set_neighbours(neighbours);
-                        }
-
-                        // store information about sender
-                        let sender_info =
-                            NodeInfo::new(sender.ip(), sender.port(), request.sender_name);
-                        s.add_node(sender_info);
-
-                        // save received information about nodes
-                        if let Some(neighbours) = request.neighbours {
-                            neighbours.into_iter().for_each(|n| s.add_node(n));
-                        }
-
-                        Some(response)
-                    });
-
-                    // send back response
-                    if let Some(encoded) = response.and_then(|r| r.serialize()) {
-                        self.sock.send_to(&encoded, sender)?;
+            let probe = match self.probes {
+                Some(ref mut probes) => match probes.poll() {
+                    Ok(Async::Ready(Some(probe))) => probe,
+                    // transmitter is gone
+                    Ok(Async::Ready(None)) | Err(()) => {
+                        self.probes = None;
+                        return Ok(());
                     }
+                    Ok(Async::NotReady) => return Ok(()),
+                },
+                None => return Ok(()),
+            };
+
+            // set sending time immediately before serialization
+            let Probe { receiver, mut request } = probe;
+            request.set_current_time();
+
+            match request.serialize() {
+                Ok(encoded) => {
+                    self.send(encoded, receiver)?;
+                    self.store.metrics().probe_sent(receiver);
+                    self.store.status().probe_sent(receiver);
                 }
+                Err(e) => error!("cannot probe {}: {}", receiver, e),
+            }
+        }
+    }
+}
+
+/// Process messages until cancelled
+impl Future for Receiver {
+    type Item = ();
+    type Error = io::Error;
 
-                _ => debug!("unexpected message: {:?}", msg_data),
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        self.flush()?;
+        self.send_probes()?;
+
+        for _ in 0..MAX_MESSAGES_PER_POLL {
+            let (msg_len, sender) = match self.sock.poll_recv_from(&mut self.buff)? {
+                Async::Ready(received) => received,
+                Async::NotReady => return Ok(Async::NotReady),
+            };
+
+            if let Some(reply) = self.process(&self.buff[..msg_len], sender)? {
+                self.send(reply, sender)?;
             }
         }
+
+        // more messages are likely waiting
+        task::current().notify();
+        Ok(Async::NotReady)
     }
 }
@@ -0,0 +1,254 @@
+/// Configuration reload of the running node
+///
+/// On SIGHUP or `reload_config` admin action configuration is produced
+/// again and settings changeable at runtime are applied: log level,
+/// probe period and peer selection of transmitter, gossip size and
+/// Vivaldi parameters kept in storage for transmitter and receiver,
+/// and auth token of interface listeners. Changes of other settings
+/// are reported as requiring restart and otherwise ignored.
+///
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures::sync::mpsc::UnboundedSender;
+
+use config::check_intervals;
+use error::{Error, Result};
+use interface::{SharedToken, Token};
+use storage::SharedStorage;
+use super::node::PROBE_PERIOD_DEFAULT_SEC;
+use super::transmitter::Control;
+use super::NodeConfig;
+
+const ERR_NO_RELOAD_HOOK: &str = "configuration source not set";
+
+/// Produces configuration to apply on reload
+pub type ReloadHook = Box<dyn FnMut() -> Result<NodeConfig> + Send>;
+
+/// Reloader shared by signal handler, admin listener and node handle
+pub type SharedReloader = Arc<Mutex<Reloader>>;
+
+/// Changed settings named by their configuration paths
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ReloadReport {
+    pub applied: Vec<String>,
+    // changes ignored until restart
+    pub restart_required: Vec<String>,
+}
+
+/// Applies reloaded configuration to the running node
+pub struct Reloader {
+    // configuration in effect
+    current: NodeConfig,
+    hook: Option<ReloadHook>,
+    store: SharedStorage,
+    // absent for landmark
+    control: Option<UnboundedSender<Control>>,
+    token: SharedToken,
+}
+
+impl Reloader {
+    pub fn new(
+        current: NodeConfig,
+        hook: Option<ReloadHook>,
+        store: SharedStorage,
+        control: Option<UnboundedSender<Control>>,
+        token: SharedToken,
+    ) -> Self {
+        Reloader {
+            current,
+            hook,
+            store,
+            control,
+            token,
+        }
+    }
+
+    pub fn shared(self) -> SharedReloader {
+        Arc::new(Mutex::new(self))
+    }
+
+    /// Produce configuration with the hook and apply it
+    pub fn reload(&mut self) -> Result<ReloadReport> {
+        let config = match self.hook {
+            Some(ref mut hook) => hook()?,
+            None => return Err(Error::Node(ERR_NO_RELOAD_HOOK)),
+        };
+        // hook of embedding application may produce any values
+        check_intervals(&config)?;
+
+        let report = self.apply(config);
+        info!(
+            "configuration reloaded, {} settings applied, {} require restart",
+            report.applied.len(),
+            report.restart_required.len()
+        );
+        Ok(report)
+    }
+
+    /// Apply settings changeable at runtime, the rest are only reported
+    pub fn apply(&mut self, config: NodeConfig) -> ReloadReport {
+        let mut report = ReloadReport::default();
+
+        if config.log_level != self.current.log_level {
+            ::log::set_max_level(config.log_level.to_level_filter());
+            self.current.log_level = config.log_level;
+            report.applied.push("node.log_level".to_string());
+        }
+
+        if config.gossip_size != self.current.gossip_size {
+            self.store.set_gossip_size(config.gossip_size);
+            self.current.gossip_size = config.gossip_size;
+            report.applied.push("node.gossip_size".to_string());
+        }
+
+        if config.vivaldi != self.current.vivaldi {
+            self.store.set_vivaldi_params(config.vivaldi);
+            self.current.vivaldi = config.vivaldi;
+            report.applied.push("vivaldi".to_string());
+        }
+
+        if config.auth_token.as_ref().map(Token::secret) != self.current.auth_token.as_ref().map(Token::secret) {
+            // already authenticated clients stay connected
+            self.token.set(config.auth_token.clone());
+            self.current.auth_token = config.auth_token.clone();
+            report.applied.push("interface.auth_token_file".to_string());
+        }
+
+        // only agents probe other nodes
+        if let Some(ref control) = self.control {
+            // changing period postpones next probe, so unchanged one is skipped
+            let default_period = Duration::from_secs(PROBE_PERIOD_DEFAULT_SEC);
+            let period = config.probe_period.unwrap_or(default_period);
+            if period != self.current.probe_period.unwrap_or(default_period) {
+                let _ = control.unbounded_send(Control::SetProbePeriod(period));
+                self.current.probe_period = config.probe_period;
+                report.applied.push("probe.period".to_string());
+            }
+
+            if config.peer_selection != self.current.peer_selection {
+                let _ = control.unbounded_send(Control::SetPeerSelection(config.peer_selection));
+                self.current.peer_selection = config.peer_selection;
+                report.applied.push("probe.selector".to_string());
+            }
+        }
+
+        for name in &report.applied {
+            info!("{} applied", name);
+        }
+
+        let current = &self.current;
+        let restart = [
+            ("node.addr", config.node_addr != current.node_addr),
+            ("node.port", config.node_port != current.node_port),
+            ("node.name", config.node_name != current.node_name),
+            ("node.landmark", config.landmark_addr != current.landmark_addr),
+            ("node.shutdown_timeout", config.shutdown_timeout != current.shutdown_timeout),
+            ("interface.addr", config.interface_addr != current.interface_addr),
+            ("interface.http", config.http_addr != current.http_addr),
+            ("interface.admin", config.admin_addr != current.admin_addr),
+            ("interface.unix_socket", config.unix_socket != current.unix_socket),
+            ("interface.tls", config.tls != current.tls),
+            ("state", config.persist != current.persist),
+        ];
+        for &(name, changed) in restart.iter() {
+            if changed {
+                warn!("{} changed, restart to apply", name);
+                report.restart_required.push(name.to_string());
+            }
+        }
+
+        report
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agent::{vivaldi, NodeType};
+    use config::Loader;
+    use futures::sync::mpsc;
+    use futures::Stream;
+    use storage::Storage;
+
+    fn agent_config(toml: &str) -> NodeConfig {
+        Loader::new(NodeType::Regular)
+            .toml(toml)
+            .set("node.landmark", "10.0.0.1:3738")
+            .build()
+            .unwrap()
+    }
+
+    #[test]
+    fn runtime_settings_applied() {
+        let initial = agent_config("[node]\nport = 3737\n");
+        let store = Arc::new(Storage::new());
+        let token = SharedToken::new(None);
+        let (control, commands) = mpsc::unbounded();
+        let mut reloader = Reloader::new(initial, None, store.clone(), Some(control), token.clone());
+
+        assert!(reloader.reload().is_err());
+
+        let changed = agent_config(
+            "[node]\nport = 4000\ngossip_size = 8\n\n\
+             [probe]\nperiod = 5\nselector = \"least-recent\"\n\n\
+             [vivaldi]\nnode_error_coeff = 0.5\n",
+        );
+        let report = reloader.apply(changed.clone());
+        assert_eq!(report.applied, vec!["node.gossip_size", "vivaldi", "probe.period", "probe.selector"]);
+        assert_eq!(report.restart_required, vec!["node.port"]);
+
+        assert_eq!(store.gossip_size(), 8);
+        assert_eq!(store.vivaldi_params().node_error_coeff, 0.5);
+        assert_eq!(
+            store.vivaldi_params().local_error_coeff,
+            vivaldi::LOCAL_ERROR_WMA_COEFF_DEFAULT
+        );
+        assert!(token.get().is_none());
+
+        // applied settings are not reported again, restart is still required
+        let report = reloader.apply(changed);
+        assert!(report.applied.is_empty());
+        assert_eq!(report.restart_required, vec!["node.port"]);
+
+        drop(reloader);
+        let commands: Vec<Control> = commands.wait().map(|c| c.unwrap()).collect();
+        assert_eq!(
+            commands,
+            vec![
+                Control::SetProbePeriod(Duration::from_secs(5)),
+                Control::SetPeerSelection(::agent::selector::PeerSelection::LeastRecentlyProbed),
+            ]
+        );
+    }
+
+    /// Reload with given probe period must fail without touching transmitter
+    fn assert_period_rejected(period: Duration) {
+        let initial = agent_config("[node]\nport = 3737\n");
+        let mut changed = initial.clone();
+        changed.probe_period = Some(period);
+        let hook: ReloadHook = Box::new(move || Ok(changed.clone()));
+
+        let (control, commands) = mpsc::unbounded();
+        let store = Arc::new(Storage::new());
+        let mut reloader = Reloader::new(initial, Some(hook), store, Some(control), SharedToken::new(None));
+
+        match reloader.reload() {
+            Err(Error::Config(errors)) => assert_eq!(errors.0[0].path, "probe.period"),
+            other => panic!("unexpected result: {:?}", other),
+        }
+
+        drop(reloader);
+        assert_eq!(commands.wait().count(), 0);
+    }
+
+    #[test]
+    fn long_period_rejected() {
+        assert_period_rejected(Duration::from_secs(u64::MAX));
+    }
+
+    #[test]
+    fn zero_period_rejected() {
+        assert_period_rejected(Duration::from_secs(0));
+    }
+}
@@ -0,0 +1,283 @@
+//! Choose the next node to be probed.
+//!
+//! Strategy is defined at startup, possibly replaced on configuration
+//! reload, and used by transmitter on every probe. Every strategy
+//! selects the landmark while no other node is known, so bootstrap
+//! works the same way for each of them. Afterwards the landmark is
+//! one of the candidates of uniform and least-recently-probed choice
+//! and of the uniform branch of near-random choice, while highest-error
+//! choice selects it only when all known nodes were probed recently.
+
+use std::collections::{HashMap, VecDeque};
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use rand::{Isaac64Rng, Rng};
+
+use storage::{Node, Storage};
+
+// number of last receivers excluded from the highest-error selection
+const HIGHEST_ERROR_EXCLUDE_RECENT: usize = 4;
+
+pub const NEAR_FRACTION_DEFAULT: f32 = 0.5;
+pub const NEAR_NEIGHBOURS_DEFAULT: usize = 8;
+
+pub trait PeerSelector: Send {
+    /// Return address of the node to be probed next.
+    fn select(&mut self, store: &Storage, landmark: &SocketAddr) -> SocketAddr;
+}
+
+/// Peer selection strategies available from config.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub enum PeerSelection {
+    #[default]
+    Uniform,
+    NearRandom { near_fraction: f32, k: usize },
+    LeastRecentlyProbed,
+    HighestError,
+}
+
+impl PeerSelection {
+    pub fn from_name(name: &str, near_fraction: f32, k: usize) -> Option<Self> {
+        match name {
+            "uniform" => Some(PeerSelection::Uniform),
+            "near-random" => Some(PeerSelection::NearRandom { near_fraction, k }),
+            "least-recent" => Some(PeerSelection::LeastRecentlyProbed),
+            "highest-error" => Some(PeerSelection::HighestError),
+            _ => None,
+        }
+    }
+
+    /// Create selector implementing given strategy
+    pub fn build(&self) -> Box<dyn PeerSelector> {
+        match *self {
+            PeerSelection::Uniform => Box::new(UniformSelector),
+            PeerSelection::NearRandom { near_fraction, k } => {
+                Box::new(NearRandomSelector::new(near_fraction, k))
+            }
+            PeerSelection::LeastRecentlyProbed => Box::new(LeastRecentlySelector::new()),
+            PeerSelection::HighestError => Box::new(HighestErrorSelector::new()),
+        }
+    }
+}
+
+/* Strategies */
+
+/// Choose any known node with equal probability,
+/// landmark takes one extra slot.
+pub struct UniformSelector;
+
+impl PeerSelector for UniformSelector {
+    fn select(&mut self, store: &Storage, landmark: &SocketAddr) -> SocketAddr {
+        store.random_receiver(landmark)
+    }
+}
+
+/// With probability `near_fraction` choose one of the `k` nodes
+/// nearest to local position, otherwise fall back to uniform choice.
+///
+/// Mix of close and random far neighbours improves Vivaldi convergence.
+pub struct NearRandomSelector {
+    near_fraction: f32,
+    k: usize,
+    rng: Isaac64Rng,
+}
+
+impl NearRandomSelector {
+    pub fn new(near_fraction: f32, k: usize) -> Self {
+        NearRandomSelector {
+            near_fraction,
+            k,
+            rng: Isaac64Rng::new_unseeded(),
+        }
+    }
+}
+
+impl PeerSelector for NearRandomSelector {
+    fn select(&mut self, store: &Storage, landmark: &SocketAddr) -> SocketAddr {
+        if self.k == 0 || self.rng.next_f32() >= self.near_fraction {
+            return store.random_receiver(landmark);
+        }
+
+        let nearest = store.nearest(&store.get_location(), self.k);
+        if nearest.is_empty() {
+            return *landmark;
+        }
+
+        let idx = self.rng.gen_range(0, nearest.len());
+        nearest[idx].1.addr()
+    }
+}
+
+/// Choose node that was not probed for the longest time,
+/// nodes never probed before go first.
+pub struct LeastRecentlySelector {
+    probed: HashMap<SocketAddr, Instant>,
+}
+
+impl LeastRecentlySelector {
+    pub fn new() -> Self {
+        LeastRecentlySelector { probed: HashMap::new() }
+    }
+}
+
+impl Default for LeastRecentlySelector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeerSelector for LeastRecentlySelector {
+    fn select(&mut self, store: &Storage, landmark: &SocketAddr) -> SocketAddr {
+        let receiver = {
+            let probed = &self.probed;
+            store
+                .snapshot()
+                .nodes
+                .iter()
+                .map(Node::addr)
+                .chain(Some(*landmark))
+                .min_by_key(|addr| probed.get(addr).cloned())
+                .unwrap_or(*landmark)
+        };
+
+        self.probed.insert(receiver, Instant::now());
+        receiver
+    }
+}
+
+/// Choose node with the largest position error.
+///
+/// Few last receivers are skipped, so that unresponsive node
+/// with stalled error couldn't take all the probes.
+pub struct HighestErrorSelector {
+    recent: VecDeque<SocketAddr>,
+}
+
+impl HighestErrorSelector {
+    pub fn new() -> Self {
+        HighestErrorSelector {
+            recent: VecDeque::with_capacity(HIGHEST_ERROR_EXCLUDE_RECENT),
+        }
+    }
+}
+
+impl Default for HighestErrorSelector {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PeerSelector for HighestErrorSelector {
+    fn select(&mut self, store: &Storage, landmark: &SocketAddr) -> SocketAddr {
+        let receiver = {
+            let recent = &self.recent;
+            store
+                .snapshot()
+                .nodes
+                .iter()
+                .filter(|n| !recent.contains(&n.addr()))
+                .max_by(|a, b| {
+                    a.info
+                        .location
+                        .pos_err
+                        .partial_cmp(&b.info.location.pos_err)
+                        .unwrap_or(::std::cmp::Ordering::Equal)
+                })
+                .map(Node::addr)
+                .unwrap_or(*landmark)
+        };
+
+        if self.recent.len() >= HIGHEST_ERROR_EXCLUDE_RECENT {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(receiver);
+
+        receiver
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agent::{NodeCoordinates, NodeInfo};
+    use std::str::FromStr;
+
+    fn landmark() -> SocketAddr {
+        SocketAddr::from_str("5.5.5.5:3738").unwrap()
+    }
+
+    fn node(port: u16, x1: f32, pos_err: f32) -> NodeInfo {
+        let mut info = NodeInfo::new(
+            SocketAddr::from_str("10.0.0.1:1").unwrap().ip(),
+            port,
+            format!("{}", port),
+        );
+        info.set_coordinates(&NodeCoordinates {
+            x1,
+            pos_err,
+            iteration: 1,
+            ..Default::default()
+        });
+        info
+    }
+
+    #[test]
+    fn empty_storage_selects_landmark() {
+        let store = Storage::new();
+
+        for selection in &[
+            PeerSelection::Uniform,
+            PeerSelection::NearRandom { near_fraction: 1.0, k: 3 },
+            PeerSelection::LeastRecentlyProbed,
+            PeerSelection::HighestError,
+        ] {
+            let mut selector = selection.build();
+            assert_eq!(selector.select(&store, &landmark()), landmark());
+        }
+    }
+
+    #[test]
+    fn near_random_only_nearest() {
+        let store = Storage::new();
+        for i in 1..20 {
+            store.add_node(node(i, i as f32, 0.5));
+        }
+
+        let mut selector = NearRandomSelector::new(1.0, 3);
+        for _ in 0..100 {
+            let receiver = selector.select(&store, &landmark());
+            assert!(receiver.port() <= 3);
+        }
+    }
+
+    #[test]
+    fn least_recently_probed_round() {
+        let store = Storage::new();
+        for i in 1..5 {
+            store.add_node(node(i, 0.0, 0.5));
+        }
+
+        let mut selector = LeastRecentlySelector::new();
+        let mut selected: Vec<SocketAddr> =
+            (0..5).map(|_| selector.select(&store, &landmark())).collect();
+        selected.sort();
+        selected.dedup();
+
+        // every node and landmark probed exactly once
+        assert_eq!(selected.len(), 5);
+    }
+
+    #[test]
+    fn highest_error_first() {
+        let store = Storage::new();
+        store.add_node(node(1, 0.0, 0.1));
+        store.add_node(node(2, 0.0, 0.9));
+        store.add_node(node(3, 0.0, 0.5));
+
+        let mut selector = HighestErrorSelector::new();
+        assert_eq!(selector.select(&store, &landmark()).port(), 2);
+        assert_eq!(selector.select(&store, &landmark()).port(), 3);
+        assert_eq!(selector.select(&store, &landmark()).port(), 1);
+    }
+}
@@ -0,0 +1,33 @@
+/// Process signals handled by node
+///
+/// SIGINT and SIGTERM start graceful shutdown,
+/// SIGHUP reloads configuration.
+///
+use std::io;
+
+use futures::{Future, Stream};
+use tokio_signal::unix::{Signal as UnixSignal, SIGHUP, SIGINT, SIGTERM};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Signal {
+    Shutdown,
+    Reload,
+}
+
+/// Stream of received signals, handlers are installed on the first poll
+pub fn listen() -> impl Stream<Item = Signal, Error = io::Error> {
+    let interrupt = UnixSignal::new(SIGINT).flatten_stream();
+    let terminate = UnixSignal::new(SIGTERM).flatten_stream();
+    let hangup = UnixSignal::new(SIGHUP).flatten_stream();
+
+    let shutdown = interrupt.select(terminate).map(|signum| {
+        info!("{} received, shutting down", if signum == SIGINT { "SIGINT" } else { "SIGTERM" });
+        Signal::Shutdown
+    });
+    let reload = hangup.map(|_| {
+        info!("SIGHUP received, reloading configuration");
+        Signal::Reload
+    });
+
+    shutdown.select(reload)
+}
@@ -2,79 +2,149 @@
 ///
 /// If neighbour table is empty, send request to landmark node,
 /// otherwise - send regular Location request.
+///
+/// Transmitter only decides when and whom to probe, probes are passed
+/// to the receiver owning UDP socket, which stamps and sends them.
 
 use std::io;
-use std::time::Duration;
-use std::thread;
-use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+use std::net::SocketAddr;
+
+use futures::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::prelude::*;
+use tokio::timer::Delay;
 
-use agent::{BinarySerializable, NodeInfo, NodeList, GOSSIP_MAX_NEIGHBOURS_IN_MSG};
+use agent::NodeList;
 use agent::probe::ProbeRequest;
+use agent::selector::{PeerSelection, PeerSelector};
 use storage::SharedStorage;
 
+/// Commands changing transmitter behaviour at runtime
+#[derive(Debug, Clone, PartialEq)]
+pub enum Control {
+    /// Send probe immediately, to the given node or chosen by selector
+    ProbeNow(Option<SocketAddr>),
+    SetProbePeriod(Duration),
+    /// Replace selector, history of the previous one is dropped
+    SetPeerSelection(PeerSelection),
+}
+
+/// Probe request waiting to be sent, time is set by receiver
+#[derive(Debug)]
+pub struct Probe {
+    pub receiver: SocketAddr,
+    pub request: ProbeRequest,
+}
+
 pub struct Transmitter {
     name: String,
     landmark: SocketAddr,
     store: SharedStorage,
+    selector: Box<dyn PeerSelector>,
     transmission_interval: Duration,
-    sock: UdpSocket,
     local_addr: SocketAddr,
+    next_probe: Delay,
+    control: UnboundedReceiver<Control>,
+    control_closed: bool,
+    probes: UnboundedSender<Probe>,
 }
 
 impl Transmitter {
-    /// Create new transmitter object
+    /// Create new transmitter task, the first probe is sent immediately
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: String,
         landmark: SocketAddr,
         store: SharedStorage,
-        sock: UdpSocket,
+        selector: Box<dyn PeerSelector>,
+        local_addr: SocketAddr,
         transmission_interval: Duration,
+        control: UnboundedReceiver<Control>,
+        probes: UnboundedSender<Probe>,
     ) -> Self {
-        let local_addr = sock.local_addr().expect("couldn't obtain socket address");
         Transmitter {
             name,
             landmark,
             store,
+            selector,
             transmission_interval,
-            sock,
             local_addr,
+            next_probe: Delay::new(Instant::now()),
+            control,
+            control_closed: false,
+            probes,
         }
     }
 
-    /// Start sending probes
-    pub fn run(&self) -> io::Result<()> {
-        loop {
-            let (receiver, neighbours) = self.get_nodes();
-            debug!("probing {}:{}", receiver.ip(), receiver.port());
-
-            // create request
-            let mut request = ProbeRequest::new(self.name.clone());
-            if let Some(neighbours) = neighbours {
-                request.set_neighbours(neighbours);
+    fn command(&mut self, command: Control) -> io::Result<()> {
+        match command {
+            Control::ProbeNow(target) => {
+                let receiver = match target {
+                    Some(addr) => addr,
+                    None => self.selector.select(&self.store, &self.landmark),
+                };
+                self.probe(receiver)
             }
-
-            // set sending time immediately before serialization
-            request.set_current_time();
-
-            if let Some(encoded) = request.serialize() {
-                self.sock.send_to(&encoded, receiver)?;
+            Control::SetProbePeriod(period) => {
+                info!("probe period set to {:?}", period);
+                self.next_probe.reset(Instant::now() + period);
+                self.transmission_interval = period;
+                self.store.status().set_probe_period(period);
+                Ok(())
+            }
+            Control::SetPeerSelection(selection) => {
+                info!("peer selection set to {:?}", selection);
+                self.selector = selection.build();
+                Ok(())
             }
+        }
+    }
+
+    fn probe(&mut self, receiver: SocketAddr) -> io::Result<()> {
+        let neighbours = self.get_neighbours(receiver);
+        debug!("probing {}:{}", receiver.ip(), receiver.port());
 
-            // wait
-            thread::sleep(self.transmission_interval);
+        // create request
+        let mut request = ProbeRequest::new(self.name.clone());
+        if let Some(neighbours) = neighbours {
+            request.set_neighbours(neighbours);
         }
+
+        self.probes
+            .unbounded_send(Probe { receiver, request })
+            .map_err(|_| io::Error::new(io::ErrorKind::BrokenPipe, "receiver stopped"))
     }
 
-    fn get_nodes(&self) -> (SocketAddr, Option<NodeList>) {
-        let mut store = self.store.lock().unwrap();
-        let receiver = store.random_receiver(&self.landmark);
+    fn get_neighbours(&self, receiver: SocketAddr) -> Option<NodeList> {
+        self.store
+            .get_random_nodes(self.store.gossip_size(), &[self.local_addr, receiver])
+    }
+}
 
-        let neighbours: Option<NodeList> =
-            store
-                .get_random_nodes(GOSSIP_MAX_NEIGHBOURS_IN_MSG, &[self.local_addr, receiver])
-                .and_then(|nodes| Some(nodes.iter().map(|&n| (*n).clone()).collect()));
+/// Send probes until cancelled, serving control commands meanwhile
+impl Future for Transmitter {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<(), io::Error> {
+        while !self.control_closed {
+            match self.control.poll() {
+                Ok(Async::Ready(Some(command))) => self.command(command)?,
+                Ok(Async::Ready(None)) | Err(()) => self.control_closed = true,
+                Ok(Async::NotReady) => break,
+            }
+        }
 
-        (receiver, neighbours)
+        loop {
+            let fired = self.next_probe.poll().map_err(|e| io::Error::other(e.to_string()))?;
+            if fired.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+
+            let receiver = self.selector.select(&self.store, &self.landmark);
+            self.probe(receiver)?;
+            self.next_probe.reset(Instant::now() + self.transmission_interval);
+        }
     }
 }
 
@@ -82,41 +152,47 @@ impl Transmitter {
 mod tests {
     use super::*;
     use storage::{SharedStorage, Storage};
+    use agent::selector::PeerSelection;
 
-    use std::sync::{Arc, Mutex};
+    use agent::NodeInfo;
+    use futures::sync::mpsc;
+    use std::sync::Arc;
     use std::str::FromStr;
 
     #[test]
     #[ignore]
     fn node_samples() {
-        let mut store = Storage::new();
+        let store = Storage::new();
 
         // fill neighbours
         for i in 1..10 {
             let addr = SocketAddr::from_str(format!("127.0.0.1:{}", i).as_ref()).unwrap();
             store.add_node(NodeInfo::new(addr.ip(), addr.port(), format!("{}", i)));
         }
-        let s: SharedStorage = Arc::new(Mutex::new(store));
+        let s: SharedStorage = Arc::new(store);
 
-        let sock = SocketAddr::from_str("127.0.0.1:12345").unwrap();
-        let trans = Transmitter::new(
+        let mut trans = Transmitter::new(
             "test".to_string(),
             SocketAddr::from_str("5.5.5.5:12345").unwrap(),
             s,
-            UdpSocket::bind(sock).unwrap(),
+            PeerSelection::Uniform.build(),
+            SocketAddr::from_str("127.0.0.1:12345").unwrap(),
             Duration::new(1, 0),
+            mpsc::unbounded().1,
+            mpsc::unbounded().0,
         );
 
         // ensure that receiver never appears in node list
         for i in 1..100 {
-            if let (receiver, Some(nodes)) = trans.get_nodes() {
+            let receiver = trans.selector.select(&trans.store, &trans.landmark);
+            if let Some(nodes) = trans.get_neighbours(receiver) {
                 assert!(!nodes.contains(&NodeInfo::new(
                     receiver.ip(),
                     receiver.port(),
                     String::new(),
                 )));
             } else {
-                panic!("get_nodes() failed");
+                panic!("get_neighbours() failed");
             }
         }
     }
@@ -11,8 +11,26 @@ use rand::Rng;
 use super::NodeCoordinates;
 
 // paper recommended
-const NODE_ERROR_COEFF: f32 = 0.25; // C_c
-const LOCAL_ERROR_WMA_COEFF: f32 = 0.5; // C_e
+pub const NODE_ERROR_COEFF_DEFAULT: f32 = 0.25; // C_c
+pub const LOCAL_ERROR_WMA_COEFF_DEFAULT: f32 = 0.5; // C_e
+
+/// Tunable coefficients of the coordinates update
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Params {
+    /// Fraction of the way towards sample position, `C_c`
+    pub node_error_coeff: f32,
+    /// Weight of sample in moving average of position error, `C_e`
+    pub local_error_coeff: f32,
+}
+
+impl Default for Params {
+    fn default() -> Self {
+        Params {
+            node_error_coeff: NODE_ERROR_COEFF_DEFAULT,
+            local_error_coeff: LOCAL_ERROR_WMA_COEFF_DEFAULT,
+        }
+    }
+}
 
 /* Height-vector arithmetic */
 
@@ -107,6 +125,7 @@ pub fn compute_location<R: Rng>(
     local: &NodeCoordinates,
     remote: &NodeCoordinates,
     rtt_sec: f32,
+    params: &Params,
     rng: &mut R,
 ) -> NodeCoordinates {
     // w
@@ -119,11 +138,11 @@ pub fn compute_location<R: Rng>(
     let sample_err = (computed_distance - rtt_sec).abs() / rtt_sec;
 
     // e_i
-    let new_pos_err = sample_err * LOCAL_ERROR_WMA_COEFF * sample_weight +
-        local.pos_err * (1.0 - LOCAL_ERROR_WMA_COEFF * sample_weight);
+    let new_pos_err = sample_err * params.local_error_coeff * sample_weight +
+        local.pos_err * (1.0 - params.local_error_coeff * sample_weight);
 
     // delta
-    let timestep = NODE_ERROR_COEFF * sample_weight;
+    let timestep = params.node_error_coeff * sample_weight;
 
     // updated x_i
     let new_pos_vec = HeightVector2D::from(local) +
@@ -140,7 +159,7 @@ pub fn compute_location<R: Rng>(
 }
 
 /// Distance between two nodes in height-vector augmented Euclidean space
-fn node_distance(n1: &NodeCoordinates, n2: &NodeCoordinates) -> f32 {
+pub fn node_distance(n1: &NodeCoordinates, n2: &NodeCoordinates) -> f32 {
     (HeightVector2D::from(n1) - HeightVector2D::from(n2)).norm()
 }
 
@@ -2,6 +2,7 @@
 //!
 
 use std::net::ToSocketAddrs;
+use interface;
 use log;
 
 pub fn validate_name(name: String) -> Result<(), String> {
@@ -19,6 +20,13 @@ pub fn validate_interval(interval: String) -> Result<(), String> {
     }
 }
 
+pub fn validate_timeout(timeout: String) -> Result<(), String> {
+    match timeout.parse::<u16>() {
+        Ok(t) if t > 0 => Ok(()),
+        _ => Err(String::from("Timeout must be a positive number of seconds")),
+    }
+}
+
 pub fn validate_address(addr: String) -> Result<(), String> {
     match addr.to_socket_addrs() {
         Ok(_) => Ok(()),
@@ -26,6 +34,13 @@ pub fn validate_address(addr: String) -> Result<(), String> {
     }
 }
 
+pub fn validate_endpoint(endpoint: String) -> Result<(), String> {
+    match endpoint.parse::<interface::client_api::Endpoint>() {
+        Ok(_) => Ok(()),
+        Err(e) => Err(String::from(e)),
+    }
+}
+
 pub fn validate_port(port: String) -> Result<(), String> {
     match port.parse::<u16>() {
         Ok(_) => Ok(()),
@@ -33,6 +48,20 @@ pub fn validate_port(port: String) -> Result<(), String> {
     }
 }
 
+pub fn validate_fraction(fraction: String) -> Result<(), String> {
+    match fraction.parse::<f32>() {
+        Ok(f) if (0.0..=1.0).contains(&f) => Ok(()),
+        _ => Err(String::from("Fraction must be a number in range [0, 1]")),
+    }
+}
+
+pub fn validate_count(count: String) -> Result<(), String> {
+    match count.parse::<usize>() {
+        Ok(_) => Ok(()),
+        Err(_) => Err(String::from("Bad number provided")),
+    }
+}
+
 pub fn parse_log_level(level: &str) -> Option<log::Level> {
     match level {
         "debug" => Some(log::Level::Debug),
@@ -42,3 +71,17 @@ pub fn parse_log_level(level: &str) -> Option<log::Level> {
         _ => None,
     }
 }
+
+pub fn validate_mode(mode: String) -> Result<(), String> {
+    match interface::parse_mode(&mode) {
+        Some(_) => Ok(()),
+        None => Err(String::from("Mode must be octal permission bits, e.g. 660")),
+    }
+}
+
+pub fn validate_owner(owner: String) -> Result<(), String> {
+    match interface::parse_owner(&owner) {
+        Some(_) => Ok(()),
+        None => Err(String::from("Owner must be numeric uid[:gid]")),
+    }
+}
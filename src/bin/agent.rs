@@ -9,38 +9,69 @@ extern crate loggerv;
 extern crate netloc;
 
 use std::process;
-use std::net::{IpAddr, ToSocketAddrs};
-use std::str::FromStr;
-use std::time::Duration;
+use std::path::Path;
 
 use clap::{App, Arg};
 
-use netloc::{agent, arg_validator::*};
+use netloc::{agent, arg_validator::*, Error};
+use netloc::agent::NodeType;
+use netloc::config::{ConfigErrors, Loader};
 
-// fixme: parse for real and use errors (failure crate?)
-fn parse_args() -> Option<agent::NodeConfig> {
+/// Command-line flags and settings they override
+const FLAGS: &[(&str, &str)] = &[
+    ("addr", "node.addr"),
+    ("port", "node.port"),
+    ("name", "node.name"),
+    ("landmark", "node.landmark"),
+    ("log_level", "node.log_level"),
+    ("shutdown_timeout", "node.shutdown_timeout"),
+    ("period", "probe.period"),
+    ("selector", "probe.selector"),
+    ("near_fraction", "probe.near_fraction"),
+    ("near_k", "probe.near_k"),
+    ("state_file", "state.file"),
+    ("state_period", "state.period"),
+    ("state_max_age", "state.max_age"),
+    ("interface", "interface.addr"),
+    ("http", "interface.http"),
+    ("admin", "interface.admin"),
+    ("unix_socket", "interface.unix_socket"),
+    ("unix_socket_mode", "interface.unix_socket_mode"),
+    ("unix_socket_owner", "interface.unix_socket_owner"),
+    ("auth_token_file", "interface.auth_token_file"),
+    ("tls_cert", "interface.tls_cert"),
+    ("tls_key", "interface.tls_key"),
+];
+
+fn load_config() -> Result<agent::NodeConfig, ConfigErrors> {
     let args = App::new("netloc-agent")
         .version("0.1")
         .author("Anton Dort-Golts <dortgolts@gmail.com>")
         .about("Location agent of the Vivaldi network coordinate system")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("path")
+                .help("TOML configuration file, overridden by NETLOC_* environment variables and flags")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("addr")
                 .short("a")
                 .long("addr")
                 .value_name("IP-address")
-                .help("IP-address to listen on")
-                .takes_value(true)
-                .default_value("0.0.0.0"),
+                .help("IP-address to listen on [default: 0.0.0.0]")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("port")
                 .short("p")
                 .long("port")
                 .value_name("UDP-port")
-                .help("UDP-port to listen on")
+                .help("UDP-port to listen on [default: 3737]")
                 .takes_value(true)
-                .validator(validate_port)
-                .default_value("3737"),
+                .validator(validate_port),
         )
         .arg(
             Arg::with_name("name")
@@ -49,35 +80,79 @@ fn parse_args() -> Option<agent::NodeConfig> {
                 .value_name("name")
                 .help("Name of the agent")
                 .takes_value(true)
-                .validator(validate_name)
-                .default_value(""),
+                .validator(validate_name),
         )
         .arg(
             Arg::with_name("period")
                 .short("r")
                 .long("probe")
                 .value_name("period")
-                .help("Probe period in seconds")
+                .help("Probe period in seconds [default: 20]")
                 .takes_value(true)
-                .validator(validate_interval)
-                .default_value("20"),
+                .validator(validate_interval),
+        )
+        .arg(
+            Arg::with_name("selector")
+                .short("s")
+                .long("selector")
+                .value_name("strategy")
+                .help("Strategy of choosing nodes to probe [default: uniform]")
+                .takes_value(true)
+                .possible_values(&["uniform", "near-random", "least-recent", "highest-error"]),
+        )
+        .arg(
+            Arg::with_name("near_fraction")
+                .long("near-fraction")
+                .value_name("fraction")
+                .help("Fraction of probes sent to the nearest nodes (near-random strategy)")
+                .takes_value(true)
+                .validator(validate_fraction),
+        )
+        .arg(
+            Arg::with_name("near_k")
+                .long("near-k")
+                .value_name("number")
+                .help("Number of the nearest nodes to choose from (near-random strategy)")
+                .takes_value(true)
+                .validator(validate_count),
+        )
+        .arg(
+            Arg::with_name("state_file")
+                .long("state-file")
+                .value_name("path")
+                .help("File to save agent state to and restore it from on startup")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("state_period")
+                .long("state-period")
+                .value_name("seconds")
+                .help("Period of saving agent state")
+                .takes_value(true)
+                .validator(validate_interval),
+        )
+        .arg(
+            Arg::with_name("state_max_age")
+                .long("state-max-age")
+                .value_name("seconds")
+                .help("Discard saved nodes that were not updated for a given time")
+                .takes_value(true)
+                .validator(validate_count),
         )
         .arg(
             Arg::with_name("log_level")
                 .short("l")
                 .long("log")
                 .value_name("level")
-                .help("logging level")
+                .help("logging level [default: info]")
                 .takes_value(true)
-                .possible_values(&["debug", "info", "warn", "error"])
-                .default_value("info"),
+                .possible_values(&["debug", "info", "warn", "error"]),
         )
         .arg(
             Arg::with_name("landmark")
                 .value_name("landmark node")
                 .help("Address of landmark node")
                 .takes_value(true)
-                .required(true)
                 .validator(validate_address),
         )
         .arg(
@@ -85,68 +160,139 @@ fn parse_args() -> Option<agent::NodeConfig> {
                 .short("i")
                 .long("interface")
                 .value_name("address")
-                .help("Address of agent's informational interface")
+                .help("Address of agent's informational interface [default: 127.0.0.1:4001]")
+                .takes_value(true)
+                .validator(validate_address),
+        )
+        .arg(
+            Arg::with_name("http")
+                .long("http")
+                .value_name("address")
+                .help("Address of optional HTTP/JSON interface")
                 .takes_value(true)
-                .validator(validate_address)
-                .default_value("127.0.0.1:4001"),
+                .validator(validate_address),
+        )
+        .arg(
+            Arg::with_name("unix_socket")
+                .long("unix-socket")
+                .value_name("path")
+                .help("Path of Unix socket for local interface clients")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unix_socket_mode")
+                .long("unix-socket-mode")
+                .value_name("mode")
+                .help("Octal permissions of Unix socket, e.g. 660")
+                .takes_value(true)
+                .validator(validate_mode),
+        )
+        .arg(
+            Arg::with_name("unix_socket_owner")
+                .long("unix-socket-owner")
+                .value_name("uid[:gid]")
+                .help("Numeric owner and group of Unix socket")
+                .takes_value(true)
+                .validator(validate_owner),
+        )
+        .arg(
+            Arg::with_name("auth_token_file")
+                .long("auth-token-file")
+                .value_name("path")
+                .help("File with token required from interface clients")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls_cert")
+                .long("tls-cert")
+                .value_name("path")
+                .help("PEM-encoded certificate chain of interface TLS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls_key")
+                .long("tls-key")
+                .value_name("path")
+                .help("PEM-encoded private key of interface TLS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("admin")
+                .long("admin")
+                .value_name("address")
+                .help("Address of interface accepting administrative actions")
+                .takes_value(true)
+                .validator(validate_address),
+        )
+        .arg(
+            Arg::with_name("shutdown_timeout")
+                .long("shutdown-timeout")
+                .value_name("seconds")
+                .help("Time given to interface clients to receive pending responses on shutdown [default: 5]")
+                .takes_value(true)
+                .validator(validate_interval),
         )
         .get_matches();
 
-    // argument conversions
-    let agent_addr = IpAddr::from_str(args.value_of("addr")?).ok()?;
-    let agent_port = args.value_of("port")?.parse::<u16>().ok()?;
-    let agent_name = args.value_of("name")?.to_string();
-    let probe_period = args.value_of("period")
-        .and_then(|p| p.parse::<u16>().ok())
-        .and_then(|t| Some(Duration::new(t as u64, 0)));
-    let landmark_addr = args.value_of("landmark")
-        .and_then(|a| a.to_socket_addrs().ok())
-        .and_then(|mut a| a.next());
-    let interface_addr = args.value_of("interface")
-        .and_then(|a| a.to_socket_addrs().ok())
-        .and_then(|mut a| a.next());
-
-    let log_level = args.value_of("log_level").and_then(|l| parse_log_level(l))?;
-
-    let config = agent::NodeConfig {
-        node_addr: agent_addr,
-        node_port: agent_port,
-        node_name: agent_name,
-        probe_period,
-        interface_addr,
-        landmark_addr,
-        log_level,
-    };
+    let mut loader = Loader::new(NodeType::Regular);
+    if let Some(path) = args.value_of("config") {
+        loader = loader.file(Path::new(path));
+    }
+    loader = loader.env();
+    for &(flag, path) in FLAGS {
+        if let Some(value) = args.value_of(flag) {
+            loader = loader.set(path, value);
+        }
+    }
 
-    Some(config)
+    loader.build()
 }
 
 fn main() {
-    match parse_args() {
-        Some(config) => {
+    match load_config() {
+        Ok(config) => {
             // init logger
+            // logger accepts everything, level is limited globally to be changed on reload
             loggerv::Logger::new()
-                .max_level(config.log_level)
+                .max_level(log::Level::Trace)
                 .level(true)
                 .separator(" | ")
                 .colors(true)
                 .no_module_path()
                 .init()
                 .unwrap();
+            log::set_max_level(config.log_level.to_level_filter());
 
             info!(
                 "agent started at {}:{}",
                 config.node_addr, config.node_port
             );
 
-            if let Err(e) = agent::run_agent(&config) {
-                panic!("agent failure: {}", e);
+            // config file and arguments are read again on SIGHUP or admin request
+            let reload = || load_config().map_err(Error::from);
+            match agent::run_agent(&config, reload) {
+                Ok(()) => {}
+                Err(Error::Config(errors)) => {
+                    print_config_errors(&errors);
+                    process::exit(1);
+                }
+                Err(e) => {
+                    error!("agent failure: {}", e);
+                    process::exit(1);
+                }
             }
         }
 
-        None => {
-            println!("ERROR | cannot parse config options");
+        Err(errors) => {
+            print_config_errors(&errors);
             process::exit(1);
         }
     }
 }
+
+/// Logger may be not initialized yet, so errors are printed to stderr directly
+fn print_config_errors(errors: &ConfigErrors) {
+    for e in &errors.0 {
+        eprintln!("ERROR | config {}", e);
+    }
+}
@@ -15,105 +15,198 @@ extern crate log;
 extern crate loggerv;
 extern crate netloc;
 
-use std::net::{IpAddr, ToSocketAddrs};
-use std::str::FromStr;
+use std::path::Path;
 use std::process;
 
 use clap::{App, Arg};
-use netloc::{agent, arg_validator::*};
+use netloc::{agent, arg_validator::*, Error};
+use netloc::agent::NodeType;
+use netloc::config::{ConfigErrors, Loader};
 
-fn parse_args() -> Option<agent::NodeConfig> {
+/// Command-line flags and settings they override
+const FLAGS: &[(&str, &str)] = &[
+    ("addr", "node.addr"),
+    ("port", "node.port"),
+    ("log_level", "node.log_level"),
+    ("shutdown_timeout", "node.shutdown_timeout"),
+    ("interface", "interface.addr"),
+    ("http", "interface.http"),
+    ("unix_socket", "interface.unix_socket"),
+    ("unix_socket_mode", "interface.unix_socket_mode"),
+    ("unix_socket_owner", "interface.unix_socket_owner"),
+    ("auth_token_file", "interface.auth_token_file"),
+    ("tls_cert", "interface.tls_cert"),
+    ("tls_key", "interface.tls_key"),
+];
+
+fn load_config() -> Result<agent::NodeConfig, ConfigErrors> {
     let args = App::new("netloc-landmark")
         .version("0.1")
         .author("Anton Dort-Golts <dortgolts@gmail.com>")
         .about("Landmark node for the Vivaldi network coordinate system")
+        .arg(
+            Arg::with_name("config")
+                .short("c")
+                .long("config")
+                .value_name("path")
+                .help("TOML configuration file, overridden by NETLOC_* environment variables and flags")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("addr")
                 .short("a")
                 .long("addr")
                 .value_name("IP-address")
-                .help("IP-address to listen on")
-                .takes_value(true)
-                .default_value("0.0.0.0"),
+                .help("IP-address to listen on [default: 0.0.0.0]")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("port")
                 .short("p")
                 .long("port")
                 .value_name("UDP-port")
-                .help("UDP-port to listen on")
-                .takes_value(true)
-                .default_value("3738"),
+                .help("UDP-port to listen on [default: 3738]")
+                .takes_value(true),
         )
         .arg(
             Arg::with_name("log_level")
                 .short("l")
                 .long("log")
                 .value_name("level")
-                .help("logging level")
+                .help("logging level [default: info]")
                 .takes_value(true)
-                .possible_values(&["debug", "info", "warn", "error"])
-                .default_value("info"),
+                .possible_values(&["debug", "info", "warn", "error"]),
         )
         .arg(
             Arg::with_name("interface")
                 .short("i")
                 .long("interface")
                 .value_name("address")
-                .help("Address of agent's informational interface")
+                .help("Address of agent's informational interface [default: 127.0.0.1:4001]")
+                .takes_value(true)
+                .validator(validate_address),
+        )
+        .arg(
+            Arg::with_name("http")
+                .long("http")
+                .value_name("address")
+                .help("Address of optional HTTP/JSON interface")
+                .takes_value(true)
+                .validator(validate_address),
+        )
+        .arg(
+            Arg::with_name("unix_socket")
+                .long("unix-socket")
+                .value_name("path")
+                .help("Path of Unix socket for local interface clients")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("unix_socket_mode")
+                .long("unix-socket-mode")
+                .value_name("mode")
+                .help("Octal permissions of Unix socket, e.g. 660")
+                .takes_value(true)
+                .validator(validate_mode),
+        )
+        .arg(
+            Arg::with_name("unix_socket_owner")
+                .long("unix-socket-owner")
+                .value_name("uid[:gid]")
+                .help("Numeric owner and group of Unix socket")
                 .takes_value(true)
-                .validator(validate_address)
-                .default_value("127.0.0.1:4001"),
+                .validator(validate_owner),
+        )
+        .arg(
+            Arg::with_name("auth_token_file")
+                .long("auth-token-file")
+                .value_name("path")
+                .help("File with token required from interface clients")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls_cert")
+                .long("tls-cert")
+                .value_name("path")
+                .help("PEM-encoded certificate chain of interface TLS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("tls_key")
+                .long("tls-key")
+                .value_name("path")
+                .help("PEM-encoded private key of interface TLS")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shutdown_timeout")
+                .long("shutdown-timeout")
+                .value_name("seconds")
+                .help("Time given to interface clients to receive pending responses on shutdown [default: 5]")
+                .takes_value(true)
+                .validator(validate_interval),
         )
         .get_matches();
 
-    let agent_addr = IpAddr::from_str(args.value_of("addr")?).ok()?;
-    let agent_port = args.value_of("port")?.parse::<u16>().ok()?;
-    let agent_name = agent::LANDMARK_NODE_NAME.to_string();
-    let log_level = args.value_of("log_level").and_then(|l| parse_log_level(l))?;
-    let interface_addr = args.value_of("interface")
-        .and_then(|a| a.to_socket_addrs().ok())
-        .and_then(|mut a| a.next());
-
-    let config = agent::NodeConfig {
-        node_addr: agent_addr,
-        node_port: agent_port,
-        node_name: agent_name,
-        interface_addr,
-        log_level,
-        landmark_addr: None,
-        probe_period: None,
-    };
+    let mut loader = Loader::new(NodeType::Landmark);
+    if let Some(path) = args.value_of("config") {
+        loader = loader.file(Path::new(path));
+    }
+    loader = loader.env();
+    for &(flag, path) in FLAGS {
+        if let Some(value) = args.value_of(flag) {
+            loader = loader.set(path, value);
+        }
+    }
 
-    Some(config)
+    loader.build()
 }
 
 fn main() {
-    match parse_args() {
-        Some(config) => {
+    match load_config() {
+        Ok(config) => {
             // init logger
+            // logger accepts everything, level is limited globally to be changed on reload
             loggerv::Logger::new()
-                .max_level(config.log_level)
+                .max_level(log::Level::Trace)
                 .level(true)
                 .separator(" | ")
                 .colors(true)
                 .no_module_path()
                 .init()
                 .unwrap();
+            log::set_max_level(config.log_level.to_level_filter());
 
             info!(
                 "{} started at {}:{}",
                 config.node_name, config.node_addr, config.node_port
             );
 
-            if let Err(e) = agent::run_landmark(&config) {
-                error!("agent failure: {}", e);
+            // config file and arguments are read again on SIGHUP or admin request
+            let reload = || load_config().map_err(Error::from);
+            match agent::run_landmark(&config, reload) {
+                Ok(()) => {}
+                Err(Error::Config(errors)) => {
+                    print_config_errors(&errors);
+                    process::exit(1);
+                }
+                Err(e) => {
+                    error!("landmark failure: {}", e);
+                    process::exit(1);
+                }
             }
         }
 
-        None => {
-            println!("ERROR | argument parsing failed");
+        Err(errors) => {
+            print_config_errors(&errors);
             process::exit(1);
         }
     }
 }
+
+/// Logger may be not initialized yet, so errors are printed to stderr directly
+fn print_config_errors(errors: &ConfigErrors) {
+    for e in &errors.0 {
+        eprintln!("ERROR | config {}", e);
+    }
+}
@@ -0,0 +1,467 @@
+//! Command-line client of the node interface
+//!
+//! Queries running agent or landmark over its informational interface
+//! (TCP or Unix socket) and prints results as tables or JSON.
+//!
+extern crate clap;
+extern crate netloc;
+extern crate serde;
+extern crate serde_json;
+
+use std::net::SocketAddr;
+use std::path::Path;
+use std::process;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use clap::{App, AppSettings, Arg, ArgMatches, SubCommand};
+use serde::Serialize;
+
+use netloc::agent::{NodeCoordinates, NodeInfo};
+use netloc::arg_validator::*;
+use netloc::interface::Token;
+use netloc::interface::client_api::{self, Client, ClientConfig, Endpoint};
+use netloc::interface::proto::{Event, FullMapParams, NodeEstimate, NodeInfoFull, SubscribeParams};
+
+fn parse_args<'a>() -> ArgMatches<'a> {
+    App::new("netloc-ctl")
+        .version("0.1")
+        .author("Anton Dort-Golts <dortgolts@gmail.com>")
+        .about("Query agent or landmark over its informational interface")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .setting(AppSettings::VersionlessSubcommands)
+        .arg(
+            Arg::with_name("connect")
+                .short("c")
+                .long("connect")
+                .value_name("endpoint")
+                .help("Interface address, or Unix socket as unix:<path>")
+                .takes_value(true)
+                .global(true)
+                .validator(validate_endpoint)
+                .default_value("127.0.0.1:4001"),
+        )
+        .arg(
+            Arg::with_name("auth_token_file")
+                .long("auth-token-file")
+                .value_name("path")
+                .help("File with token required by interface")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("timeout")
+                .long("timeout")
+                .value_name("seconds")
+                .help("Timeout of each request")
+                .takes_value(true)
+                .global(true)
+                .validator(validate_timeout)
+                .default_value("10"),
+        )
+        .arg(
+            Arg::with_name("json")
+                .short("j")
+                .long("json")
+                .help("Print JSON instead of tables")
+                .global(true),
+        )
+        .subcommand(SubCommand::with_name("location").about("Coordinates of the node"))
+        .subcommand(
+            SubCommand::with_name("nodes")
+                .about("Nodes known to the node")
+                .arg(Arg::with_name("name_prefix").long("name-prefix").value_name("prefix").takes_value(true))
+                .arg(Arg::with_name("name_regex").long("name-regex").value_name("regex").takes_value(true))
+                .arg(
+                    Arg::with_name("network")
+                        .long("network")
+                        .value_name("cidr")
+                        .help("IP network of nodes, e.g. 10.0.0.0/8")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max_pos_err")
+                        .long("max-pos-err")
+                        .value_name("error")
+                        .takes_value(true)
+                        .validator(validate_fraction),
+                )
+                .arg(
+                    Arg::with_name("sort")
+                        .long("sort")
+                        .value_name("order")
+                        .takes_value(true)
+                        .possible_values(&["name", "distance", "freshness", "error"]),
+                )
+                .arg(
+                    Arg::with_name("limit")
+                        .long("limit")
+                        .value_name("N")
+                        .help("Print at most N nodes")
+                        .takes_value(true)
+                        .validator(validate_count),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("node")
+                .about("Information about single node")
+                .arg(Arg::with_name("addr").value_name("ip:port").required(true)),
+        )
+        .subcommand(
+            SubCommand::with_name("recent")
+                .about("Most recently updated nodes")
+                .arg(
+                    Arg::with_name("max")
+                        .long("max")
+                        .value_name("N")
+                        .takes_value(true)
+                        .validator(validate_count),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("nearest")
+                .about("Nodes with the lowest estimated RTT")
+                .arg(
+                    Arg::with_name("k")
+                        .short("k")
+                        .value_name("N")
+                        .takes_value(true)
+                        .validator(validate_count)
+                        .default_value("5"),
+                )
+                .arg(
+                    Arg::with_name("node")
+                        .long("node")
+                        .value_name("ip:port")
+                        .help("Reference node instead of the local one")
+                        .takes_value(true),
+                )
+                .arg(
+                    Arg::with_name("max_pos_err")
+                        .long("max-pos-err")
+                        .value_name("error")
+                        .takes_value(true)
+                        .validator(validate_fraction),
+                )
+                .arg(
+                    Arg::with_name("max_age")
+                        .long("max-age")
+                        .value_name("seconds")
+                        .takes_value(true)
+                        .validator(validate_count),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("estimate")
+                .about("Estimated RTT between two nodes, the local one by default")
+                .arg(Arg::with_name("a").value_name("ip:port").required(true))
+                .arg(Arg::with_name("b").value_name("ip:port")),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .about("Print changes of the overlay until interrupted")
+                .arg(
+                    Arg::with_name("node")
+                        .long("node")
+                        .value_name("ip:port")
+                        .help("Report only changes of the node")
+                        .takes_value(true)
+                        .multiple(true)
+                        .number_of_values(1),
+                )
+                .arg(
+                    Arg::with_name("interval")
+                        .long("interval")
+                        .value_name("ms")
+                        .help("How often changes are checked")
+                        .takes_value(true)
+                        .validator(validate_count),
+                ),
+        )
+        .get_matches()
+}
+
+fn client_config(args: &ArgMatches) -> Result<ClientConfig, String> {
+    // values are checked by validators
+    let endpoint: Endpoint = args.value_of("connect").unwrap().parse().map_err(String::from)?;
+    let timeout = args.value_of("timeout").unwrap().parse::<u64>().map_err(|e| e.to_string())?;
+
+    let mut config = ClientConfig::new(endpoint);
+    config.request_timeout = Duration::from_secs(timeout);
+    if let Some(path) = args.value_of("auth_token_file") {
+        let token = Token::from_file(Path::new(path)).map_err(|e| e.to_string())?;
+        config.auth_token = Some(token.secret().to_string());
+    }
+
+    Ok(config)
+}
+
+fn parsed<T: std::str::FromStr>(args: &ArgMatches, name: &str) -> Option<T> {
+    args.value_of(name).and_then(|v| v.parse().ok())
+}
+
+/// Columns aligned by the widest value
+struct Table {
+    rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    fn new(header: &[&str]) -> Self {
+        Table {
+            rows: vec![header.iter().map(|h| h.to_string()).collect()],
+        }
+    }
+
+    fn add(&mut self, row: Vec<String>) {
+        self.rows.push(row);
+    }
+
+    fn print(&self) {
+        let mut widths = vec![0; self.rows[0].len()];
+        for row in &self.rows {
+            for (width, value) in widths.iter_mut().zip(row) {
+                *width = (*width).max(value.len());
+            }
+        }
+
+        for row in &self.rows {
+            let line: Vec<String> = row
+                .iter()
+                .zip(&widths)
+                .map(|(value, width)| format!("{:<1$}", value, width))
+                .collect();
+            println!("{}", line.join("  ").trim_end());
+        }
+    }
+}
+
+fn coordinates(loc: &NodeCoordinates) -> Vec<String> {
+    vec![
+        format!("{:.4}", loc.x1),
+        format!("{:.4}", loc.x2),
+        format!("{:.4}", loc.height),
+        format!("{:.3}", loc.pos_err),
+        loc.iteration.to_string(),
+    ]
+}
+
+fn node_row(name: &str, addr: SocketAddr, loc: &NodeCoordinates) -> Vec<String> {
+    let mut row = vec![name.to_string(), addr.to_string()];
+    row.extend(coordinates(loc));
+    row
+}
+
+/// Age of UNIX timestamp, e.g. `15s`
+fn age(timestamp: u64) -> String {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or(0);
+    format!("{}s", now.saturating_sub(timestamp))
+}
+
+// nodes requested at once, the rest is read by cursor over the same connection
+const PAGE_SIZE: usize = 1000;
+
+const NODE_COLUMNS: [&str; 7] = ["NAME", "ADDRESS", "X1", "X2", "HEIGHT", "POS_ERR", "ITERATION"];
+
+fn print_nodes(nodes: &[NodeInfo]) {
+    let mut table = Table::new(&NODE_COLUMNS);
+    for node in nodes {
+        table.add(node_row(&node.name, SocketAddr::new(node.ip, node.port), &node.location));
+    }
+    table.print();
+}
+
+fn print_node(node: &NodeInfoFull) {
+    let mut columns = NODE_COLUMNS.to_vec();
+    columns.push("UPDATED");
+    let mut table = Table::new(&columns);
+
+    let mut row = node_row(&node.name, SocketAddr::new(node.ip, node.port), &node.location);
+    row.push(age(node.updated_at));
+    table.add(row);
+    table.print();
+}
+
+fn print_estimates(nodes: &[NodeEstimate]) {
+    let mut table = Table::new(&["NAME", "ADDRESS", "RTT_MS", "POS_ERR", "UPDATED"]);
+    for node in nodes {
+        let info = &node.info;
+        table.add(vec![
+            info.name.clone(),
+            SocketAddr::new(info.ip, info.port).to_string(),
+            format!("{:.2}", node.rtt * 1000.0),
+            format!("{:.3}", info.location.pos_err),
+            age(info.updated_at),
+        ]);
+    }
+    table.print();
+}
+
+fn print_event(event: &Event) {
+    let describe = |node: &NodeInfoFull| format!("{} {}", node.name, SocketAddr::new(node.ip, node.port));
+
+    match *event {
+        Event::LocationChanged { ref loc } => println!(
+            "location_changed x1={:.4} x2={:.4} height={:.4} pos_err={:.3}",
+            loc.x1, loc.x2, loc.height, loc.pos_err
+        ),
+        Event::NodeJoined { ref node } => println!("node_joined {}", describe(node)),
+        Event::NodeUpdated { ref node, shift } => {
+            println!("node_updated {} shift={:.4}", describe(node), shift)
+        }
+        Event::NodeExpired { ref node } => println!("node_expired {}", describe(node)),
+        Event::EventsDropped { count } => println!("events_dropped count={}", count),
+    }
+}
+
+fn print_json<T: Serialize>(value: &T) -> Result<(), String> {
+    let encoded = serde_json::to_string_pretty(value).map_err(|e| e.to_string())?;
+    println!("{}", encoded);
+    Ok(())
+}
+
+fn nodes(client: &mut Client, args: &ArgMatches, json: bool) -> Result<(), String> {
+    let limit = parsed::<usize>(args, "limit").unwrap_or(usize::MAX);
+    let mut params = FullMapParams {
+        name_prefix: args.value_of("name_prefix").map(String::from),
+        name_regex: args.value_of("name_regex").map(String::from),
+        network: args.value_of("network").map(String::from),
+        max_pos_err: parsed(args, "max_pos_err"),
+        sort: args
+            .value_of("sort")
+            .and_then(|s| serde_json::from_value(serde_json::Value::from(s)).ok()),
+        limit: Some(limit.min(PAGE_SIZE)),
+        ..FullMapParams::default()
+    };
+
+    // cursor is valid only within this connection, so all pages are read here
+    let mut map = client.get_full_map(params).map_err(|e| e.to_string())?;
+    while map.nodes.len() < limit {
+        let cursor = match map.next_cursor.take() {
+            Some(cursor) => cursor,
+            None => break,
+        };
+        params = FullMapParams {
+            cursor: Some(cursor),
+            limit: Some((limit - map.nodes.len()).min(PAGE_SIZE)),
+            ..FullMapParams::default()
+        };
+        let page = client.get_full_map(params).map_err(|e| e.to_string())?;
+        map.nodes.extend(page.nodes);
+        map.next_cursor = page.next_cursor;
+    }
+    let truncated = map.next_cursor.take().is_some();
+
+    if json {
+        print_json(&map)?;
+    } else {
+        print_nodes(&map.nodes);
+    }
+    if truncated {
+        eprintln!("more nodes match, increase --limit to print them");
+    }
+    Ok(())
+}
+
+fn watch(client: Client, args: &ArgMatches, json: bool) -> Result<(), String> {
+    let params = SubscribeParams {
+        nodes: args.values_of("node").map(|nodes| nodes.map(String::from).collect()),
+        interval_ms: parsed(args, "interval"),
+        ..SubscribeParams::default()
+    };
+
+    for event in client.subscribe(params).map_err(|e| e.to_string())? {
+        let event = event.map_err(|e| e.to_string())?;
+        if json {
+            println!("{}", serde_json::to_string(&event).map_err(|e| e.to_string())?);
+        } else {
+            print_event(&event);
+        }
+    }
+
+    Err("connection closed".to_string())
+}
+
+fn run(args: &ArgMatches) -> Result<(), String> {
+    let json = args.is_present("json");
+    let mut client = Client::connect(client_config(args)?).map_err(|e| e.to_string())?;
+    let fail = |e: client_api::Error| e.to_string();
+
+    match args.subcommand() {
+        ("location", Some(_)) => {
+            let loc = client.get_location().map_err(fail)?;
+            if json {
+                return print_json(&loc);
+            }
+            let mut table = Table::new(&NODE_COLUMNS[2..]);
+            table.add(coordinates(&loc));
+            table.print();
+        }
+
+        ("nodes", Some(sub)) => nodes(&mut client, sub, json)?,
+
+        ("node", Some(sub)) => {
+            let node = client.get_node_info(sub.value_of("addr").unwrap()).map_err(fail)?;
+            if json {
+                return print_json(&node);
+            }
+            print_node(&node);
+        }
+
+        ("recent", Some(sub)) => {
+            let nodes = client.get_recent_nodes(parsed(sub, "max")).map_err(fail)?;
+            if json {
+                return print_json(&nodes);
+            }
+            print_nodes(&nodes);
+        }
+
+        ("nearest", Some(sub)) => {
+            let nodes = client
+                .get_nearest_nodes(
+                    parsed(sub, "k").unwrap_or(5),
+                    sub.value_of("node"),
+                    None,
+                    parsed(sub, "max_pos_err"),
+                    parsed(sub, "max_age"),
+                )
+                .map_err(fail)?;
+            if json {
+                return print_json(&nodes);
+            }
+            print_estimates(&nodes);
+        }
+
+        ("estimate", Some(sub)) => {
+            let estimate = client
+                .estimate_rtt(sub.value_of("a").unwrap(), sub.value_of("b"))
+                .map_err(fail)?;
+            if json {
+                return print_json(&estimate);
+            }
+            let mut table = Table::new(&["RTT_MS", "POS_ERR_A", "POS_ERR_B"]);
+            table.add(vec![
+                format!("{:.2}", estimate.rtt * 1000.0),
+                format!("{:.3}", estimate.pos_err_a),
+                format!("{:.3}", estimate.pos_err_b),
+            ]);
+            table.print();
+        }
+
+        ("watch", Some(sub)) => watch(client, sub, json)?,
+
+        _ => return Err("unknown subcommand".to_string()),
+    }
+
+    Ok(())
+}
+
+fn main() {
+    let args = parse_args();
+
+    if let Err(e) = run(&args) {
+        eprintln!("ERROR | {}", e);
+        process::exit(1);
+    }
+}
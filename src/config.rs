@@ -0,0 +1,662 @@
+//! Configuration of agent and landmark
+//!
+//! Settings are read from TOML file, then overridden by environment
+//! variables and finally by command-line flags. Each setting is named
+//! by its section and key, e.g. `probe.period`, the same path is used
+//! in environment variable name (`NETLOC_PROBE_PERIOD`) and in errors.
+//!
+//! ```toml
+//! [node]
+//! addr = "10.0.0.2"
+//! port = 5001
+//! name = "web-1"
+//! landmark = "10.0.0.1:3738"
+//!
+//! [probe]
+//! period = 20
+//! selector = "near-random"
+//!
+//! [interface]
+//! addr = "127.0.0.1:4001"
+//! ```
+//!
+
+use std::collections::BTreeMap;
+use std::env;
+use std::error;
+use std::fmt;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use toml::Value;
+use toml::value::Table;
+
+use agent::{self, vivaldi, NodeConfig, NodeType};
+use agent::selector::{self, PeerSelection};
+use arg_validator::parse_log_level;
+use interface::{self, TlsConfig, Token, UnixSocketConfig};
+use persist::{self, PersistConfig};
+
+pub const ENV_PREFIX: &str = "NETLOC_";
+pub const AGENT_PORT_DEFAULT: u16 = 3737;
+pub const LANDMARK_PORT_DEFAULT: u16 = 3738;
+pub const INTERFACE_ADDR_DEFAULT: &str = "127.0.0.1:4001";
+const NAME_MAX_LEN: usize = 254;
+/// Longest period or timeout in seconds, the same as command-line flags allow
+pub const INTERVAL_MAX_SEC: u64 = u16::MAX as u64;
+
+/// Settings of both agent and landmark
+const COMMON_KEYS: &[&str] = &[
+    "node.addr",
+    "node.port",
+    "node.log_level",
+    "node.gossip_size",
+    "node.shutdown_timeout",
+    "interface.addr",
+    "interface.http",
+    "interface.unix_socket",
+    "interface.unix_socket_mode",
+    "interface.unix_socket_owner",
+    "interface.auth_token_file",
+    "interface.tls_cert",
+    "interface.tls_key",
+];
+
+/// Settings used only by agents
+const AGENT_KEYS: &[&str] = &[
+    "node.name",
+    "node.landmark",
+    "interface.admin",
+    "probe.period",
+    "probe.selector",
+    "probe.near_fraction",
+    "probe.near_k",
+    "vivaldi.node_error_coeff",
+    "vivaldi.local_error_coeff",
+    "state.file",
+    "state.period",
+    "state.max_age",
+];
+
+/// Invalid setting
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigError {
+    /// Setting path like `probe.period`, or path of unreadable file
+    pub path: String,
+    pub reason: String,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}: {}", self.path, self.reason)
+    }
+}
+
+/// All problems found in configuration
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigErrors(pub Vec<ConfigError>);
+
+impl fmt::Display for ConfigErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let errors: Vec<String> = self.0.iter().map(|e| e.to_string()).collect();
+        write!(f, "{}", errors.join("; "))
+    }
+}
+
+impl error::Error for ConfigErrors {}
+
+/// Check intervals of configuration built in code rather than loaded,
+/// timers can't be scheduled too far in the future
+pub fn check_intervals(config: &NodeConfig) -> Result<(), ConfigErrors> {
+    let max = Duration::from_secs(INTERVAL_MAX_SEC);
+    // zero period makes timers fire continuously, zero timeout is fine
+    let intervals = [
+        ("probe.period", config.probe_period, true),
+        ("node.shutdown_timeout", Some(config.shutdown_timeout), false),
+        ("state.period", config.persist.as_ref().map(|p| p.save_period), true),
+    ];
+
+    let errors: Vec<ConfigError> = intervals
+        .iter()
+        .filter_map(|&(path, interval, positive)| {
+            let interval = interval?;
+            let reason = if positive && (interval == Duration::from_secs(0) || interval > max) {
+                format!("expected positive number of seconds up to {}, got {:?}", INTERVAL_MAX_SEC, interval)
+            } else if interval > max {
+                format!("expected number of seconds up to {}, got {:?}", INTERVAL_MAX_SEC, interval)
+            } else {
+                return None;
+            };
+            Some(ConfigError {
+                path: path.to_string(),
+                reason,
+            })
+        })
+        .collect();
+
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(ConfigErrors(errors))
+    }
+}
+
+type Parse<T> = fn(&Value) -> Result<T, String>;
+
+/// Collects settings from all sources, later ones take precedence
+pub struct Loader {
+    node_type: NodeType,
+    values: BTreeMap<String, Value>,
+    errors: Vec<ConfigError>,
+}
+
+impl Loader {
+    pub fn new(node_type: NodeType) -> Self {
+        Loader {
+            node_type,
+            values: BTreeMap::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// Read settings from TOML file
+    pub fn file(self, path: &Path) -> Self {
+        match fs::read_to_string(path) {
+            Ok(text) => self.toml(&text),
+            Err(e) => self.reject(&path.display().to_string(), &e.to_string()),
+        }
+    }
+
+    /// Read settings from TOML document, unknown ones are reported
+    pub fn toml(mut self, text: &str) -> Self {
+        let document: Table = match text.parse::<Value>() {
+            Ok(Value::Table(document)) => document,
+            Ok(_) => return self.reject("config", "expected TOML document"),
+            Err(e) => return self.reject("config", &e.to_string()),
+        };
+
+        for (section, table) in document {
+            match table {
+                Value::Table(table) => {
+                    for (key, value) in table {
+                        self = self.set_value(&format!("{}.{}", section, key), value);
+                    }
+                }
+                _ => self = self.reject(&section, "expected section"),
+            }
+        }
+        self
+    }
+
+    /// Override settings with `NETLOC_<SECTION>_<KEY>` variables of process environment
+    pub fn env(self) -> Self {
+        self.env_vars(env::vars())
+    }
+
+    /// Override settings with given environment variables, irrelevant ones are ignored
+    pub fn env_vars<I>(mut self, vars: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let vars: BTreeMap<String, String> = vars.into_iter().collect();
+        for key in self.keys() {
+            if let Some(value) = vars.get(&env_name(key)) {
+                self.values.insert(key.to_string(), Value::String(value.clone()));
+            }
+        }
+        self
+    }
+
+    /// Override single setting, e.g. with command-line flag
+    pub fn set(self, path: &str, value: &str) -> Self {
+        self.set_value(path, Value::String(value.to_string()))
+    }
+
+    /// Validate settings, reporting all problems at once
+    pub fn build(mut self) -> Result<NodeConfig, ConfigErrors> {
+        let is_agent = self.node_type == NodeType::Regular;
+
+        let node_addr = self
+            .get("node.addr", ip_address)
+            .unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+        let default_port = if is_agent { AGENT_PORT_DEFAULT } else { LANDMARK_PORT_DEFAULT };
+        let node_port = self.get("node.port", port).unwrap_or(default_port);
+        let node_name = match self.node_type {
+            NodeType::Regular => self.get("node.name", name).unwrap_or_default(),
+            NodeType::Landmark => agent::LANDMARK_NODE_NAME.to_string(),
+        };
+        let log_level = self.get("node.log_level", log_level).unwrap_or(::log::Level::Info);
+        let gossip_size = self
+            .get("node.gossip_size", gossip_size)
+            .unwrap_or(agent::GOSSIP_MAX_NEIGHBOURS_IN_MSG);
+        let shutdown_timeout = self
+            .get("node.shutdown_timeout", timeout)
+            .unwrap_or_else(|| Duration::from_secs(agent::SHUTDOWN_TIMEOUT_DEFAULT_SEC));
+
+        let landmark_addr = if is_agent {
+            if !self.values.contains_key("node.landmark") {
+                self = self.reject("node.landmark", "landmark address is required");
+            }
+            self.get("node.landmark", address)
+        } else {
+            None
+        };
+
+        // interface
+        let interface_addr = if self.values.contains_key("interface.addr") {
+            self.get("interface.addr", address)
+        } else {
+            INTERFACE_ADDR_DEFAULT.parse().ok()
+        };
+        let http_addr = self.get("interface.http", address);
+        let admin_addr = self.get("interface.admin", address);
+        let unix_socket = self.unix_socket();
+        let auth_token = self.get("interface.auth_token_file", auth_token);
+        let tls = self.tls();
+
+        // probing
+        let probe_period = self.get("probe.period", period);
+        let near_fraction = self
+            .get("probe.near_fraction", fraction)
+            .unwrap_or(selector::NEAR_FRACTION_DEFAULT);
+        let near_k = self
+            .get("probe.near_k", positive::<usize>)
+            .unwrap_or(selector::NEAR_NEIGHBOURS_DEFAULT);
+        let peer_selection = match self.get("probe.selector", parsed::<String>) {
+            Some(name) => match PeerSelection::from_name(&name, near_fraction, near_k) {
+                Some(selection) => selection,
+                None => {
+                    let reason = "expected uniform, near-random, least-recent or highest-error";
+                    self = self.reject("probe.selector", reason);
+                    PeerSelection::default()
+                }
+            },
+            None => PeerSelection::default(),
+        };
+
+        let defaults = vivaldi::Params::default();
+        let vivaldi = vivaldi::Params {
+            node_error_coeff: self
+                .get("vivaldi.node_error_coeff", coefficient)
+                .unwrap_or(defaults.node_error_coeff),
+            local_error_coeff: self
+                .get("vivaldi.local_error_coeff", coefficient)
+                .unwrap_or(defaults.local_error_coeff),
+        };
+
+        let persist = self.persist();
+
+        let config = NodeConfig {
+            node_addr,
+            node_port,
+            node_name,
+            probe_period,
+            interface_addr,
+            http_addr,
+            admin_addr,
+            unix_socket,
+            auth_token,
+            tls,
+            landmark_addr,
+            peer_selection,
+            gossip_size,
+            vivaldi,
+            persist,
+            shutdown_timeout,
+            log_level,
+        };
+
+        if self.errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigErrors(self.errors))
+        }
+    }
+
+    fn unix_socket(&mut self) -> Option<UnixSocketConfig> {
+        self.require("interface.unix_socket", &["interface.unix_socket_mode", "interface.unix_socket_owner"]);
+        let path = self.get("interface.unix_socket", parsed::<PathBuf>);
+        let mode = self.get("interface.unix_socket_mode", mode);
+        let owner = self.get("interface.unix_socket_owner", owner);
+
+        path.map(|path| UnixSocketConfig {
+            path,
+            mode,
+            owner: owner.and_then(|(uid, _)| uid),
+            group: owner.and_then(|(_, gid)| gid),
+        })
+    }
+
+    fn tls(&mut self) -> Option<TlsConfig> {
+        let cert_path = self.get("interface.tls_cert", parsed::<PathBuf>);
+        let key_path = self.get("interface.tls_key", parsed::<PathBuf>);
+
+        match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig { cert_path, key_path }),
+            (Some(_), None) => {
+                self.reject_mut("interface.tls_cert", "requires interface.tls_key");
+                None
+            }
+            (None, Some(_)) => {
+                self.reject_mut("interface.tls_key", "requires interface.tls_cert");
+                None
+            }
+            (None, None) => None,
+        }
+    }
+
+    fn persist(&mut self) -> Option<PersistConfig> {
+        self.require("state.file", &["state.period", "state.max_age"]);
+        let path = self.get("state.file", parsed::<PathBuf>);
+        let save_period = self
+            .get("state.period", period)
+            .unwrap_or_else(|| Duration::from_secs(persist::STATE_SAVE_PERIOD_DEFAULT));
+        let max_age = self
+            .get("state.max_age", seconds)
+            .unwrap_or_else(|| Duration::from_secs(persist::STATE_MAX_AGE_DEFAULT));
+
+        path.map(|path| PersistConfig {
+            path,
+            save_period,
+            max_age,
+        })
+    }
+
+    /// Settings relevant for the node type
+    fn keys(&self) -> Vec<&'static str> {
+        let mut keys = COMMON_KEYS.to_vec();
+        if self.node_type == NodeType::Regular {
+            keys.extend_from_slice(AGENT_KEYS);
+        }
+        keys
+    }
+
+    fn set_value(mut self, path: &str, value: Value) -> Self {
+        if self.keys().contains(&path) {
+            self.values.insert(path.to_string(), value);
+            self
+        } else if AGENT_KEYS.contains(&path) {
+            self.reject(path, "not supported by landmark")
+        } else {
+            self.reject(path, "unknown setting")
+        }
+    }
+
+    /// Take setting out and parse it, recording failure
+    fn get<T>(&mut self, path: &str, parse: Parse<T>) -> Option<T> {
+        let value = self.values.remove(path)?;
+        match parse(&value) {
+            Ok(parsed) => Some(parsed),
+            Err(reason) => {
+                self.reject_mut(path, &reason);
+                None
+            }
+        }
+    }
+
+    /// Report and drop settings given without the one they depend on
+    fn require(&mut self, required: &str, dependent: &[&str]) {
+        if self.values.contains_key(required) {
+            return;
+        }
+        for path in dependent {
+            if self.values.remove(*path).is_some() {
+                self.reject_mut(path, &format!("requires {}", required));
+            }
+        }
+    }
+
+    fn reject(mut self, path: &str, reason: &str) -> Self {
+        self.reject_mut(path, reason);
+        self
+    }
+
+    fn reject_mut(&mut self, path: &str, reason: &str) {
+        self.errors.push(ConfigError {
+            path: path.to_string(),
+            reason: reason.to_string(),
+        });
+    }
+}
+
+/// Name of environment variable overriding the setting
+pub fn env_name(path: &str) -> String {
+    format!("{}{}", ENV_PREFIX, path.replace('.', "_").to_uppercase())
+}
+
+/// Scalar value as text, e.g. to parse numbers given as strings
+fn text(value: &Value) -> Option<String> {
+    match *value {
+        Value::String(ref s) => Some(s.trim().to_string()),
+        Value::Integer(i) => Some(i.to_string()),
+        Value::Float(f) => Some(f.to_string()),
+        Value::Boolean(b) => Some(b.to_string()),
+        _ => None,
+    }
+}
+
+fn parsed<T: FromStr>(value: &Value) -> Result<T, String> {
+    text(value)
+        .and_then(|text| text.parse().ok())
+        .ok_or_else(|| format!("invalid value {}", value))
+}
+
+fn positive<T: FromStr + PartialOrd + Default>(value: &Value) -> Result<T, String> {
+    match parsed::<T>(value) {
+        Ok(number) if number > T::default() => Ok(number),
+        _ => Err(format!("expected positive number, got {}", value)),
+    }
+}
+
+fn seconds(value: &Value) -> Result<Duration, String> {
+    parsed::<u64>(value)
+        .map(Duration::from_secs)
+        .map_err(|_| format!("expected number of seconds, got {}", value))
+}
+
+fn timeout(value: &Value) -> Result<Duration, String> {
+    match parsed::<u64>(value) {
+        Ok(secs) if secs <= INTERVAL_MAX_SEC => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("expected number of seconds up to {}, got {}", INTERVAL_MAX_SEC, value)),
+    }
+}
+
+fn period(value: &Value) -> Result<Duration, String> {
+    match positive::<u64>(value) {
+        Ok(secs) if secs <= INTERVAL_MAX_SEC => Ok(Duration::from_secs(secs)),
+        _ => Err(format!("expected positive number of seconds up to {}, got {}", INTERVAL_MAX_SEC, value)),
+    }
+}
+
+fn ip_address(value: &Value) -> Result<IpAddr, String> {
+    parsed(value).map_err(|_| format!("expected IP address, got {}", value))
+}
+
+fn port(value: &Value) -> Result<u16, String> {
+    parsed(value).map_err(|_| format!("expected port number from 0 to 65535, got {}", value))
+}
+
+fn address(value: &Value) -> Result<SocketAddr, String> {
+    text(value)
+        .and_then(|text| text.to_socket_addrs().ok())
+        .and_then(|mut addrs| addrs.next())
+        .ok_or_else(|| format!("expected address like host:port, got {}", value))
+}
+
+fn name(value: &Value) -> Result<String, String> {
+    match *value {
+        Value::String(ref name) if name.len() <= NAME_MAX_LEN => Ok(name.clone()),
+        Value::String(_) => Err(format!("name is longer than {} bytes", NAME_MAX_LEN)),
+        _ => Err(format!("expected string, got {}", value)),
+    }
+}
+
+fn log_level(value: &Value) -> Result<::log::Level, String> {
+    text(value)
+        .and_then(|level| parse_log_level(&level))
+        .ok_or_else(|| format!("expected debug, info, warn or error, got {}", value))
+}
+
+fn gossip_size(value: &Value) -> Result<usize, String> {
+    match parsed::<usize>(value) {
+        Ok(size) if size <= agent::GOSSIP_SIZE_MAX => Ok(size),
+        _ => Err(format!("expected number from 0 to {}, got {}", agent::GOSSIP_SIZE_MAX, value)),
+    }
+}
+
+fn fraction(value: &Value) -> Result<f32, String> {
+    match parsed::<f32>(value) {
+        Ok(fraction) if (0.0..=1.0).contains(&fraction) => Ok(fraction),
+        _ => Err(format!("expected number in range [0, 1], got {}", value)),
+    }
+}
+
+/// Vivaldi coefficient, zero would stop coordinates update
+fn coefficient(value: &Value) -> Result<f32, String> {
+    match parsed::<f32>(value) {
+        Ok(coeff) if coeff > 0.0 && coeff <= 1.0 => Ok(coeff),
+        _ => Err(format!("expected number in range (0, 1], got {}", value)),
+    }
+}
+
+fn mode(value: &Value) -> Result<u32, String> {
+    text(value)
+        .and_then(|mode| interface::parse_mode(&mode))
+        .ok_or_else(|| format!("expected octal permissions like 660, got {}", value))
+}
+
+fn owner(value: &Value) -> Result<(Option<u32>, Option<u32>), String> {
+    text(value)
+        .and_then(|owner| interface::parse_owner(&owner))
+        .ok_or_else(|| format!("expected numeric uid[:gid], got {}", value))
+}
+
+fn auth_token(value: &Value) -> Result<Token, String> {
+    let path = parsed::<PathBuf>(value)?;
+    Token::from_file(&path).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FILE: &str = r#"
+        [node]
+        port = 5001
+        name = "file"
+        landmark = "10.0.0.1:3738"
+        gossip_size = 8
+
+        [probe]
+        period = 10
+        selector = "near-random"
+        near_k = 3
+
+        [vivaldi]
+        node_error_coeff = 0.1
+    "#;
+
+    fn vars(vars: &[(&str, &str)]) -> Vec<(String, String)> {
+        vars.iter().map(|&(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn sources_precedence() {
+        let config = Loader::new(NodeType::Regular)
+            .toml(FILE)
+            .env_vars(vars(&[("NETLOC_NODE_NAME", "env"), ("NETLOC_PROBE_PERIOD", "30")]))
+            .set("node.name", "flag")
+            .build()
+            .unwrap();
+
+        assert_eq!(config.node_port, 5001);
+        assert_eq!(config.node_name, "flag");
+        assert_eq!(config.probe_period, Some(Duration::from_secs(30)));
+        assert_eq!(config.landmark_addr, "10.0.0.1:3738".parse().ok());
+        assert_eq!(config.gossip_size, 8);
+        assert_eq!(config.vivaldi.node_error_coeff, 0.1);
+        assert_eq!(config.peer_selection, PeerSelection::NearRandom {
+            near_fraction: selector::NEAR_FRACTION_DEFAULT,
+            k: 3,
+        });
+        assert_eq!(config.interface_addr, INTERFACE_ADDR_DEFAULT.parse().ok());
+    }
+
+    #[test]
+    fn all_errors_reported() {
+        let errors = Loader::new(NodeType::Regular)
+            .toml("[node]\nport = 70000\ncolour = \"red\"\n[probe]\nperiod = 0\n[state]\nperiod = 5")
+            .env_vars(vars(&[("NETLOC_VIVALDI_LOCAL_ERROR_COEFF", "2")]))
+            .build()
+            .unwrap_err();
+
+        let paths: Vec<&str> = errors.0.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec![
+            "node.colour",
+            "node.port",
+            "node.landmark",
+            "probe.period",
+            "vivaldi.local_error_coeff",
+            "state.period",
+        ]);
+        assert_eq!(errors.0[0].reason, "unknown setting");
+        assert_eq!(errors.0[5].reason, "requires state.file");
+    }
+
+    #[test]
+    fn intervals_bounded() {
+        let errors = Loader::new(NodeType::Regular)
+            .toml("[node]\nlandmark = \"10.0.0.1:3738\"\nshutdown_timeout = 65536\n[probe]\nperiod = \"18446744073709551615\"")
+            .env_vars(vars(&[("NETLOC_STATE_FILE", "/tmp/state"), ("NETLOC_STATE_PERIOD", "100000")]))
+            .build()
+            .unwrap_err();
+
+        let paths: Vec<&str> = errors.0.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["node.shutdown_timeout", "probe.period", "state.period"]);
+
+        let mut config = Loader::new(NodeType::Regular)
+            .toml("[node]\nlandmark = \"10.0.0.1:3738\"\nshutdown_timeout = 65535")
+            .build()
+            .unwrap();
+        assert_eq!(check_intervals(&config), Ok(()));
+
+        // configuration built in code is checked separately
+        config.probe_period = Some(Duration::from_secs(u64::MAX));
+        let errors = check_intervals(&config).unwrap_err();
+        assert_eq!(
+            errors.to_string(),
+            "probe.period: expected positive number of seconds up to 65535, got 18446744073709551615s"
+        );
+
+        config.probe_period = Some(Duration::from_secs(0));
+        config.shutdown_timeout = Duration::from_secs(0);
+        config.persist = Some(PersistConfig {
+            path: PathBuf::from("/tmp/state"),
+            save_period: Duration::from_secs(0),
+            max_age: Duration::from_secs(60),
+        });
+        let paths: Vec<String> = check_intervals(&config).unwrap_err().0.into_iter().map(|e| e.path).collect();
+        assert_eq!(paths, vec!["probe.period", "state.period"]);
+    }
+
+    #[test]
+    fn landmark_settings() {
+        let errors = Loader::new(NodeType::Landmark)
+            .toml(FILE)
+            .build()
+            .unwrap_err();
+        assert!(errors.0.iter().all(|e| e.reason == "not supported by landmark"));
+
+        // agent settings in environment are ignored
+        let config = Loader::new(NodeType::Landmark)
+            .env_vars(vars(&[("NETLOC_PROBE_PERIOD", "30"), ("NETLOC_NODE_PORT", "4000")]))
+            .build()
+            .unwrap();
+        assert_eq!(config.node_port, 4000);
+        assert_eq!(config.node_name, agent::LANDMARK_NODE_NAME);
+        assert_eq!(config.probe_period, None);
+    }
+}
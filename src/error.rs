@@ -0,0 +1,86 @@
+//! Errors of the library
+//!
+//! Failures are described in terms of the node rather than of the
+//! underlying call, e.g. which address couldn't be bound or which file
+//! couldn't be read, so that binaries print them as is.
+//!
+
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::result;
+
+use config::ConfigErrors;
+
+pub type Result<T> = result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Invalid settings, all problems found at once
+    Config(ConfigErrors),
+    /// UDP socket of the node or interface listener cannot be bound
+    Bind { addr: String, source: io::Error },
+    /// Malformed message of the node protocol
+    Decode(&'static str),
+    /// Message cannot be encoded
+    Encode(&'static str),
+    /// State file cannot be saved or restored
+    Storage { path: PathBuf, source: io::Error },
+    /// Interface cannot be set up, e.g. TLS key or auth token is unreadable
+    Interface {
+        what: &'static str,
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// Node cannot serve the request or is not running
+    Node(&'static str),
+    /// Failure of the running node
+    Io(io::Error),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Config(ref errors) => write!(f, "invalid configuration: {}", errors),
+            Error::Bind { ref addr, ref source } => write!(f, "cannot bind {}: {}", addr, source),
+            Error::Decode(reason) => write!(f, "malformed message: {}", reason),
+            Error::Encode(reason) => write!(f, "cannot encode message: {}", reason),
+            Error::Storage { ref path, ref source } => {
+                write!(f, "state file {}: {}", path.display(), source)
+            }
+            Error::Interface {
+                what,
+                ref path,
+                ref source,
+            } => write!(f, "{} {}: {}", what, path.display(), source),
+            Error::Node(reason) => write!(f, "{}", reason),
+            Error::Io(ref e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl error::Error for Error {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match *self {
+            Error::Config(ref errors) => Some(errors),
+            Error::Bind { ref source, .. }
+            | Error::Storage { ref source, .. }
+            | Error::Interface { ref source, .. } => Some(source),
+            Error::Io(ref e) => Some(e),
+            Error::Decode(_) | Error::Encode(_) | Error::Node(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        Error::Io(e)
+    }
+}
+
+impl From<ConfigErrors> for Error {
+    fn from(errors: ConfigErrors) -> Self {
+        Error::Config(errors)
+    }
+}
@@ -1,52 +1,593 @@
 /// Request processing module
 ///
 use std::net::SocketAddr;
+use std::sync::PoisonError;
+use std::time::Duration;
+
+use futures::sync::mpsc::UnboundedSender;
 
 use agent::NodeList;
-use storage::SharedStorage;
-use super::proto::{Request, Response, NodeInfoFull};
-use super::proto::{REASON_NODE_NOT_FOUND, REASON_BAD_NODE_ADDR, REASON_NO_INFORMATION};
+use agent::{vivaldi, NodeCoordinates, NodeInfo};
+use agent::{Control, SharedReloader};
+use config::INTERVAL_MAX_SEC;
+use status;
+use storage::{now_sec, Node, SharedStorage, StorageView};
+use super::proto::{ErrorDetails, Failure, Request, Response, NodeInfoFull, NodeEstimate, Point};
+use super::proto::{RankedCandidate, UnknownPlacement};
+use super::proto::{REASON_NODE_NOT_FOUND, REASON_NO_INFORMATION};
+use super::proto::{REASON_AMBIGUOUS_REFERENCE, REASON_TOO_MANY_NODES, REASON_NOT_SUPPORTED};
+use super::proto::{REASON_ADMIN_DISABLED, REASON_BAD_PERIOD, REASON_RELOAD_FAILED};
+use super::query::{full_map, Cursors};
 
 const NUM_RECENT_NODES_DEFAULT: usize = 10;
-const ERR_LOCK_FAILED: &str = "lock failed";
+const MAX_DISTANCE_MATRIX_NODES: usize = 256;
+const MIN_PROBE_PERIOD_MS: u64 = 100;
+const MAX_PROBE_PERIOD_MS: u64 = INTERVAL_MAX_SEC * 1000;
 
-pub fn process_request(request: Request, store: &mut SharedStorage) -> Response {
+pub fn process_request(request: Request, store: &dyn StorageView, cursors: &mut Cursors) -> Response {
     debug!("get request: {:?}", request);
+    store.metrics().interface_request(request.action());
+
     match request {
         Request::GetLocation => {
-            let location = store.lock().expect(ERR_LOCK_FAILED).get_location();
+            let location = store.get_location();
             Response::Location { loc: location }
         }
 
-        Request::GetFullMap => {
-            let nodes = store.lock().expect(ERR_LOCK_FAILED).get_all_nodes();
-            Response::FullMap { nodes }
-        }
+        Request::GetFullMap(params) => full_map(params, store, cursors),
 
         Request::GetNodeInfo { node_addr } => {
-            node_addr
-                .parse()
-                .and_then(|addr| match store
-                    .lock()
-                    .expect(ERR_LOCK_FAILED)
-                    .find_node(addr) {
-                    Some(info) => Ok(Response::NodeInfo { info: NodeInfoFull::from(info) }),
-                    None => Ok(Response::Failure { reason: REASON_NODE_NOT_FOUND }),
+            parse_addr(&node_addr)
+                .map(|addr| match store.find_node(addr) {
+                    Some(info) => Response::NodeInfo { info: NodeInfoFull::from(info) },
+                    None => Response::failure(REASON_NODE_NOT_FOUND),
                 })
-                .unwrap_or(Response::Failure { reason: REASON_BAD_NODE_ADDR })
+                .unwrap_or_else(Response::from)
         }
 
         Request::GetRecentNodes { max_nodes } => {
-            match store.lock().expect(ERR_LOCK_FAILED).get_most_recent(
-                max_nodes.unwrap_or(
-                    NUM_RECENT_NODES_DEFAULT,
-                ),
-            ) {
-                Some(info) => Response::RecentNodes {
-                    nodes: info.iter().map(|&n| n.clone()).collect(),
+            match store.snapshot().most_recent(max_nodes.unwrap_or(NUM_RECENT_NODES_DEFAULT)) {
+                Some(nodes) => Response::RecentNodes { nodes },
+                None => Response::failure(REASON_NO_INFORMATION),
+            }
+        }
+
+        Request::GetNearestNodes {
+            k,
+            node_addr,
+            coordinates,
+            max_pos_err,
+            max_age,
+        } => {
+            let (reference, exclude) = match reference_point(store, node_addr, coordinates) {
+                Ok(reference) => reference,
+                Err(failure) => return Response::Failure(failure),
+            };
+
+            let updated_since = max_age.map(|age| now_sec().saturating_sub(age));
+            let filter = |n: &Node| {
+                Some(n.addr()) != exclude
+                    && max_pos_err.is_none_or(|e| n.info.location.pos_err <= e)
+                    && updated_since.is_none_or(|t| n.last_updated_sec >= t)
+            };
+
+            let nodes = store
+                .snapshot()
+                .nearest(&reference, k, filter)
+                .into_iter()
+                .map(|(rtt, n)| NodeEstimate {
+                    info: NodeInfoFull::from(n.clone()),
+                    rtt,
+                })
+                .collect();
+
+            Response::NearestNodes { nodes }
+        }
+
+        Request::EstimateRtt { node_a, node_b } => {
+            let location_a = match find_location(store, &node_a) {
+                Ok(location) => location,
+                Err(failure) => return Response::Failure(failure),
+            };
+
+            // local node by default
+            let location_b = match node_b {
+                Some(node_b) => match find_location(store, &node_b) {
+                    Ok(location) => location,
+                    Err(failure) => return Response::Failure(failure),
                 },
-                None => Response::Failure { reason: REASON_NO_INFORMATION },
+                None => store.get_location(),
+            };
+
+            Response::RttEstimate {
+                rtt: vivaldi::node_distance(&location_a, &location_b),
+                pos_err_a: location_a.pos_err,
+                pos_err_b: location_b.pos_err,
+            }
+        }
+
+        Request::DistanceMatrix { nodes } => {
+            if nodes.len() > MAX_DISTANCE_MATRIX_NODES {
+                return Response::failure(REASON_TOO_MANY_NODES);
+            }
+
+            let mut locations: Vec<Option<NodeCoordinates>> = Vec::with_capacity(nodes.len());
+            for node_addr in &nodes {
+                match find_location(store, node_addr) {
+                    Ok(location) => locations.push(Some(location)),
+                    Err(ref failure) if failure.reason == REASON_NODE_NOT_FOUND => {
+                        locations.push(None)
+                    }
+                    Err(failure) => return Response::Failure(failure),
+                }
+            }
+
+            let rtt = locations
+                .iter()
+                .map(|a| {
+                    locations
+                        .iter()
+                        .map(|b| match (a, b) {
+                            (Some(a), Some(b)) => Some(vivaldi::node_distance(a, b)),
+                            _ => None,
+                        })
+                        .collect()
+                })
+                .collect();
+
+            Response::DistanceMatrix {
+                pos_err: locations.iter().map(|l| l.as_ref().map(|l| l.pos_err)).collect(),
+                rtt,
+            }
+        }
+
+        Request::RankCandidates {
+            candidates,
+            unknown,
+        } => {
+            let local = store.get_location();
+            let now = now_sec();
+
+            let mut known = Vec::with_capacity(candidates.len());
+            let mut not_found = Vec::new();
+
+            for candidate in candidates {
+                let addr = match parse_addr(&candidate) {
+                    Ok(addr) => addr,
+                    Err(failure) => return Response::Failure(failure),
+                };
+
+                match store.find_node(addr) {
+                    Some(node) => known.push(RankedCandidate {
+                        addr: candidate,
+                        known: true,
+                        rtt: Some(vivaldi::node_distance(&local, &node.info.location)),
+                        pos_err: Some(node.info.location.pos_err),
+                        age: Some(now.saturating_sub(node.last_updated_sec)),
+                    }),
+                    None => not_found.push(RankedCandidate {
+                        addr: candidate,
+                        known: false,
+                        rtt: None,
+                        pos_err: None,
+                        age: None,
+                    }),
+                }
+            }
+
+            // stable sort keeps order of candidates with equal estimates
+            known.sort_by(|a, b| {
+                a.rtt
+                    .partial_cmp(&b.rtt)
+                    .unwrap_or(::std::cmp::Ordering::Equal)
+            });
+
+            let candidates = match unknown {
+                UnknownPlacement::First => not_found.into_iter().chain(known).collect(),
+                UnknownPlacement::Last => known.into_iter().chain(not_found).collect(),
+            };
+
+            Response::RankedCandidates { candidates }
+        }
+
+        Request::GetStatus => Response::Status {
+            status: status::report(store),
+        },
+
+        // handled by the connection itself
+        Request::Subscribe(_) | Request::Auth { .. } | Request::Batch { .. } => {
+            Response::failure(REASON_NOT_SUPPORTED)
+        }
+
+        // allowed only for admin connections
+        Request::ProbeNow { .. }
+        | Request::AddPeer { .. }
+        | Request::RemoveNode { .. }
+        | Request::ResetLocation
+        | Request::SetProbePeriod { .. }
+        | Request::ReloadConfig => Response::failure(REASON_ADMIN_DISABLED),
+    }
+}
+
+/// Process request of admin connection, passing commands to transmitter.
+/// Landmark has no transmitter and stays at the origin, so actions
+/// involving probes or location are rejected before changing anything.
+pub fn process_admin_request(
+    request: Request,
+    store: &SharedStorage,
+    cursors: &mut Cursors,
+    control: Option<&UnboundedSender<Control>>,
+    reload: &SharedReloader,
+) -> Response {
+    if !request.is_admin() {
+        return process_request(request, store.as_ref(), cursors);
+    }
+
+    info!("admin request: {:?}", request);
+    store.metrics().interface_request(request.action());
+
+    let needs_transmitter = matches!(
+        request,
+        Request::ProbeNow { .. } | Request::AddPeer { .. } | Request::SetProbePeriod { .. } | Request::ResetLocation
+    );
+    if needs_transmitter && control.is_none() {
+        return Response::failure(REASON_NOT_SUPPORTED);
+    }
+
+    let result = match request {
+        Request::ProbeNow { node_addr } => match node_addr {
+            Some(addr) => parse_addr(&addr).map(|a| Control::ProbeNow(Some(a))),
+            None => Ok(Control::ProbeNow(None)),
+        }.and_then(|c| send_control(control, c)),
+
+        Request::AddPeer { node_addr } => parse_addr(&node_addr).and_then(|addr| {
+            store.add_node(NodeInfo::new(addr.ip(), addr.port(), String::new()));
+            send_control(control, Control::ProbeNow(Some(addr)))
+        }),
+
+        Request::RemoveNode { node_addr } => parse_addr(&node_addr).and_then(|addr| {
+            if store.remove_node(addr) {
+                Ok(())
+            } else {
+                Err(Failure::new(REASON_NODE_NOT_FOUND))
+            }
+        }),
+
+        Request::ResetLocation => {
+            store.set_location(NodeCoordinates::empty());
+            Ok(())
+        }
+
+        Request::SetProbePeriod { period_ms } => {
+            if !(MIN_PROBE_PERIOD_MS..=MAX_PROBE_PERIOD_MS).contains(&period_ms) {
+                Err(Failure::new(REASON_BAD_PERIOD))
+            } else {
+                send_control(control, Control::SetProbePeriod(Duration::from_millis(period_ms)))
+            }
+        }
+
+        Request::ReloadConfig => {
+            let result = reload.lock().unwrap_or_else(PoisonError::into_inner).reload();
+            return match result {
+                Ok(report) => Response::Reloaded { report },
+                Err(e) => {
+                    error!("cannot reload configuration: {}", e);
+                    Response::Failure(Failure::new(REASON_RELOAD_FAILED).with_details(ErrorDetails {
+                        cause: Some(e.to_string()),
+                        ..ErrorDetails::default()
+                    }))
+                }
+            };
+        }
+
+        _ => unreachable!("not an admin request"),
+    };
+
+    match result {
+        Ok(()) => Response::Done,
+        Err(failure) => Response::Failure(failure),
+    }
+}
+
+fn parse_addr(node_addr: &str) -> Result<SocketAddr, Failure> {
+    node_addr.parse().map_err(|_| Failure::bad_node_addr(node_addr))
+}
+
+fn send_control(control: Option<&UnboundedSender<Control>>, command: Control) -> Result<(), Failure> {
+    control
+        .ok_or(REASON_NOT_SUPPORTED)?
+        .unbounded_send(command)
+        .map_err(|_| Failure::new(REASON_NOT_SUPPORTED))
+}
+
+/// Find location of the node by its network address
+fn find_location(store: &dyn StorageView, node_addr: &str) -> Result<NodeCoordinates, Failure> {
+    let addr = parse_addr(node_addr)?;
+    let node = store.find_node(addr).ok_or(REASON_NODE_NOT_FOUND)?;
+    Ok(node.info.location)
+}
+
+/// Resolve point of the request, local location by default.
+/// Return coordinates and address of the reference node, if any.
+fn reference_point(
+    store: &dyn StorageView,
+    node_addr: Option<String>,
+    coordinates: Option<Point>,
+) -> Result<(NodeCoordinates, Option<SocketAddr>), Failure> {
+    match (node_addr, coordinates) {
+        (Some(_), Some(_)) => Err(Failure::new(REASON_AMBIGUOUS_REFERENCE)),
+        (Some(node_addr), None) => {
+            let location = find_location(store, &node_addr)?;
+            Ok((location, node_addr.parse().ok()))
+        }
+        (None, Some(point)) => Ok((NodeCoordinates::from(point), None)),
+        (None, None) => Ok((store.get_location(), None)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agent::{NodeConfig, NodeInfo, Reloader};
+    use futures::Stream;
+    use interface::SharedToken;
+    use storage::Storage;
+    use std::sync::Arc;
+
+    fn store_with_line() -> SharedStorage {
+        let store = Storage::new();
+        for i in 1..6 {
+            let mut node = NodeInfo::new("10.0.0.1".parse().unwrap(), i, format!("{}", i));
+            node.location.x1 = i as f32;
+            node.location.pos_err = 0.1 * i as f32;
+            node.location.iteration = 1;
+            store.add_node(node);
+        }
+        Arc::new(store)
+    }
+
+    fn process(request: Request, store: &SharedStorage) -> Response {
+        process_request(request, store.as_ref(), &mut Cursors::default())
+    }
+
+    fn nearest_ports(response: Response) -> Vec<u16> {
+        match response {
+            Response::NearestNodes { nodes } => nodes.iter().map(|n| n.info.port).collect(),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    fn nearest_request(
+        k: usize,
+        node_addr: Option<&str>,
+        coordinates: Option<Point>,
+        max_pos_err: Option<f32>,
+    ) -> Request {
+        Request::GetNearestNodes {
+            k,
+            node_addr: node_addr.map(|a| a.to_string()),
+            coordinates,
+            max_pos_err,
+            max_age: None,
+        }
+    }
+
+    #[test]
+    fn nearest_to_local_node() {
+        let store = store_with_line();
+        let response = process(nearest_request(2, None, None, None), &store);
+        assert_eq!(nearest_ports(response), vec![1, 2]);
+    }
+
+    #[test]
+    fn nearest_to_other_node() {
+        let store = store_with_line();
+        let response =
+            process(nearest_request(2, Some("10.0.0.1:3"), None, None), &store);
+        assert_eq!(nearest_ports(response), vec![2, 4]);
+    }
+
+    #[test]
+    fn nearest_to_point_filtered() {
+        let store = store_with_line();
+        let point = Point {
+            x1: 5.0,
+            x2: 0.0,
+            height: 0.0,
+        };
+        let response = process(nearest_request(2, None, Some(point), Some(0.35)), &store);
+        assert_eq!(nearest_ports(response), vec![3, 2]);
+    }
+
+    #[test]
+    fn nearest_bad_reference() {
+        let store = store_with_line();
+        let point = Point {
+            x1: 0.0,
+            x2: 0.0,
+            height: 0.0,
+        };
+
+        match process(nearest_request(2, Some("10.0.0.1:3"), Some(point), None), &store) {
+            Response::Failure(failure) => assert_eq!(failure.reason, REASON_AMBIGUOUS_REFERENCE),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        match process(nearest_request(2, Some("10.0.0.2:3"), None, None), &store) {
+            Response::Failure(failure) => assert_eq!(failure.reason, REASON_NODE_NOT_FOUND),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn estimate_between_nodes() {
+        let store = store_with_line();
+        let request = Request::EstimateRtt {
+            node_a: "10.0.0.1:1".to_string(),
+            node_b: Some("10.0.0.1:4".to_string()),
+        };
+
+        match process(request, &store) {
+            Response::RttEstimate {
+                rtt,
+                pos_err_a,
+                pos_err_b,
+            } => {
+                assert_eq!(rtt, 3.0);
+                assert_eq!(pos_err_a, 0.1);
+                assert_eq!(pos_err_b, 0.4);
             }
+            other => panic!("unexpected response: {:?}", other),
         }
     }
+
+    #[test]
+    fn distance_matrix_with_unknown_node() {
+        let store = store_with_line();
+        let request = Request::DistanceMatrix {
+            nodes: vec![
+                "10.0.0.1:1".to_string(),
+                "10.0.0.2:1".to_string(),
+                "10.0.0.1:3".to_string(),
+            ],
+        };
+
+        match process(request, &store) {
+            Response::DistanceMatrix { pos_err, rtt } => {
+                assert_eq!(pos_err, vec![Some(0.1), None, Some(0.3)]);
+                assert_eq!(
+                    rtt,
+                    vec![
+                        vec![Some(0.0), None, Some(2.0)],
+                        vec![None, None, None],
+                        vec![Some(2.0), None, Some(0.0)],
+                    ]
+                );
+            }
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    fn ranked(store: &SharedStorage, unknown: UnknownPlacement) -> Vec<(String, bool)> {
+        let request = Request::RankCandidates {
+            candidates: vec![
+                "10.0.0.1:4".to_string(),
+                "10.0.0.9:1".to_string(),
+                "10.0.0.1:2".to_string(),
+                "10.0.0.1:3".to_string(),
+            ],
+            unknown,
+        };
+
+        match process(request, store) {
+            Response::RankedCandidates { candidates } => candidates
+                .into_iter()
+                .map(|c| (c.addr, c.known))
+                .collect(),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rank_candidates_by_rtt() {
+        let store = store_with_line();
+        let expected = |a: &str, known: bool| (a.to_string(), known);
+
+        assert_eq!(
+            ranked(&store, UnknownPlacement::Last),
+            vec![
+                expected("10.0.0.1:2", true),
+                expected("10.0.0.1:3", true),
+                expected("10.0.0.1:4", true),
+                expected("10.0.0.9:1", false),
+            ]
+        );
+        assert_eq!(
+            ranked(&store, UnknownPlacement::First)[0],
+            expected("10.0.0.9:1", false)
+        );
+    }
+
+    #[test]
+    fn admin_disabled_by_default() {
+        let store = store_with_line();
+
+        match process(Request::ResetLocation, &store) {
+            Response::Failure(failure) => assert_eq!(failure.reason, REASON_ADMIN_DISABLED),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn admin_commands() {
+        let store = store_with_line();
+        let (control, commands) = ::futures::sync::mpsc::unbounded();
+        let reload = Reloader::new(NodeConfig::default(), None, store.clone(), None, SharedToken::default()).shared();
+        let mut cursors = Cursors::default();
+
+        let add = Request::AddPeer { node_addr: "10.0.0.2:7".to_string() };
+        match process_admin_request(add, &store, &mut cursors, Some(&control), &reload) {
+            Response::Done => {}
+            other => panic!("unexpected response: {:?}", other),
+        }
+        let addr: SocketAddr = "10.0.0.2:7".parse().unwrap();
+        assert!(store.find_node(addr).is_some());
+        // not positioned until probed
+        assert!(store.nearest(&NodeCoordinates::empty(), 10).iter().all(|(_, n)| n.addr() != addr));
+
+        let remove = Request::RemoveNode { node_addr: "10.0.0.1:1".to_string() };
+        match process_admin_request(remove, &store, &mut cursors, Some(&control), &reload) {
+            Response::Done => {}
+            other => panic!("unexpected response: {:?}", other),
+        }
+        assert_eq!(store.len(), 5);
+
+        for &period_ms in &[10, u64::MAX] {
+            let period = Request::SetProbePeriod { period_ms };
+            match process_admin_request(period, &store, &mut cursors, Some(&control), &reload) {
+                Response::Failure(failure) => assert_eq!(failure.reason, REASON_BAD_PERIOD),
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        // configuration source is not set
+        match process_admin_request(Request::ReloadConfig, &store, &mut cursors, Some(&control), &reload) {
+            Response::Failure(failure) => assert_eq!(failure.reason, REASON_RELOAD_FAILED),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        // only the new peer is probed
+        drop(control);
+        let commands: Vec<Control> = commands.wait().map(Result::unwrap).collect();
+        assert_eq!(commands, vec![Control::ProbeNow(Some(addr))]);
+    }
+
+    #[test]
+    fn landmark_admin_commands() {
+        let store = Arc::new(Storage::new());
+        let origin = NodeCoordinates {
+            pos_err: 0.0,
+            ..NodeCoordinates::empty()
+        };
+        store.set_location(origin.clone());
+        let reload = Reloader::new(NodeConfig::default(), None, store.clone(), None, SharedToken::default()).shared();
+        let mut cursors = Cursors::default();
+
+        let requests = vec![
+            Request::ProbeNow { node_addr: None },
+            Request::AddPeer { node_addr: "10.0.0.2:7".to_string() },
+            Request::SetProbePeriod { period_ms: 1000 },
+            Request::ResetLocation,
+        ];
+        for request in requests {
+            match process_admin_request(request, &store, &mut cursors, None, &reload) {
+                Response::Failure(failure) => assert_eq!(failure.reason, REASON_NOT_SUPPORTED),
+                other => panic!("unexpected response: {:?}", other),
+            }
+        }
+
+        // rejected requests change nothing
+        assert_eq!(store.len(), 0);
+        assert_eq!(store.get_location(), origin);
+    }
 }
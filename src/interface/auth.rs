@@ -0,0 +1,100 @@
+/// Bearer token authentication of interface clients
+///
+/// Token is shared by all listeners and may be replaced
+/// on configuration reload, affecting new authentications only.
+///
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, PoisonError, RwLock};
+
+use error::{Error, Result};
+
+/// Secret shared with interface clients
+#[derive(Clone)]
+pub struct Token(String);
+
+impl Token {
+    pub fn new(token: String) -> Self {
+        Token(token)
+    }
+
+    /// Read token from the first line of file
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let interface_error = |source| Error::Interface {
+            what: "auth token file",
+            path: path.to_path_buf(),
+            source,
+        };
+        let content = fs::read_to_string(path).map_err(interface_error)?;
+        let token = content.lines().next().unwrap_or("").trim();
+
+        if token.is_empty() {
+            return Err(interface_error(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "empty auth token",
+            )));
+        }
+
+        Ok(Token(token.to_string()))
+    }
+
+    /// Token to present to the interface
+    pub fn secret(&self) -> &str {
+        &self.0
+    }
+
+    /// Compare in constant time, not revealing matched prefix length
+    pub fn verify(&self, provided: &str) -> bool {
+        let expected = self.0.as_bytes();
+        let provided = provided.as_bytes();
+
+        let diff = expected
+            .iter()
+            .zip(provided.iter())
+            .fold(expected.len() ^ provided.len(), |acc, (a, b)| acc | (a ^ b) as usize);
+
+        diff == 0
+    }
+}
+
+// never print the secret itself
+impl ::std::fmt::Debug for Token {
+    fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+        write!(f, "Token(..)")
+    }
+}
+
+/// Token required by listeners, absent if authentication is disabled
+#[derive(Debug, Clone, Default)]
+pub struct SharedToken(Arc<RwLock<Option<Token>>>);
+
+impl SharedToken {
+    pub fn new(token: Option<Token>) -> Self {
+        SharedToken(Arc::new(RwLock::new(token)))
+    }
+
+    pub fn get(&self) -> Option<Token> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+
+    pub fn set(&self, token: Option<Token>) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = token;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_verification() {
+        let token = Token::new("secret".to_string());
+
+        assert!(token.verify("secret"));
+        assert!(!token.verify("secreT"));
+        assert!(!token.verify("secret1"));
+        assert!(!token.verify("secre"));
+        assert!(!token.verify(""));
+    }
+}
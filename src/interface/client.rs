@@ -1,73 +1,333 @@
 
 use tokio;
 use tokio::io;
-use tokio::net::{TcpListener, TcpStream};
-use tokio_io::codec::{Framed, LinesCodec};
+use tokio::codec::{Decoder, Framed, LinesCodec};
 use tokio::prelude::*;
 
 use futures::future;
 
-use std::net::SocketAddr;
 
-use storage::SharedStorage;
-use super::proto::{Request, Response, REASON_BAD_REQUEST};
-use super::actions::process_request;
+use storage::{FrozenStorage, SharedStorage};
+use super::{Access, Lifetime};
+use super::jsonrpc::{self, RpcError, INVALID_REQUEST, PARSE_ERROR};
+use super::proto::{ErrorDetails, Failure, Reply, Request, Response, SubscribeParams};
+use super::proto::REASON_BAD_REQUEST;
+use super::proto::{REASON_AUTH_FAILED, REASON_AUTH_REQUIRED};
+use super::proto::{REASON_BATCH_TOO_LARGE, REASON_NOT_IN_BATCH};
+use super::actions::{process_admin_request, process_request};
+use super::query::Cursors;
+use super::subscription::Subscription;
 
-use serde_json;
+use serde_json::{self, Value};
+
+const MAX_BATCH_SIZE: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Framing {
+    Native,
+    JsonRpc,
+}
 
 /// Client processes stream of newline-delimited JSON-messages
-/// responding in the client-server manner
+/// responding in the client-server manner.
+///
+/// After successful subscription connection turns into the stream
+/// of events, all further incoming messages are ignored.
+///
+/// If token is configured, the first message must be `auth` one,
+/// connection is closed after failed authentication.
+///
+/// Messages are either native ones with optional `id` echoed in response,
+/// or JSON-RPC 2.0 calls, depending on the first message of connection.
+///
+/// On server shutdown connection is closed after sending pending replies.
 pub struct Client<T, U> {
     stream: Framed<T, U>,
-    peer_addr: SocketAddr,
+    // peer description for logging
+    peer: String,
     store: SharedStorage,
+    access: Access,
+    lifetime: Lifetime,
+    authenticated: bool,
+    closing: bool,
+    // chosen by the first message
+    framing: Option<Framing>,
+    subscription: Option<Subscription>,
+    // selections of the paginated full map requests
+    cursors: Cursors,
+    // encoded reply or event not yet accepted by socket
+    pending: Option<String>,
 }
 
 
-impl Client<TcpStream, LinesCodec> {
-    pub fn new(s: TcpStream, store: SharedStorage) -> Self {
-        let peer_addr = s.peer_addr().unwrap();
+impl<T: AsyncRead + AsyncWrite> Client<T, LinesCodec> {
+    pub fn new(s: T, peer: String, store: SharedStorage, access: Access, lifetime: Lifetime) -> Self {
         Client {
-            stream: s.framed(LinesCodec::new()),
-            peer_addr,
+            stream: LinesCodec::new().framed(s),
+            peer,
             store,
+            authenticated: access.token.get().is_none(),
+            access,
+            lifetime,
+            closing: false,
+            framing: None,
+            subscription: None,
+            cursors: Cursors::default(),
+            pending: None,
+        }
+    }
+
+    /// Process incoming message, return encoded reply if any
+    fn process_message(&mut self, msg: &str) -> Result<Option<String>, io::Error> {
+        let message: Value = match serde_json::from_str(msg) {
+            Ok(message) => message,
+            Err(e) => {
+                debug!("bad request from {}, error: {}, message: {}", self.peer, e, msg);
+                let encoded = match self.framing {
+                    Some(Framing::JsonRpc) => {
+                        let error = RpcError::new(PARSE_ERROR).with_details(ErrorDetails::from(&e));
+                        serde_json::to_string(&jsonrpc::error(Value::Null, error))?
+                    }
+                    _ => {
+                        let failure = Failure::new(REASON_BAD_REQUEST).with_details(ErrorDetails::from(&e));
+                        serde_json::to_string(&Response::Failure(failure))?
+                    }
+                };
+                return Ok(Some(encoded));
+            }
+        };
+
+        // framing is chosen by the first message of connection
+        let framing = *self.framing.get_or_insert(if jsonrpc::is_rpc(&message) {
+            Framing::JsonRpc
+        } else {
+            Framing::Native
+        });
+
+        match framing {
+            Framing::Native => {
+                let reply = self.process_native(message, None);
+                Ok(Some(serde_json::to_string(&reply)?))
+            }
+            Framing::JsonRpc => match self.process_rpc(message) {
+                Some(reply) => Ok(Some(serde_json::to_string(&reply)?)),
+                None => Ok(None),
+            },
+        }
+    }
+
+    fn process_native(&mut self, message: Value, view: Option<&FrozenStorage>) -> Reply {
+        let id = message.get("id").cloned();
+        let response = match Request::decode(message) {
+            Ok(request) => self.dispatch(request, view),
+            Err(failure) => {
+                debug!("bad request from {}: {:?}", self.peer, failure);
+                Response::Failure(failure)
+            }
+        };
+
+        Reply { response, id }
+    }
+
+    fn process_rpc(&mut self, message: Value) -> Option<Value> {
+        match message {
+            Value::Array(calls) => {
+                if calls.is_empty() {
+                    return Some(jsonrpc::error(Value::Null, RpcError::new(INVALID_REQUEST)));
+                }
+                if calls.len() > MAX_BATCH_SIZE {
+                    let error = RpcError::from(Failure::new(REASON_BATCH_TOO_LARGE));
+                    return Some(jsonrpc::error(Value::Null, error));
+                }
+
+                let view = self.store.freeze();
+                let replies: Vec<Value> = calls
+                    .into_iter()
+                    .filter_map(|call| self.process_call(call, Some(&view)))
+                    .collect();
+
+                // batch of notifications is not answered
+                if replies.is_empty() {
+                    None
+                } else {
+                    Some(Value::Array(replies))
+                }
+            }
+            call => self.process_call(call, None),
+        }
+    }
+
+    fn process_call(&mut self, call: Value, view: Option<&FrozenStorage>) -> Option<Value> {
+        let call = jsonrpc::decode(call);
+        match call.request {
+            Ok(request) => {
+                let response = self.dispatch(request, view);
+                call.id.map(|id| jsonrpc::reply(id, response))
+            }
+            Err(error) => call.id.map(|id| jsonrpc::error(id, error)),
         }
     }
+
+    /// Process request, using frozen storage view for requests of the batch
+    fn dispatch(&mut self, request: Request, view: Option<&FrozenStorage>) -> Response {
+        let in_batch = view.is_some();
+
+        match request {
+            Request::Auth { token } if !in_batch => self.authenticate(&token),
+            _ if !self.authenticated => Response::failure(REASON_AUTH_REQUIRED),
+            Request::Subscribe(params) if !in_batch => self.subscribe(params),
+            Request::Batch { requests } if !in_batch => self.batch(requests),
+            Request::Auth { .. } | Request::Subscribe(_) | Request::Batch { .. } => {
+                Response::failure(REASON_NOT_IN_BATCH)
+            }
+            ref request if request.is_admin() && in_batch => Response::failure(REASON_NOT_IN_BATCH),
+            request => match (view, &self.access.reload) {
+                (Some(view), _) => process_request(request, view, &mut self.cursors),
+                (None, Some(reload)) => {
+                    let control = self.access.control.as_ref();
+                    process_admin_request(request, &self.store, &mut self.cursors, control, reload)
+                }
+                _ => process_request(request, self.store.as_ref(), &mut self.cursors),
+            },
+        }
+    }
+
+    /// Answer all requests from the same state of storage
+    fn batch(&mut self, requests: Vec<Value>) -> Response {
+        self.store.metrics().interface_request("batch");
+
+        if requests.len() > MAX_BATCH_SIZE {
+            return Response::failure(REASON_BATCH_TOO_LARGE);
+        }
+
+        let view = self.store.freeze();
+        let responses = requests
+            .into_iter()
+            .map(|request| self.process_native(request, Some(&view)))
+            .collect();
+
+        Response::Batch { responses }
+    }
+
+    fn authenticate(&mut self, token: &str) -> Response {
+        self.store.metrics().interface_request("auth");
+
+        self.authenticated = match self.access.token.get() {
+            Some(expected) => expected.verify(token),
+            None => true,
+        };
+
+        if self.authenticated {
+            Response::Authenticated
+        } else {
+            warn!("authentication failed: {}", self.peer);
+            self.closing = true;
+            Response::failure(REASON_AUTH_FAILED)
+        }
+    }
+
+    fn subscribe(&mut self, params: SubscribeParams) -> Response {
+        self.store.metrics().interface_request("subscribe");
+
+        match Subscription::new(params) {
+            Ok(subscription) => {
+                info!("client subscribed: {}", self.peer);
+                let interval_ms = subscription.interval_ms();
+                self.subscription = Some(subscription);
+                Response::Subscribed { interval_ms }
+            }
+            Err(failure) => Response::Failure(failure),
+        }
+    }
+
+    /// Send queued events while socket accepts them
+    fn send_events(&mut self) -> Result<(), io::Error> {
+        if let Some(ref mut subscription) = self.subscription {
+            subscription.poll_changes(&self.store)?;
+
+            loop {
+                let encoded = match self.pending.take() {
+                    Some(encoded) => encoded,
+                    None => match subscription.pop_front() {
+                        Some(event) => {
+                            let event = Response::Event { event };
+                            match self.framing {
+                                Some(Framing::JsonRpc) => {
+                                    serde_json::to_string(&jsonrpc::notification("event", event))?
+                                }
+                                _ => serde_json::to_string(&event)?,
+                            }
+                        }
+                        None => break,
+                    },
+                };
+
+                if let AsyncSink::NotReady(encoded) = self.stream.start_send(encoded)? {
+                    self.pending = Some(encoded);
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
 }
 
-impl Future for Client<TcpStream, LinesCodec> {
+impl<T: AsyncRead + AsyncWrite> Future for Client<T, LinesCodec> {
     type Item = ();
     type Error = io::Error;
 
     fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if !self.closing && self.lifetime.is_ending() {
+            debug!("closing connection of {} on shutdown", self.peer);
+            self.closing = true;
+        }
+
         // process until closed
         loop {
-            match try_ready!(self.stream.poll()) {
-                Some(msg) => {
-                    let response = match serde_json::from_str::<Request>(&msg) {
-                        Ok(request) => process_request(request, &mut self.store),
-                        Err(e) => {
-                            debug!(
-                                "bad request from {}, error: {}, message: {}",
-                                self.peer_addr,
-                                e,
-                                msg
-                            );
-                            Response::Failure { reason: REASON_BAD_REQUEST }
-                        }
-                    };
-
-                    // encode and send back
-                    let encoded = serde_json::to_string(&response)?;
-                    self.stream.start_send(encoded)?;
+            // reply or event not accepted by socket goes first
+            if let Some(encoded) = self.pending.take() {
+                if let AsyncSink::NotReady(encoded) = self.stream.start_send(encoded)? {
+                    self.pending = Some(encoded);
+                    // flushing makes room for it
                     try_ready!(self.stream.poll_complete());
+                    continue;
                 }
+            }
 
-                None => {
-                    info!("client disconnected: {}", self.peer_addr);
+            // deliver last response before closing
+            if self.closing {
+                try_ready!(self.stream.poll_complete());
+                info!("client disconnected: {}", self.peer);
+                return Ok(Async::Ready(()));
+            }
+
+            match self.stream.poll()? {
+                Async::Ready(Some(msg)) => {
+                    if self.subscription.is_some() {
+                        debug!("message from subscribed client {} ignored", self.peer);
+                        continue;
+                    }
+
+                    // encode and send back, notifications are not answered
+                    if let Some(encoded) = self.process_message(&msg)? {
+                        self.pending = Some(encoded);
+                        continue;
+                    }
+                }
+
+                Async::Ready(None) => {
+                    info!("client disconnected: {}", self.peer);
                     return Ok(Async::Ready(()));
                 }
+
+                Async::NotReady => break,
             }
         }
+
+        self.send_events()?;
+        try_ready!(self.stream.poll_complete());
+
+        Ok(Async::NotReady)
     }
 }
@@ -0,0 +1,413 @@
+/// Blocking interface client
+///
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+
+use agent::{NodeCoordinates, NodeList, ReloadReport};
+use status::StatusReport;
+use super::super::proto::{Event, FullMapParams, NodeEstimate, NodeInfoFull, Point};
+use super::super::proto::{RankedCandidate, Request, Response, SubscribeParams, UnknownPlacement};
+use super::{auth_request, check, decode, encode, batch_request};
+use super::{ClientConfig, DistanceMatrix, Endpoint, Error, FullMap, Result, RttEstimate};
+
+enum Stream {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+}
+
+/// Stream with deadline of the current request
+struct Connection {
+    stream: Stream,
+    // every read gets the time left, so slow response can't exceed it
+    deadline: Option<Instant>,
+}
+
+impl Connection {
+    fn open(config: &ClientConfig) -> io::Result<Self> {
+        let stream = match config.endpoint {
+            Endpoint::Tcp(ref addr) => {
+                let stream = TcpStream::connect_timeout(addr, config.connect_timeout)?;
+                stream.set_nodelay(true)?;
+                Stream::Tcp(stream)
+            }
+            Endpoint::Unix(ref path) => Stream::Unix(UnixStream::connect(path)?),
+        };
+
+        let connection = Connection {
+            stream,
+            deadline: None,
+        };
+        connection.set_timeout(Some(config.request_timeout))?;
+        Ok(connection)
+    }
+
+    fn set_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.stream {
+            Stream::Tcp(ref s) => s.set_read_timeout(timeout).and(s.set_write_timeout(timeout)),
+            Stream::Unix(ref s) => s.set_read_timeout(timeout).and(s.set_write_timeout(timeout)),
+        }
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self.stream {
+            Stream::Tcp(ref s) => s.set_read_timeout(timeout),
+            Stream::Unix(ref s) => s.set_read_timeout(timeout),
+        }
+    }
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if let Some(deadline) = self.deadline {
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "request deadline exceeded"));
+            }
+            self.set_read_timeout(Some(deadline - now))?;
+        }
+
+        match self.stream {
+            Stream::Tcp(ref mut s) => s.read(buf),
+            Stream::Unix(ref mut s) => s.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.stream {
+            Stream::Tcp(ref mut s) => s.write(buf),
+            Stream::Unix(ref mut s) => s.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.stream {
+            Stream::Tcp(ref mut s) => s.flush(),
+            Stream::Unix(ref mut s) => s.flush(),
+        }
+    }
+}
+
+/// Send encoded request and wait for the response with the same id,
+/// no longer than `timeout` in total
+fn exchange(conn: &mut BufReader<Connection>, encoded: &str, id: u64, timeout: Duration) -> Result<Response> {
+    let stream = conn.get_mut();
+    stream.deadline = Instant::now().checked_add(timeout);
+    stream.write_all(encoded.as_bytes())?;
+    stream.write_all(b"\n")?;
+
+    loop {
+        let mut line = String::new();
+        if conn.read_line(&mut line)? == 0 {
+            return Err(Error::Closed);
+        }
+
+        // responses of abandoned requests are skipped,
+        // messages which couldn't be parsed by agent have no id
+        match decode(line.trim_end())? {
+            (Some(reply_id), _) if reply_id != id => continue,
+            (_, response) => return Ok(response),
+        }
+    }
+}
+
+/// Client waiting for response of each request
+pub struct Client {
+    config: ClientConfig,
+    conn: Option<BufReader<Connection>>,
+    next_id: u64,
+}
+
+impl Client {
+    /// Create client, connection is established with the first request
+    pub fn new(config: ClientConfig) -> Self {
+        Client {
+            config,
+            conn: None,
+            next_id: 1,
+        }
+    }
+
+    /// Create client, connecting and authenticating immediately
+    pub fn connect(config: ClientConfig) -> Result<Self> {
+        let mut client = Client::new(config);
+        client.conn = Some(client.open()?);
+        Ok(client)
+    }
+
+    fn id(&mut self) -> u64 {
+        let id = self.next_id;
+        self.next_id += 1;
+        id
+    }
+
+    fn open(&mut self) -> Result<BufReader<Connection>> {
+        let mut conn = BufReader::new(Connection::open(&self.config)?);
+
+        if let Some(token) = self.config.auth_token.clone() {
+            let id = self.id();
+            let timeout = self.config.request_timeout;
+            match check(exchange(&mut conn, &encode(&auth_request(&token), id)?, id, timeout)?)? {
+                Response::Authenticated => {}
+                _ => return Err(Error::UnexpectedResponse),
+            }
+        }
+
+        Ok(conn)
+    }
+
+    /// Send request and wait for its response, failure is returned as error.
+    /// Informational requests are repeated on a new connection if it's broken.
+    pub fn request(&mut self, request: Request) -> Result<Response> {
+        let retries = if request.is_admin() { 0 } else { self.config.retries };
+        let mut attempt = 0;
+
+        loop {
+            let id = self.id();
+            let encoded = encode(&request, id)?;
+
+            if self.conn.is_none() {
+                self.conn = Some(self.open()?);
+            }
+            let result = match self.conn {
+                Some(ref mut conn) => exchange(conn, &encoded, id, self.config.request_timeout),
+                None => Err(Error::Closed),
+            };
+
+            match result {
+                Ok(response) => return check(response),
+                Err(e) => {
+                    // state of connection is unknown after failure
+                    self.conn = None;
+                    if !e.is_connection_error() || attempt >= retries {
+                        return Err(e);
+                    }
+                    attempt += 1;
+                    debug!("reconnecting to {}: {}", self.config.endpoint, e);
+                }
+            }
+        }
+    }
+
+    pub fn get_location(&mut self) -> Result<NodeCoordinates> {
+        self.request(Request::GetLocation).and_then(super::location)
+    }
+
+    pub fn get_full_map(&mut self, params: FullMapParams) -> Result<FullMap> {
+        self.request(Request::GetFullMap(params)).and_then(super::full_map)
+    }
+
+    pub fn get_node_info(&mut self, node_addr: &str) -> Result<NodeInfoFull> {
+        let request = Request::GetNodeInfo {
+            node_addr: node_addr.to_string(),
+        };
+        self.request(request).and_then(super::node_info)
+    }
+
+    pub fn get_recent_nodes(&mut self, max_nodes: Option<usize>) -> Result<NodeList> {
+        self.request(Request::GetRecentNodes { max_nodes })
+            .and_then(super::recent_nodes)
+    }
+
+    pub fn get_nearest_nodes(
+        &mut self,
+        k: usize,
+        node_addr: Option<&str>,
+        coordinates: Option<Point>,
+        max_pos_err: Option<f32>,
+        max_age: Option<u64>,
+    ) -> Result<Vec<NodeEstimate>> {
+        let request = Request::GetNearestNodes {
+            k,
+            node_addr: node_addr.map(|a| a.to_string()),
+            coordinates,
+            max_pos_err,
+            max_age,
+        };
+        self.request(request).and_then(super::nearest_nodes)
+    }
+
+    /// Estimate RTT between nodes, `node_b` is the local node by default
+    pub fn estimate_rtt(&mut self, node_a: &str, node_b: Option<&str>) -> Result<RttEstimate> {
+        let request = Request::EstimateRtt {
+            node_a: node_a.to_string(),
+            node_b: node_b.map(|b| b.to_string()),
+        };
+        self.request(request).and_then(super::rtt_estimate)
+    }
+
+    pub fn distance_matrix(&mut self, nodes: &[&str]) -> Result<DistanceMatrix> {
+        let request = Request::DistanceMatrix {
+            nodes: nodes.iter().map(|n| n.to_string()).collect(),
+        };
+        self.request(request).and_then(super::distance_matrix)
+    }
+
+    pub fn rank_candidates(
+        &mut self,
+        candidates: &[&str],
+        unknown: UnknownPlacement,
+    ) -> Result<Vec<RankedCandidate>> {
+        let request = Request::RankCandidates {
+            candidates: candidates.iter().map(|c| c.to_string()).collect(),
+            unknown,
+        };
+        self.request(request).and_then(super::ranked_candidates)
+    }
+
+    pub fn get_status(&mut self) -> Result<StatusReport> {
+        self.request(Request::GetStatus).and_then(super::status)
+    }
+
+    /// Answer requests from the same state of agent,
+    /// each of them could fail separately.
+    pub fn batch(&mut self, requests: Vec<Request>) -> Result<Vec<Response>> {
+        let request = batch_request(requests)?;
+        self.request(request).and_then(super::batch)
+    }
+
+    /* Administrative actions */
+
+    pub fn probe_now(&mut self, node_addr: Option<&str>) -> Result<()> {
+        let request = Request::ProbeNow {
+            node_addr: node_addr.map(|a| a.to_string()),
+        };
+        self.request(request).and_then(super::done)
+    }
+
+    pub fn add_peer(&mut self, node_addr: &str) -> Result<()> {
+        let request = Request::AddPeer {
+            node_addr: node_addr.to_string(),
+        };
+        self.request(request).and_then(super::done)
+    }
+
+    pub fn remove_node(&mut self, node_addr: &str) -> Result<()> {
+        let request = Request::RemoveNode {
+            node_addr: node_addr.to_string(),
+        };
+        self.request(request).and_then(super::done)
+    }
+
+    pub fn reset_location(&mut self) -> Result<()> {
+        self.request(Request::ResetLocation).and_then(super::done)
+    }
+
+    pub fn set_probe_period(&mut self, period_ms: u64) -> Result<()> {
+        self.request(Request::SetProbePeriod { period_ms })
+            .and_then(super::done)
+    }
+
+    /// Make agent re-read its configuration
+    pub fn reload_config(&mut self) -> Result<ReloadReport> {
+        self.request(Request::ReloadConfig).and_then(super::reloaded)
+    }
+
+    /// Turn connection into the stream of events
+    pub fn subscribe(mut self, params: SubscribeParams) -> Result<Events> {
+        match self.request(Request::Subscribe(params))? {
+            Response::Subscribed { .. } => {}
+            _ => return Err(Error::UnexpectedResponse),
+        }
+
+        let mut conn = self.conn.take().ok_or(Error::Closed)?;
+        conn.get_mut().deadline = None;
+        conn.get_ref().set_timeout(None)?;
+        Ok(Events { conn })
+    }
+}
+
+/// Events of subscription, iteration ends when connection is closed
+pub struct Events {
+    conn: BufReader<Connection>,
+}
+
+impl Iterator for Events {
+    type Item = Result<Event>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        match self.conn.read_line(&mut line) {
+            Ok(0) => None,
+            Ok(_) => Some(decode(line.trim_end()).and_then(|(_, response)| match response {
+                Response::Event { event } => Ok(event),
+                _ => Err(Error::UnexpectedResponse),
+            })),
+            Err(e) => Some(Err(Error::from(e))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
+    use std::thread;
+
+    /// Answer `get_location` with given iteration, closing connection after each answer
+    fn flaky_agent() -> Endpoint {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            for (iteration, stream) in listener.incoming().take(2).enumerate() {
+                let mut stream = BufReader::new(stream.unwrap());
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+
+                let id = ::serde_json::from_str::<::serde_json::Value>(&line).unwrap()["id"].clone();
+                let response = json!({
+                    "type": "location",
+                    "loc": {"x1": 0.0, "x2": 0.0, "height": 0.0, "pos_err": 1.0, "iteration": iteration},
+                    "id": id,
+                });
+                writeln!(stream.get_mut(), "{}", response).unwrap();
+            }
+        });
+
+        Endpoint::Tcp(addr)
+    }
+
+    /// Keep answering requests of other ids, never the asked one
+    fn chatty_agent() -> Endpoint {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut stream = listener.accept().unwrap().0;
+            let response = json!({"type": "done", "id": 1000});
+            while writeln!(stream, "{}", response).is_ok() {
+                thread::sleep(Duration::from_millis(20));
+            }
+        });
+
+        Endpoint::Tcp(addr)
+    }
+
+    #[test]
+    fn request_deadline() {
+        let mut config = ClientConfig::new(chatty_agent());
+        config.request_timeout = Duration::from_millis(200);
+        config.retries = 0;
+        let mut client = Client::new(config);
+
+        let started = Instant::now();
+        match client.get_location() {
+            Err(Error::Timeout) => {}
+            other => panic!("unexpected result: {:?}", other),
+        }
+        assert!(started.elapsed() < Duration::from_secs(2));
+    }
+
+    #[test]
+    fn reconnect_on_closed_connection() {
+        let mut client = Client::new(ClientConfig::new(flaky_agent()));
+
+        assert_eq!(client.get_location().unwrap().iteration, 0);
+        // previous connection is closed by agent
+        assert_eq!(client.get_location().unwrap().iteration, 1);
+    }
+}
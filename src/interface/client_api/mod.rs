@@ -0,0 +1,339 @@
+/// Typed clients of the agent interface
+///
+/// `Client` is blocking, `AsyncClient` works on top of tokio runtime.
+/// Both speak the native line-based protocol, tagging each request with id.
+/// Connection is reused between requests and re-established after failure,
+/// informational requests are retried on a fresh connection.
+///
+use std::error;
+use std::fmt;
+use std::io;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use serde_json::{self, Value};
+
+use agent::{NodeCoordinates, NodeList, ReloadReport};
+use status::StatusReport;
+use super::proto::{Failure, NodeEstimate, NodeInfoFull, RankedCandidate, Reply, Request, Response};
+
+mod blocking;
+mod nonblocking;
+
+pub use self::blocking::{Client, Events};
+pub use self::nonblocking::AsyncClient;
+
+const CONNECT_TIMEOUT_SEC: u64 = 5;
+const REQUEST_TIMEOUT_SEC: u64 = 10;
+const RETRIES_DEFAULT: u32 = 1;
+
+/// Address of the interface listener
+#[derive(Debug, Clone, PartialEq)]
+pub enum Endpoint {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+/// Parse `<ip>:<port>`, `unix:<path>` or absolute socket path
+impl FromStr for Endpoint {
+    type Err = &'static str;
+
+    fn from_str(endpoint: &str) -> ::std::result::Result<Self, Self::Err> {
+        if let Some(path) = endpoint.strip_prefix("unix:") {
+            return Ok(Endpoint::Unix(PathBuf::from(path)));
+        }
+        if endpoint.starts_with('/') {
+            return Ok(Endpoint::Unix(PathBuf::from(endpoint)));
+        }
+
+        endpoint
+            .parse()
+            .map(Endpoint::Tcp)
+            .map_err(|_| "bad interface endpoint")
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Endpoint::Tcp(ref addr) => write!(f, "{}", addr),
+            Endpoint::Unix(ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ClientConfig {
+    pub endpoint: Endpoint,
+    // sent on every new connection
+    pub auth_token: Option<String>,
+    pub connect_timeout: Duration,
+    pub request_timeout: Duration,
+    // attempts to repeat informational request on a new connection
+    pub retries: u32,
+}
+
+impl ClientConfig {
+    pub fn new(endpoint: Endpoint) -> Self {
+        ClientConfig {
+            endpoint,
+            auth_token: None,
+            connect_timeout: Duration::from_secs(CONNECT_TIMEOUT_SEC),
+            request_timeout: Duration::from_secs(REQUEST_TIMEOUT_SEC),
+            retries: RETRIES_DEFAULT,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Error {
+    Io(io::Error),
+    Timeout,
+    // malformed message from agent
+    Decode(serde_json::Error),
+    // request failed on agent side
+    Failure(Failure),
+    // response of unexpected type
+    UnexpectedResponse,
+    Closed,
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Io(ref e) => write!(f, "interface connection error: {}", e),
+            Error::Timeout => write!(f, "interface request timed out"),
+            Error::Decode(ref e) => write!(f, "malformed response: {}", e),
+            Error::Failure(ref failure) => write!(f, "{} (code {})", failure.reason, failure.code),
+            Error::UnexpectedResponse => write!(f, "unexpected response"),
+            Error::Closed => write!(f, "connection closed"),
+        }
+    }
+}
+
+impl error::Error for Error {}
+
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        match e.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => Error::Timeout,
+            _ => Error::Io(e),
+        }
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Decode(e)
+    }
+}
+
+impl Error {
+    /// Whether request could be repeated on a new connection
+    fn is_connection_error(&self) -> bool {
+        matches!(*self, Error::Io(_) | Error::Closed)
+    }
+
+    /// Copy for each of the requests failed together
+    fn duplicate(&self) -> Error {
+        match *self {
+            Error::Io(ref e) => Error::Io(io::Error::new(e.kind(), e.to_string())),
+            Error::Timeout => Error::Timeout,
+            Error::Failure(ref failure) => Error::Failure(failure.clone()),
+            _ => Error::Closed,
+        }
+    }
+}
+
+pub type Result<T> = ::std::result::Result<T, Error>;
+
+/// Page of the full map
+#[derive(Debug, Serialize)]
+pub struct FullMap {
+    pub nodes: NodeList,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct RttEstimate {
+    pub rtt: f32,
+    pub pos_err_a: f32,
+    pub pos_err_b: f32,
+}
+
+/// Estimated RTT between every pair of nodes, None for unknown ones
+#[derive(Debug, Serialize)]
+pub struct DistanceMatrix {
+    pub pos_err: Vec<Option<f32>>,
+    pub rtt: Vec<Vec<Option<f32>>>,
+}
+
+/// Encode request tagged with id
+fn encode(request: &Request, id: u64) -> Result<String> {
+    let mut encoded = serde_json::to_value(request)?;
+    if let Value::Object(ref mut fields) = encoded {
+        fields.insert("id".to_string(), Value::from(id));
+    }
+    Ok(serde_json::to_string(&encoded)?)
+}
+
+/// Decode response and id of the request it answers
+fn decode(msg: &str) -> Result<(Option<u64>, Response)> {
+    let reply: Reply = serde_json::from_str(msg)?;
+    Ok((reply.id.and_then(|id| id.as_u64()), reply.response))
+}
+
+fn auth_request(token: &str) -> Request {
+    Request::Auth {
+        token: token.to_string(),
+    }
+}
+
+/// Convert failure response into error
+fn check(response: Response) -> Result<Response> {
+    match response {
+        Response::Failure(failure) => Err(Error::Failure(failure)),
+        response => Ok(response),
+    }
+}
+
+/* Typed results of responses */
+
+fn location(response: Response) -> Result<NodeCoordinates> {
+    match response {
+        Response::Location { loc } => Ok(loc),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn full_map(response: Response) -> Result<FullMap> {
+    match response {
+        Response::FullMap { nodes, next_cursor } => Ok(FullMap { nodes, next_cursor }),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn node_info(response: Response) -> Result<NodeInfoFull> {
+    match response {
+        Response::NodeInfo { info } => Ok(info),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn recent_nodes(response: Response) -> Result<NodeList> {
+    match response {
+        Response::RecentNodes { nodes } => Ok(nodes),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn nearest_nodes(response: Response) -> Result<Vec<NodeEstimate>> {
+    match response {
+        Response::NearestNodes { nodes } => Ok(nodes),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn rtt_estimate(response: Response) -> Result<RttEstimate> {
+    match response {
+        Response::RttEstimate {
+            rtt,
+            pos_err_a,
+            pos_err_b,
+        } => Ok(RttEstimate {
+            rtt,
+            pos_err_a,
+            pos_err_b,
+        }),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn distance_matrix(response: Response) -> Result<DistanceMatrix> {
+    match response {
+        Response::DistanceMatrix { pos_err, rtt } => Ok(DistanceMatrix { pos_err, rtt }),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn ranked_candidates(response: Response) -> Result<Vec<RankedCandidate>> {
+    match response {
+        Response::RankedCandidates { candidates } => Ok(candidates),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn status(response: Response) -> Result<StatusReport> {
+    match response {
+        Response::Status { status } => Ok(status),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn batch(response: Response) -> Result<Vec<Response>> {
+    match response {
+        Response::Batch { responses } => Ok(responses.into_iter().map(|r| r.response).collect()),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn reloaded(response: Response) -> Result<ReloadReport> {
+    match response {
+        Response::Reloaded { report } => Ok(report),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+fn done(response: Response) -> Result<()> {
+    match response {
+        Response::Done => Ok(()),
+        _ => Err(Error::UnexpectedResponse),
+    }
+}
+
+/// Wrap requests of the batch
+fn batch_request(requests: Vec<Request>) -> Result<Request> {
+    let requests = requests
+        .iter()
+        .map(serde_json::to_value)
+        .collect::<::std::result::Result<Vec<Value>, _>>()?;
+    Ok(Request::Batch { requests })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn endpoint_parsing() {
+        assert_eq!(
+            "127.0.0.1:4001".parse(),
+            Ok(Endpoint::Tcp("127.0.0.1:4001".parse().unwrap()))
+        );
+        assert_eq!(
+            "unix:agent.sock".parse(),
+            Ok(Endpoint::Unix(PathBuf::from("agent.sock")))
+        );
+        assert_eq!(
+            "/run/netloc.sock".parse(),
+            Ok(Endpoint::Unix(PathBuf::from("/run/netloc.sock")))
+        );
+        assert!("localhost".parse::<Endpoint>().is_err());
+    }
+
+    #[test]
+    fn request_encoding() {
+        let encoded = encode(&Request::GetNodeInfo { node_addr: "10.0.0.1:1".to_string() }, 3).unwrap();
+        let value: Value = serde_json::from_str(&encoded).unwrap();
+        assert_eq!(value, json!({"action": "get_node_info", "node_addr": "10.0.0.1:1", "id": 3}));
+
+        let (id, response) = decode(r#"{"type":"failure","code":200,"reason":"node not found","id":3}"#).unwrap();
+        assert_eq!(id, Some(3));
+        match check(response) {
+            Err(Error::Failure(failure)) => assert_eq!(failure.code, 200),
+            other => panic!("unexpected result: {:?}", other),
+        }
+    }
+}
@@ -0,0 +1,541 @@
+/// Asynchronous interface client
+///
+/// Requests of all clones of `AsyncClient` are pipelined over a single
+/// connection owned by the background task, responses are matched by id.
+///
+use std::collections::{BTreeMap, VecDeque};
+use std::mem;
+use std::sync::Arc;
+
+use futures::future;
+use futures::sync::{mpsc, oneshot};
+
+use tokio;
+use tokio::codec::{Decoder, Framed, LinesCodec};
+use tokio::net::{TcpStream, UnixStream};
+use tokio::prelude::*;
+use tokio::timer::{self, Timeout};
+
+use agent::{NodeCoordinates, NodeList, ReloadReport};
+use status::StatusReport;
+use super::super::proto::{Event, FullMapParams, NodeEstimate, NodeInfoFull, Point};
+use super::super::proto::{RankedCandidate, Request, Response, SubscribeParams, UnknownPlacement};
+use super::{auth_request, batch_request, check, decode, encode};
+use super::{ClientConfig, DistanceMatrix, Endpoint, Error, FullMap, Result, RttEstimate};
+
+/// Stream connected to the interface listener
+trait Io: AsyncRead + AsyncWrite + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Send> Io for T {}
+
+type Connection = Framed<Box<dyn Io>, LinesCodec>;
+
+pub type ClientFuture<T> = Box<dyn Future<Item = T, Error = Error> + Send>;
+pub type EventStream = Box<dyn Stream<Item = Event, Error = Error> + Send>;
+
+fn timeout_error(e: timer::timeout::Error<Error>) -> Error {
+    e.into_inner().unwrap_or(Error::Timeout)
+}
+
+/// Send single request and wait for the response
+fn exchange(conn: Connection, request: &Request, id: u64) -> ClientFuture<(Response, Connection)> {
+    let encoded = match encode(request, id) {
+        Ok(encoded) => encoded,
+        Err(e) => return Box::new(future::err(e)),
+    };
+
+    let exchange = conn
+        .send(encoded)
+        .and_then(|conn| conn.into_future().map_err(|(e, _)| e))
+        .from_err()
+        .and_then(|(line, conn)| match line {
+            Some(line) => decode(&line).and_then(|(_, response)| check(response)).map(|r| (r, conn)),
+            None => Err(Error::Closed),
+        });
+
+    Box::new(exchange)
+}
+
+/// Connect and authenticate if token is configured
+fn connect(config: &ClientConfig) -> ClientFuture<Connection> {
+    let stream: Box<dyn Future<Item = Box<dyn Io>, Error = _> + Send> = match config.endpoint {
+        Endpoint::Tcp(ref addr) => Box::new(TcpStream::connect(addr).map(|s| {
+            // best effort, requests are small
+            let _ = s.set_nodelay(true);
+            Box::new(s) as Box<dyn Io>
+        })),
+        Endpoint::Unix(ref path) => Box::new(UnixStream::connect(path).map(|s| Box::new(s) as Box<dyn Io>)),
+    };
+
+    let token = config.auth_token.clone();
+    let connected = stream
+        .from_err()
+        .and_then(move |stream| -> ClientFuture<Connection> {
+            let conn = LinesCodec::new().framed(stream);
+            match token {
+                Some(token) => Box::new(exchange(conn, &auth_request(&token), 0).and_then(
+                    |(response, conn)| match response {
+                        Response::Authenticated => Ok(conn),
+                        _ => Err(Error::UnexpectedResponse),
+                    },
+                )),
+                None => Box::new(future::ok(conn)),
+            }
+        });
+
+    Box::new(Timeout::new(connected, config.connect_timeout).map_err(timeout_error))
+}
+
+struct Call {
+    request: Request,
+    // attempts left to repeat request on a new connection
+    retries: u32,
+    reply: oneshot::Sender<Result<Response>>,
+}
+
+impl Call {
+    fn fail(self, e: Error) {
+        // nobody waits for the response if receiver is dropped
+        let _ = self.reply.send(Err(e));
+    }
+}
+
+enum State {
+    Idle,
+    Connecting(ClientFuture<Connection>),
+    Connected(Connection),
+}
+
+/// Background task owning the connection
+struct Worker {
+    config: Arc<ClientConfig>,
+    calls: mpsc::UnboundedReceiver<Call>,
+    // all clients are dropped
+    closed: bool,
+    // calls not yet sent
+    queue: VecDeque<Call>,
+    // calls sent over current connection by id
+    pending: BTreeMap<u64, Call>,
+    next_id: u64,
+    state: State,
+}
+
+impl Worker {
+    fn receive_calls(&mut self) {
+        while !self.closed {
+            match self.calls.poll() {
+                Ok(Async::Ready(Some(call))) => self.queue.push_back(call),
+                Ok(Async::Ready(None)) | Err(()) => self.closed = true,
+                Ok(Async::NotReady) => break,
+            }
+        }
+    }
+
+    /// Send queued calls and deliver responses, ready when connection is done
+    fn exchange(&mut self, conn: &mut Connection) -> Poll<(), Error> {
+        while let Some(call) = self.queue.pop_front() {
+            if call.reply.is_canceled() {
+                continue;
+            }
+
+            let encoded = match encode(&call.request, self.next_id) {
+                Ok(encoded) => encoded,
+                Err(e) => {
+                    call.fail(e);
+                    continue;
+                }
+            };
+
+            if let AsyncSink::NotReady(_) = conn.start_send(encoded)? {
+                self.queue.push_front(call);
+                break;
+            }
+            self.pending.insert(self.next_id, call);
+            self.next_id += 1;
+        }
+        conn.poll_complete()?;
+
+        loop {
+            match conn.poll()? {
+                Async::Ready(Some(line)) => {
+                    // caller of the malformed response gets timeout,
+                    // the rest keep using the connection
+                    let (id, response) = match decode(&line) {
+                        Ok(decoded) => decoded,
+                        Err(e) => {
+                            warn!("malformed response from {}: {}", self.config.endpoint, e);
+                            continue;
+                        }
+                    };
+                    match id.and_then(|id| self.pending.remove(&id)) {
+                        Some(call) => {
+                            let _ = call.reply.send(check(response));
+                        }
+                        None => debug!("response of unknown request: {}", line),
+                    }
+                }
+                Async::Ready(None) => return Ok(Async::Ready(())),
+                Async::NotReady => break,
+            }
+        }
+        self.drop_cancelled();
+
+        if self.closed && self.queue.is_empty() && self.pending.is_empty() {
+            Ok(Async::Ready(()))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+
+    /// Forget sent calls nobody waits for anymore, e.g. timed out ones.
+    /// Task is woken up when caller of any pending call gives up.
+    fn drop_cancelled(&mut self) {
+        self.pending
+            .retain(|_, call| call.reply.poll_cancel() == Ok(Async::NotReady));
+    }
+
+    /// Repeat calls of the lost connection if allowed, fail the rest
+    fn disconnected(&mut self, e: Error) {
+        if !self.pending.is_empty() {
+            debug!("connection to {} lost: {}", self.config.endpoint, e);
+        }
+
+        let pending = mem::take(&mut self.pending);
+        for (_, mut call) in pending.into_iter().rev() {
+            if e.is_connection_error() && call.retries > 0 {
+                call.retries -= 1;
+                self.queue.push_front(call);
+            } else {
+                call.fail(e.duplicate());
+            }
+        }
+    }
+}
+
+impl Future for Worker {
+    type Item = ();
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<(), ()> {
+        self.receive_calls();
+
+        loop {
+            self.state = match mem::replace(&mut self.state, State::Idle) {
+                State::Idle => {
+                    if self.queue.is_empty() {
+                        return Ok(if self.closed { Async::Ready(()) } else { Async::NotReady });
+                    }
+                    State::Connecting(connect(&self.config))
+                }
+
+                State::Connecting(mut connecting) => match connecting.poll() {
+                    Ok(Async::Ready(conn)) => State::Connected(conn),
+                    Ok(Async::NotReady) => {
+                        self.state = State::Connecting(connecting);
+                        return Ok(Async::NotReady);
+                    }
+                    Err(e) => {
+                        debug!("failed to connect to {}: {}", self.config.endpoint, e);
+                        for call in self.queue.drain(..) {
+                            call.fail(e.duplicate());
+                        }
+                        State::Idle
+                    }
+                },
+
+                State::Connected(mut conn) => match self.exchange(&mut conn) {
+                    Ok(Async::NotReady) => {
+                        self.state = State::Connected(conn);
+                        return Ok(Async::NotReady);
+                    }
+                    Ok(Async::Ready(())) => {
+                        self.disconnected(Error::Closed);
+                        State::Idle
+                    }
+                    Err(e) => {
+                        self.disconnected(e);
+                        State::Idle
+                    }
+                },
+            };
+        }
+    }
+}
+
+/// Client pipelining requests over shared connection
+///
+/// Must be created within tokio runtime, connection is closed
+/// when all clones of the client are dropped.
+#[derive(Clone)]
+pub struct AsyncClient {
+    config: Arc<ClientConfig>,
+    calls: mpsc::UnboundedSender<Call>,
+}
+
+impl AsyncClient {
+    /// Create client, connection is established with the first request
+    pub fn new(config: ClientConfig) -> Self {
+        let config = Arc::new(config);
+        let (tx, rx) = mpsc::unbounded();
+
+        tokio::spawn(Worker {
+            config: config.clone(),
+            calls: rx,
+            closed: false,
+            queue: VecDeque::new(),
+            pending: BTreeMap::new(),
+            next_id: 1,
+            state: State::Idle,
+        });
+
+        AsyncClient { config, calls: tx }
+    }
+
+    /// Send request and wait for its response, failure is returned as error.
+    /// Request timeout includes time to (re)connect.
+    pub fn request(&self, request: Request) -> ClientFuture<Response> {
+        let (tx, rx) = oneshot::channel();
+        let call = Call {
+            retries: if request.is_admin() { 0 } else { self.config.retries },
+            request,
+            reply: tx,
+        };
+
+        if self.calls.unbounded_send(call).is_err() {
+            return Box::new(future::err(Error::Closed));
+        }
+
+        let response = rx.map_err(|_| Error::Closed).and_then(|result| result);
+        Box::new(Timeout::new(response, self.config.request_timeout).map_err(timeout_error))
+    }
+
+    pub fn get_location(&self) -> ClientFuture<NodeCoordinates> {
+        Box::new(self.request(Request::GetLocation).and_then(super::location))
+    }
+
+    pub fn get_full_map(&self, params: FullMapParams) -> ClientFuture<FullMap> {
+        Box::new(self.request(Request::GetFullMap(params)).and_then(super::full_map))
+    }
+
+    pub fn get_node_info(&self, node_addr: &str) -> ClientFuture<NodeInfoFull> {
+        let request = Request::GetNodeInfo {
+            node_addr: node_addr.to_string(),
+        };
+        Box::new(self.request(request).and_then(super::node_info))
+    }
+
+    pub fn get_recent_nodes(&self, max_nodes: Option<usize>) -> ClientFuture<NodeList> {
+        Box::new(self.request(Request::GetRecentNodes { max_nodes })
+            .and_then(super::recent_nodes))
+    }
+
+    pub fn get_nearest_nodes(
+        &self,
+        k: usize,
+        node_addr: Option<&str>,
+        coordinates: Option<Point>,
+        max_pos_err: Option<f32>,
+        max_age: Option<u64>,
+    ) -> ClientFuture<Vec<NodeEstimate>> {
+        let request = Request::GetNearestNodes {
+            k,
+            node_addr: node_addr.map(|a| a.to_string()),
+            coordinates,
+            max_pos_err,
+            max_age,
+        };
+        Box::new(self.request(request).and_then(super::nearest_nodes))
+    }
+
+    /// Estimate RTT between nodes, `node_b` is the local node by default
+    pub fn estimate_rtt(&self, node_a: &str, node_b: Option<&str>) -> ClientFuture<RttEstimate> {
+        let request = Request::EstimateRtt {
+            node_a: node_a.to_string(),
+            node_b: node_b.map(|b| b.to_string()),
+        };
+        Box::new(self.request(request).and_then(super::rtt_estimate))
+    }
+
+    pub fn distance_matrix(&self, nodes: &[&str]) -> ClientFuture<DistanceMatrix> {
+        let request = Request::DistanceMatrix {
+            nodes: nodes.iter().map(|n| n.to_string()).collect(),
+        };
+        Box::new(self.request(request).and_then(super::distance_matrix))
+    }
+
+    pub fn rank_candidates(
+        &self,
+        candidates: &[&str],
+        unknown: UnknownPlacement,
+    ) -> ClientFuture<Vec<RankedCandidate>> {
+        let request = Request::RankCandidates {
+            candidates: candidates.iter().map(|c| c.to_string()).collect(),
+            unknown,
+        };
+        Box::new(self.request(request).and_then(super::ranked_candidates))
+    }
+
+    pub fn get_status(&self) -> ClientFuture<StatusReport> {
+        Box::new(self.request(Request::GetStatus).and_then(super::status))
+    }
+
+    /// Answer requests from the same state of agent,
+    /// each of them could fail separately.
+    pub fn batch(&self, requests: Vec<Request>) -> ClientFuture<Vec<Response>> {
+        match batch_request(requests) {
+            Ok(request) => Box::new(self.request(request).and_then(super::batch)),
+            Err(e) => Box::new(future::err(e)),
+        }
+    }
+
+    /* Administrative actions */
+
+    pub fn probe_now(&self, node_addr: Option<&str>) -> ClientFuture<()> {
+        let request = Request::ProbeNow {
+            node_addr: node_addr.map(|a| a.to_string()),
+        };
+        Box::new(self.request(request).and_then(super::done))
+    }
+
+    pub fn add_peer(&self, node_addr: &str) -> ClientFuture<()> {
+        let request = Request::AddPeer {
+            node_addr: node_addr.to_string(),
+        };
+        Box::new(self.request(request).and_then(super::done))
+    }
+
+    pub fn remove_node(&self, node_addr: &str) -> ClientFuture<()> {
+        let request = Request::RemoveNode {
+            node_addr: node_addr.to_string(),
+        };
+        Box::new(self.request(request).and_then(super::done))
+    }
+
+    pub fn reset_location(&self) -> ClientFuture<()> {
+        Box::new(self.request(Request::ResetLocation).and_then(super::done))
+    }
+
+    pub fn set_probe_period(&self, period_ms: u64) -> ClientFuture<()> {
+        Box::new(self.request(Request::SetProbePeriod { period_ms })
+            .and_then(super::done))
+    }
+
+    /// Make agent re-read its configuration
+    pub fn reload_config(&self) -> ClientFuture<ReloadReport> {
+        Box::new(self.request(Request::ReloadConfig).and_then(super::reloaded))
+    }
+
+    /// Subscribe over dedicated connection, stream ends when it's closed
+    pub fn subscribe(&self, params: SubscribeParams) -> EventStream {
+        let request = Request::Subscribe(params);
+        let subscribed = connect(&self.config)
+            .and_then(move |conn| exchange(conn, &request, 1))
+            .and_then(|(response, conn)| match response {
+                Response::Subscribed { .. } => Ok(conn),
+                _ => Err(Error::UnexpectedResponse),
+            });
+
+        let events = subscribed
+            .map(|conn| {
+                conn.from_err().and_then(|line: String| {
+                    decode(&line).and_then(|(_, response)| match response {
+                        Response::Event { event } => Ok(event),
+                        _ => Err(Error::UnexpectedResponse),
+                    })
+                })
+            })
+            .flatten_stream();
+
+        Box::new(events)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::thread;
+
+    use serde_json::{self, Value};
+    use tokio::runtime::current_thread::Runtime;
+
+    /// Answer two pipelined `get_location` requests in reverse order,
+    /// reporting id of the request as iteration
+    fn reordering_agent() -> Endpoint {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut stream = BufReader::new(listener.accept().unwrap().0);
+            let mut ids = vec![];
+            for _ in 0..2 {
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                ids.push(serde_json::from_str::<Value>(&line).unwrap()["id"].clone());
+            }
+
+            for id in ids.into_iter().rev() {
+                let response = json!({
+                    "type": "location",
+                    "loc": {"x1": 0.0, "x2": 0.0, "height": 0.0, "pos_err": 1.0, "iteration": id},
+                    "id": id,
+                });
+                writeln!(stream.get_mut(), "{}", response).unwrap();
+            }
+        });
+
+        Endpoint::Tcp(addr)
+    }
+
+    #[test]
+    fn pipelined_requests() {
+        let endpoint = reordering_agent();
+        let mut runtime = Runtime::new().unwrap();
+
+        let (first, second) = runtime
+            .block_on(future::lazy(move || {
+                let client = AsyncClient::new(ClientConfig::new(endpoint));
+                client.get_location().join(client.get_location())
+            }))
+            .unwrap();
+
+        assert_eq!((first.iteration, second.iteration), (1, 2));
+    }
+
+    /// Answer `get_location` after a line which is not a response
+    fn garbling_agent() -> Endpoint {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        thread::spawn(move || {
+            let mut stream = BufReader::new(listener.accept().unwrap().0);
+            let mut line = String::new();
+            stream.read_line(&mut line).unwrap();
+            let id = serde_json::from_str::<Value>(&line).unwrap()["id"].clone();
+
+            let response = json!({
+                "type": "location",
+                "loc": {"x1": 0.0, "x2": 0.0, "height": 0.0, "pos_err": 1.0, "iteration": 7},
+                "id": id,
+            });
+            writeln!(stream.get_mut(), "garbage\n{}", response).unwrap();
+        });
+
+        Endpoint::Tcp(addr)
+    }
+
+    #[test]
+    fn malformed_line_skipped() {
+        let endpoint = garbling_agent();
+        let mut runtime = Runtime::new().unwrap();
+
+        let location = runtime
+            .block_on(future::lazy(move || {
+                AsyncClient::new(ClientConfig::new(endpoint)).get_location()
+            }))
+            .unwrap();
+
+        assert_eq!(location.iteration, 7);
+    }
+}
@@ -0,0 +1,476 @@
+/// HTTP/JSON interface
+///
+/// Exposes the same actions as the line-based protocol as REST endpoints,
+/// so that agent could be queried with curl, browsers and other HTTP tools.
+/// Only GET requests without body are supported. Body of other requests
+/// is not read, so connection is closed after responding to them.
+///
+/// Endpoints:
+/// - `/location`
+/// - `/status`
+/// - `/nodes[?name_prefix=<s>][&name_regex=<re>][&network=<cidr>][&max_pos_err=<f>][&updated_since=<sec>][&sort=name|distance|freshness|error][&limit=<N>]`
+/// - `/nodes/recent?max=<N>`
+/// - `/nodes/nearest?k=<N>[&node=<addr>|&x1=<f>&x2=<f>&height=<f>][&max_pos_err=<f>][&max_age=<sec>]`
+/// - `/nodes/<addr>`
+/// - `/rtt?a=<addr>[&b=<addr>]`
+/// - `/matrix?node=<addr>&node=<addr>...`
+/// - `/rank?candidate=<addr>&candidate=<addr>...[&unknown=first|last]`
+/// - `/metrics` - agent metrics in Prometheus text format
+///
+use tokio::codec::{Decoder, Framed, LinesCodec};
+use tokio::io;
+use tokio::prelude::*;
+
+use std::net::SocketAddr;
+use std::str::FromStr;
+
+use metrics;
+use storage::SharedStorage;
+use super::proto::{FullMapParams, Point, Request, Response, SortOrder, UnknownPlacement};
+use super::proto::{REASON_BAD_REQUEST, REASON_NODE_NOT_FOUND, REASON_NO_INFORMATION};
+use super::proto::{REASON_AUTH_FAILED, REASON_AUTH_REQUIRED, REASON_NOT_SUPPORTED};
+use super::proto::{REASON_METHOD_NOT_ALLOWED, REASON_UNKNOWN_ENDPOINT};
+use super::auth::{SharedToken, Token};
+use super::Lifetime;
+use super::actions::process_request;
+use super::query::Cursors;
+
+use serde_json;
+
+const MAX_HEADER_LINES: usize = 100;
+const MAX_LINE_LENGTH: usize = 8192;
+
+const CONTENT_TYPE_JSON: &str = "application/json";
+const CONTENT_TYPE_METRICS: &str = "text/plain; version=0.0.4";
+
+/// HTTP status code corresponding to response
+fn status(response: &Response) -> u16 {
+    match *response {
+        Response::Failure(ref failure) => match &*failure.reason {
+            REASON_NODE_NOT_FOUND | REASON_NO_INFORMATION | REASON_UNKNOWN_ENDPOINT => 404,
+            REASON_AUTH_REQUIRED | REASON_AUTH_FAILED => 401,
+            REASON_METHOD_NOT_ALLOWED => 405,
+            REASON_NOT_SUPPORTED => 501,
+            _ => 400,
+        },
+        _ => 200,
+    }
+}
+
+fn status_text(code: u16) -> &'static str {
+    match code {
+        200 => "OK",
+        400 => "Bad Request",
+        401 => "Unauthorized",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        501 => "Not Implemented",
+        _ => "Unknown",
+    }
+}
+
+/// Decode percent-encoded query component
+fn decode(s: &str) -> Option<String> {
+    let mut bytes = Vec::with_capacity(s.len());
+    let mut iter = s.bytes();
+
+    while let Some(b) = iter.next() {
+        match b {
+            b'%' => {
+                let hex = [iter.next()?, iter.next()?];
+                let hex = ::std::str::from_utf8(&hex).ok()?;
+                bytes.push(u8::from_str_radix(hex, 16).ok()?);
+            }
+            b'+' => bytes.push(b' '),
+            _ => bytes.push(b),
+        }
+    }
+
+    String::from_utf8(bytes).ok()
+}
+
+/// Parsed query string
+struct Query(Vec<(String, String)>);
+
+impl Query {
+    fn parse(query: &str) -> Option<Self> {
+        let mut params = Vec::new();
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let mut kv = pair.splitn(2, '=');
+            let key = decode(kv.next()?)?;
+            let value = decode(kv.next().unwrap_or(""))?;
+            params.push((key, value));
+        }
+
+        Some(Query(params))
+    }
+
+    fn get(&self, key: &str) -> Option<String> {
+        self.0.iter().find(|&(k, _)| k == key).map(|(_, v)| v.clone())
+    }
+
+    fn get_all(&self, key: &str) -> Vec<String> {
+        self.0
+            .iter()
+            .filter(|&(k, _)| k == key)
+            .map(|(_, v)| v.clone())
+            .collect()
+    }
+
+    /// Parse optional value, failing on malformed one
+    fn parse_opt<T: FromStr>(&self, key: &str) -> Result<Option<T>, &'static str> {
+        match self.get(key) {
+            Some(v) => v.parse().map(Some).map_err(|_| REASON_BAD_REQUEST),
+            None => Ok(None),
+        }
+    }
+}
+
+/// Translate request target into interface request
+fn route(method: &str, target: &str) -> Result<Request, &'static str> {
+    if method != "GET" {
+        return Err(REASON_METHOD_NOT_ALLOWED);
+    }
+
+    let mut parts = target.splitn(2, '?');
+    let path = parts.next().unwrap_or("");
+    let query = Query::parse(parts.next().unwrap_or("")).ok_or(REASON_BAD_REQUEST)?;
+
+    let segments: Vec<&str> = path.split('/').filter(|s| !s.is_empty()).collect();
+
+    match segments.as_slice() {
+        ["location"] => Ok(Request::GetLocation),
+        ["status"] => Ok(Request::GetStatus),
+        ["nodes"] => Ok(Request::GetFullMap(FullMapParams {
+            name_prefix: query.get("name_prefix"),
+            name_regex: query.get("name_regex"),
+            network: query.get("network"),
+            max_pos_err: query.parse_opt("max_pos_err")?,
+            updated_since: query.parse_opt("updated_since")?,
+            sort: match query.get("sort").as_deref() {
+                Some("name") => Some(SortOrder::Name),
+                Some("distance") => Some(SortOrder::Distance),
+                Some("freshness") => Some(SortOrder::Freshness),
+                Some("error") => Some(SortOrder::Error),
+                None => None,
+                Some(_) => return Err(REASON_BAD_REQUEST),
+            },
+            limit: query.parse_opt("limit")?,
+            cursor: None,
+        })),
+        ["nodes", "recent"] => Ok(Request::GetRecentNodes {
+            max_nodes: query.parse_opt("max")?,
+        }),
+        ["nodes", "nearest"] => {
+            let coordinates = match (query.parse_opt("x1")?, query.parse_opt("x2")?) {
+                (Some(x1), Some(x2)) => Some(Point {
+                    x1,
+                    x2,
+                    height: query.parse_opt("height")?.unwrap_or(0.0),
+                }),
+                (None, None) => None,
+                _ => return Err(REASON_BAD_REQUEST),
+            };
+
+            Ok(Request::GetNearestNodes {
+                k: query.parse_opt("k")?.ok_or(REASON_BAD_REQUEST)?,
+                node_addr: query.get("node"),
+                coordinates,
+                max_pos_err: query.parse_opt("max_pos_err")?,
+                max_age: query.parse_opt("max_age")?,
+            })
+        }
+        ["nodes", addr] => Ok(Request::GetNodeInfo {
+            node_addr: decode(addr).ok_or(REASON_BAD_REQUEST)?,
+        }),
+        ["rtt"] => Ok(Request::EstimateRtt {
+            node_a: query.get("a").ok_or(REASON_BAD_REQUEST)?,
+            node_b: query.get("b"),
+        }),
+        ["matrix"] => Ok(Request::DistanceMatrix {
+            nodes: query.get_all("node"),
+        }),
+        ["rank"] => Ok(Request::RankCandidates {
+            candidates: query.get_all("candidate"),
+            unknown: match query.get("unknown").as_deref() {
+                Some("first") => UnknownPlacement::First,
+                Some("last") | None => UnknownPlacement::Last,
+                Some(_) => return Err(REASON_BAD_REQUEST),
+            },
+        }),
+        _ => Err(REASON_UNKNOWN_ENDPOINT),
+    }
+}
+
+/// Process parsed request head and encode HTTP response.
+///
+/// Body is newline-terminated, final newline is appended by the codec.
+fn respond(head: &[String], store: &SharedStorage, token: Option<&Token>) -> io::Result<String> {
+    let mut request_line = head[0].split_whitespace();
+    let method = request_line.next().unwrap_or("");
+    let target = request_line.next().unwrap_or("");
+
+    let auth_failure = token.and_then(|token| {
+        let provided = header(head, "authorization").and_then(|v| {
+            let mut parts = v.splitn(2, ' ');
+            match (parts.next(), parts.next()) {
+                (Some(scheme), Some(value)) if scheme.eq_ignore_ascii_case("bearer") => {
+                    Some(value.trim())
+                }
+                _ => None,
+            }
+        });
+
+        match provided {
+            Some(provided) if token.verify(provided) => None,
+            Some(_) => Some(REASON_AUTH_FAILED),
+            None => Some(REASON_AUTH_REQUIRED),
+        }
+    });
+
+    let (code, content_type, body) = if let Some(reason) = auth_failure {
+        let response = Response::failure(reason);
+        (status(&response), CONTENT_TYPE_JSON, serde_json::to_string(&response)?)
+    } else if method == "GET" && target == "/metrics" {
+        // trailing newline is already there
+        let mut body = metrics::render(store);
+        body.pop();
+        (200, CONTENT_TYPE_METRICS, body)
+    } else {
+        let response = match route(method, target) {
+            // next request may come over another connection, so no paging
+            Ok(request) => process_request(request, store.as_ref(), &mut Cursors::disabled()),
+            Err(reason) => Response::failure(reason),
+        };
+        (status(&response), CONTENT_TYPE_JSON, serde_json::to_string(&response)?)
+    };
+
+    // ask client for credentials
+    let authenticate = if code == 401 {
+        "WWW-Authenticate: Bearer\r\n"
+    } else {
+        ""
+    };
+    let connection = if closes_connection(head) {
+        "Connection: close\r\n"
+    } else {
+        ""
+    };
+
+    Ok(format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}{}\r\n{}",
+        code,
+        status_text(code),
+        content_type,
+        body.len() + 1,
+        authenticate,
+        connection,
+        body
+    ))
+}
+
+/// Find value of the header, name is case-insensitive
+fn header<'a>(head: &'a [String], name: &str) -> Option<&'a str> {
+    head[1..]
+        .iter()
+        .filter_map(|h| {
+            let mut kv = h.splitn(2, ':');
+            match (kv.next(), kv.next()) {
+                (Some(k), Some(v)) if k.trim().eq_ignore_ascii_case(name) => Some(v.trim()),
+                _ => None,
+            }
+        })
+        .next()
+}
+
+/// Whether connection should be closed after response
+fn close_requested(head: &[String]) -> bool {
+    let http_10 = head[0].ends_with("HTTP/1.0");
+    let connection = header(head, "connection").map(|c| c.to_ascii_lowercase());
+
+    match connection {
+        Some(ref c) if c == "close" => true,
+        Some(ref c) if c == "keep-alive" => false,
+        _ => http_10,
+    }
+}
+
+/// Whether request is followed by body, malformed length counts as body
+fn has_body(head: &[String]) -> bool {
+    let length = header(head, "content-length").map(|l| l.parse::<u64>().map_err(|_| ()));
+    header(head, "transfer-encoding").is_some() || length.is_some_and(|l| l != Ok(0))
+}
+
+/// Whether connection is closed after response, body is never read
+/// and would be taken for the next request otherwise
+fn closes_connection(head: &[String]) -> bool {
+    close_requested(head) || has_body(head)
+}
+
+/// HTTP connection, serves requests one by one
+pub struct HttpClient<T, U> {
+    stream: Framed<T, U>,
+    peer_addr: SocketAddr,
+    store: SharedStorage,
+    token: SharedToken,
+    lifetime: Lifetime,
+    // request line and headers received so far
+    head: Vec<String>,
+    closing: bool,
+}
+
+impl<T: AsyncRead + AsyncWrite> HttpClient<T, LinesCodec> {
+    pub fn new(s: T, peer_addr: SocketAddr, store: SharedStorage, token: SharedToken, lifetime: Lifetime) -> Self {
+        HttpClient {
+            stream: LinesCodec::new_with_max_length(MAX_LINE_LENGTH).framed(s),
+            peer_addr,
+            store,
+            token,
+            lifetime,
+            head: Vec::new(),
+            closing: false,
+        }
+    }
+}
+
+impl<T: AsyncRead + AsyncWrite> Future for HttpClient<T, LinesCodec> {
+    type Item = ();
+    type Error = io::Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            // request being received is served before closing
+            if !self.closing && self.head.is_empty() && self.lifetime.is_ending() {
+                self.closing = true;
+            }
+
+            if self.closing {
+                try_ready!(self.stream.poll_complete());
+                debug!("http client disconnected: {}", self.peer_addr);
+                return Ok(Async::Ready(()));
+            }
+
+            let line = match try_ready!(self.stream.poll()) {
+                Some(line) => line.trim_end_matches('\r').to_string(),
+                None => {
+                    debug!("http client disconnected: {}", self.peer_addr);
+                    return Ok(Async::Ready(()));
+                }
+            };
+
+            // skip empty lines before request line
+            if line.is_empty() && self.head.is_empty() {
+                continue;
+            }
+
+            if !line.is_empty() {
+                if self.head.len() >= MAX_HEADER_LINES {
+                    return Err(io::Error::new(io::ErrorKind::InvalidData, "too many headers"));
+                }
+                self.head.push(line);
+                continue;
+            }
+
+            // end of request head
+            debug!("http request from {}: {}", self.peer_addr, self.head[0]);
+            let encoded = respond(&self.head, &self.store, self.token.get().as_ref())?;
+            self.closing = closes_connection(&self.head);
+            self.head.clear();
+
+            self.stream.start_send(encoded)?;
+            try_ready!(self.stream.poll_complete());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn query_decoding() {
+        let query = Query::parse("node=%5B%3A%3A1%5D%3A5001&node=10.0.0.1:1&name=a+b").unwrap();
+
+        assert_eq!(query.get_all("node"), vec!["[::1]:5001", "10.0.0.1:1"]);
+        assert_eq!(query.get("name"), Some("a b".to_string()));
+        assert_eq!(query.get("other"), None);
+        assert!(Query::parse("bad=%5").is_none());
+    }
+
+    #[test]
+    fn routing() {
+        match route("GET", "/nodes/recent?max=3") {
+            Ok(Request::GetRecentNodes { max_nodes }) => assert_eq!(max_nodes, Some(3)),
+            other => panic!("unexpected route: {:?}", other),
+        }
+
+        match route("GET", "/nodes/10.0.0.1:5001") {
+            Ok(Request::GetNodeInfo { node_addr }) => assert_eq!(node_addr, "10.0.0.1:5001"),
+            other => panic!("unexpected route: {:?}", other),
+        }
+
+        match route("GET", "/nodes/nearest?k=2&x1=0.5&x2=1") {
+            Ok(Request::GetNearestNodes { k, coordinates, .. }) => {
+                assert_eq!(k, 2);
+                assert_eq!(coordinates.map(|c| c.x2), Some(1.0));
+            }
+            other => panic!("unexpected route: {:?}", other),
+        }
+
+        assert_eq!(route("POST", "/nodes").err(), Some(REASON_METHOD_NOT_ALLOWED));
+        assert_eq!(route("GET", "/unknown").err(), Some(REASON_UNKNOWN_ENDPOINT));
+        assert_eq!(route("GET", "/nodes/recent?max=x").err(), Some(REASON_BAD_REQUEST));
+    }
+
+    #[test]
+    fn failure_status() {
+        let failure = Response::failure;
+
+        assert_eq!(status(&failure(REASON_NODE_NOT_FOUND)), 404);
+        assert_eq!(status(&failure(REASON_BAD_REQUEST)), 400);
+        assert_eq!(status(&failure(REASON_NOT_SUPPORTED)), 501);
+        assert_eq!(
+            status(&Response::FullMap {
+                nodes: vec![],
+                next_cursor: None,
+            }),
+            200
+        );
+    }
+
+    #[test]
+    fn connection_close() {
+        let head = |lines: &[&str]| -> Vec<String> { lines.iter().map(|l| l.to_string()).collect() };
+
+        assert!(!close_requested(&head(&["GET / HTTP/1.1"])));
+        assert!(close_requested(&head(&["GET / HTTP/1.1", "Connection: close"])));
+        assert!(close_requested(&head(&["GET / HTTP/1.0"])));
+        assert!(!close_requested(&head(&["GET / HTTP/1.0", "connection: Keep-Alive"])));
+
+        // body is not read
+        assert!(!closes_connection(&head(&["GET / HTTP/1.1", "Content-Length: 0"])));
+        assert!(closes_connection(&head(&["POST / HTTP/1.1", "Content-Length: 12"])));
+        assert!(closes_connection(&head(&["POST / HTTP/1.1", "Content-Length: x"])));
+        assert!(closes_connection(&head(&["POST / HTTP/1.1", "Transfer-Encoding: chunked"])));
+    }
+
+    #[test]
+    fn bearer_token() {
+        let store: SharedStorage = ::std::sync::Arc::new(::storage::Storage::new());
+        let token = Token::new("secret".to_string());
+        let head = |lines: &[&str]| -> Vec<String> { lines.iter().map(|l| l.to_string()).collect() };
+
+        let missing = respond(&head(&["GET /location HTTP/1.1"]), &store, Some(&token)).unwrap();
+        assert!(missing.starts_with("HTTP/1.1 401 Unauthorized"));
+        assert!(missing.contains("WWW-Authenticate: Bearer"));
+
+        let wrong = head(&["GET /location HTTP/1.1", "Authorization: Bearer other"]);
+        assert!(respond(&wrong, &store, Some(&token)).unwrap().starts_with("HTTP/1.1 401"));
+
+        let valid = head(&["GET /location HTTP/1.1", "authorization: bearer secret"]);
+        assert!(respond(&valid, &store, Some(&token)).unwrap().starts_with("HTTP/1.1 200"));
+
+        let open = head(&["GET /location HTTP/1.1"]);
+        assert!(respond(&open, &store, None).unwrap().starts_with("HTTP/1.1 200"));
+    }
+}
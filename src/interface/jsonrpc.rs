@@ -0,0 +1,225 @@
+/// JSON-RPC 2.0 framing of interface protocol
+///
+/// Method is the name of action and named params are its fields,
+/// successful response is returned as result and failure as error
+/// with the code, reason and details of the failure as its data.
+/// Notifications (calls without id) are processed, but never answered.
+///
+use std::borrow::Cow;
+
+use serde_json::{self, Map, Value};
+
+use super::proto::{ErrorDetails, Failure, Request, Response, ACTIONS};
+
+pub const VERSION: &str = "2.0";
+
+/* Error codes */
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+// action processed, but failed
+pub const SERVER_ERROR: i64 = -32000;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: Cow<'static, str>,
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i64) -> Self {
+        let message = match code {
+            PARSE_ERROR => "parse error",
+            INVALID_REQUEST => "invalid request",
+            METHOD_NOT_FOUND => "method not found",
+            INVALID_PARAMS => "invalid params",
+            _ => "server error",
+        };
+
+        RpcError {
+            code,
+            message: Cow::Borrowed(message),
+            data: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: ErrorDetails) -> Self {
+        self.data = serde_json::to_value(details).ok();
+        self
+    }
+}
+
+impl From<Failure> for RpcError {
+    fn from(failure: Failure) -> Self {
+        RpcError {
+            code: SERVER_ERROR,
+            data: serde_json::to_value(&failure).ok(),
+            message: failure.reason,
+        }
+    }
+}
+
+/// Decoded call, id is None for notifications
+#[derive(Debug)]
+pub struct Call {
+    pub id: Option<Value>,
+    pub request: Result<Request, RpcError>,
+}
+
+/// Whether message uses JSON-RPC framing: single call or batch
+pub fn is_rpc(message: &Value) -> bool {
+    match *message {
+        Value::Array(_) => true,
+        Value::Object(ref call) => call.contains_key("jsonrpc"),
+        _ => false,
+    }
+}
+
+/// Decode single call into interface request
+pub fn decode(message: Value) -> Call {
+    let mut call = match message {
+        Value::Object(call) => call,
+        _ => {
+            return Call {
+                id: Some(Value::Null),
+                request: Err(RpcError::new(INVALID_REQUEST)),
+            }
+        }
+    };
+
+    let id = call.remove("id");
+    let request = decode_request(&mut call);
+
+    // answer invalid requests even without id
+    let id = match (id, &request) {
+        (None, Err(e)) if e.code == INVALID_REQUEST => Some(Value::Null),
+        (id, _) => id,
+    };
+
+    Call { id, request }
+}
+
+fn decode_request(call: &mut Map<String, Value>) -> Result<Request, RpcError> {
+    if call.get("jsonrpc").and_then(|v| v.as_str()) != Some(VERSION) {
+        return Err(RpcError::new(INVALID_REQUEST));
+    }
+
+    let method = match call.remove("method") {
+        Some(Value::String(method)) => method,
+        _ => return Err(RpcError::new(INVALID_REQUEST)),
+    };
+
+    // only named params are supported
+    let mut params = match call.remove("params") {
+        None => Map::new(),
+        Some(Value::Object(params)) => params,
+        Some(Value::Array(_)) => return Err(RpcError::new(INVALID_PARAMS)),
+        Some(_) => return Err(RpcError::new(INVALID_REQUEST)),
+    };
+
+    if !ACTIONS.contains(&method.as_str()) {
+        return Err(RpcError::new(METHOD_NOT_FOUND).with_details(ErrorDetails {
+            action: Some(method),
+            ..ErrorDetails::default()
+        }));
+    }
+
+    params.insert("action".to_string(), Value::String(method));
+    serde_json::from_value(Value::Object(params))
+        .map_err(|e| RpcError::new(INVALID_PARAMS).with_details(ErrorDetails::from(&e)))
+}
+
+/// Encode response of the call
+pub fn reply(id: Value, response: Response) -> Value {
+    match response {
+        Response::Failure(failure) => error(id, RpcError::from(failure)),
+        response => json!({
+            "jsonrpc": VERSION,
+            "result": response,
+            "id": id,
+        }),
+    }
+}
+
+pub fn error(id: Value, error: RpcError) -> Value {
+    let mut encoded = json!({"code": error.code, "message": error.message});
+    if let Some(data) = error.data {
+        encoded["data"] = data;
+    }
+
+    json!({
+        "jsonrpc": VERSION,
+        "error": encoded,
+        "id": id,
+    })
+}
+
+/// Message sent by agent without request
+pub fn notification(method: &str, params: Response) -> Value {
+    json!({
+        "jsonrpc": VERSION,
+        "method": method,
+        "params": params,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_str(message: &str) -> Call {
+        decode(serde_json::from_str(message).unwrap())
+    }
+
+    #[test]
+    fn call_decoding() {
+        let call = decode_str(
+            r#"{"jsonrpc": "2.0", "method": "get_node_info", "params": {"node_addr": "10.0.0.1:1"}, "id": 7}"#,
+        );
+        assert_eq!(call.id, Some(json!(7)));
+        match call.request {
+            Ok(Request::GetNodeInfo { node_addr }) => assert_eq!(node_addr, "10.0.0.1:1"),
+            other => panic!("unexpected request: {:?}", other),
+        }
+
+        let notification = decode_str(r#"{"jsonrpc": "2.0", "method": "get_location"}"#);
+        assert_eq!(notification.id, None);
+        assert!(notification.request.is_ok());
+    }
+
+    #[test]
+    fn call_errors() {
+        let code = |message: &str| decode_str(message).request.unwrap_err().code;
+
+        assert_eq!(code(r#"{"jsonrpc": "1.0", "method": "get_location", "id": 1}"#), INVALID_REQUEST);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "method": "unknown", "id": 1}"#), METHOD_NOT_FOUND);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "method": "get_node_info", "id": 1}"#), INVALID_PARAMS);
+        assert_eq!(code(r#"{"jsonrpc": "2.0", "method": "get_location", "params": [1]}"#), INVALID_PARAMS);
+
+        // invalid request is answered even without id
+        assert_eq!(decode_str(r#"{"jsonrpc": "2.0"}"#).id, Some(Value::Null));
+        assert_eq!(decode_str("1").id, Some(Value::Null));
+    }
+
+    #[test]
+    fn failure_encoded_as_error() {
+        let encoded = reply(json!("a"), Response::failure("node not found"));
+        assert_eq!(
+            encoded,
+            json!({
+                "jsonrpc": "2.0",
+                "error": {
+                    "code": -32000,
+                    "message": "node not found",
+                    "data": {"code": 200, "reason": "node not found"},
+                },
+                "id": "a",
+            })
+        );
+
+        let encoded = reply(json!(1), Response::Done);
+        assert_eq!(encoded, json!({"jsonrpc": "2.0", "result": {"type": "done"}, "id": 1}));
+    }
+}
@@ -2,52 +2,287 @@
 ///
 /// Implements request/response JSON-based protocol server,
 /// that could be used to obtain current network coordinates.
+///
+/// On shutdown listeners stop accepting connections, while
+/// connected clients receive responses already being processed
+/// and get disconnected.
 
 use log;
 use bytes::{BytesMut, Bytes, BufMut};
 
 use tokio;
-use tokio::io;
-use tokio::net::{TcpListener, TcpStream};
+use tokio::net::TcpListener;
 use tokio::prelude::*;
 use tokio::runtime::current_thread::Runtime;
 
-use tokio_io::codec::{Framed, LinesCodec};
+use tokio::codec::{Framed, LinesCodec};
 use futures::future::{self, Either};
+use futures::future::Shared;
+use futures::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use futures::sync::oneshot;
 
+use std::fs;
 use std::net::SocketAddr;
 
+use tokio_rustls::TlsAcceptor;
+
+use error::{Error, Result};
 use storage;
+use agent::{Control, SharedReloader};
 use self::client::Client;
+use self::http::HttpClient;
 
 mod actions;
+mod auth;
 mod client;
-mod proto;
+pub mod client_api;
+mod http;
+mod jsonrpc;
+pub mod proto;
+mod query;
+mod subscription;
+mod tls;
+mod unix;
+
+pub use self::auth::{SharedToken, Token};
+pub use self::tls::TlsConfig;
+pub use self::unix::{parse_mode, parse_owner, UnixSocketConfig};
+
+
+/// Settings shared by all interface listeners
+#[derive(Debug, Clone)]
+pub struct InterfaceConfig {
+    pub addr: Option<SocketAddr>,
+    pub http_addr: Option<SocketAddr>,
+    pub unix_socket: Option<UnixSocketConfig>,
+    // changed on configuration reload
+    pub auth_token: SharedToken,
+    pub tls: Option<TlsConfig>,
+}
+
+
+/// Listener accepting administrative actions besides informational ones
+pub struct AdminConfig {
+    pub addr: SocketAddr,
+    // absent for landmark, which has no transmitter
+    pub control: Option<UnboundedSender<Control>>,
+    pub reload: SharedReloader,
+}
+
+
+/// Permissions of connections accepted by listener
+#[derive(Clone, Default)]
+pub struct Access {
+    token: SharedToken,
+    // transmitter of admin listener, absent for landmark
+    control: Option<UnboundedSender<Control>>,
+    // present only for admin listener
+    reload: Option<SharedReloader>,
+}
+
+
+/// Shutdown notice shared by listeners and their connections
+#[derive(Clone)]
+pub struct Lifetime {
+    shutdown: Shared<oneshot::Receiver<()>>,
+    // channel is closed when the last holder is dropped
+    _alive: UnboundedSender<()>,
+}
 
+impl Lifetime {
+    /// Whether shutdown has started, current task is notified when it does
+    pub fn is_ending(&mut self) -> bool {
+        !matches!(self.shutdown.poll(), Ok(Async::NotReady))
+    }
 
-fn process_stream(stream: TcpStream, store: storage::SharedStorage) {
-    tokio::spawn(Client::new(stream, store).map_err(|e| {
-        error!("interface client error: {}", e)
-    }));
+    /// Run listener until shutdown
+    fn limit<F>(&self, listener: F) -> impl Future<Item = (), Error = ()>
+    where
+        F: Future<Item = (), Error = ()>,
+    {
+        let shutdown = self.shutdown.clone().then(|_| Ok(()));
+        listener.select(shutdown).then(|_| Ok(()))
+    }
 }
 
 
-pub fn run_server(addr: SocketAddr, store: storage::SharedStorage) -> io::Result<()> {
-    debug!("interface server started at {}", addr);
-    let server = TcpListener::bind(&addr)?
+#[derive(Debug, Clone, Copy)]
+enum Protocol {
+    Lines,
+    Http,
+}
+
+
+fn process_stream<T>(
+    stream: T,
+    peer_addr: SocketAddr,
+    protocol: Protocol,
+    store: storage::SharedStorage,
+    access: Access,
+    lifetime: Lifetime,
+) where
+    T: AsyncRead + AsyncWrite + Send + 'static,
+{
+    match protocol {
+        Protocol::Lines => tokio::spawn(Client::new(stream, peer_addr.to_string(), store, access, lifetime).map_err(|e| {
+            error!("interface client error: {}", e)
+        })),
+        Protocol::Http => tokio::spawn(HttpClient::new(stream, peer_addr, store, access.token, lifetime).map_err(|e| {
+            error!("http client error: {}", e)
+        })),
+    };
+}
+
+
+fn listen(
+    addr: &SocketAddr,
+    protocol: Protocol,
+    store: storage::SharedStorage,
+    access: Access,
+    tls: Option<TlsAcceptor>,
+    lifetime: &Lifetime,
+) -> Result<impl Future<Item = (), Error = ()>> {
+    let listener = TcpListener::bind(addr).map_err(|source| Error::Bind {
+        addr: addr.to_string(),
+        source,
+    })?;
+    debug!("{:?} interface server started at {}", protocol, addr);
+    let connection_lifetime = lifetime.clone();
+    let server = listener
         .incoming()
         .for_each(move |stream| {
             let peer_addr = stream.peer_addr()?;
             info!("client connected: {}", peer_addr);
 
-            process_stream(stream, store.clone());
+            let store = store.clone();
+            let access = access.clone();
+            let lifetime = connection_lifetime.clone();
+            match tls {
+                Some(ref tls) => {
+                    let handshake = tls
+                        .accept(stream)
+                        .map(move |stream| process_stream(stream, peer_addr, protocol, store, access, lifetime))
+                        .map_err(move |e| warn!("tls handshake with {} failed: {}", peer_addr, e));
+                    tokio::spawn(handshake);
+                }
+                None => process_stream(stream, peer_addr, protocol, store, access, lifetime),
+            }
+            Ok(())
+        })
+        .map_err(|e| error!("accept connection: {}", e));
+
+    Ok(lifetime.limit(server))
+}
+
+
+/// Serve line-based protocol on Unix socket, TLS is never used locally.
+/// Socket file is removed on shutdown.
+fn listen_unix(
+    config: &UnixSocketConfig,
+    store: storage::SharedStorage,
+    access: Access,
+    lifetime: &Lifetime,
+) -> Result<impl Future<Item = (), Error = ()>> {
+    debug!("unix interface server started at {}", config.path.display());
+    let path = config.path.display().to_string();
+    let socket_path = config.path.clone();
+    let connection_lifetime = lifetime.clone();
+    let server = unix::bind(config)?
+        .incoming()
+        .for_each(move |stream| {
+            info!("client connected: {}", path);
+
+            let lifetime = connection_lifetime.clone();
+            let client = Client::new(stream, path.clone(), store.clone(), access.clone(), lifetime);
+            tokio::spawn(client.map_err(|e| error!("interface client error: {}", e)));
             Ok(())
         })
         .map_err(|e| error!("accept connection: {}", e));
 
-    // single threaded runtime
-    let mut r = Runtime::new().unwrap();
-    let _ = r.spawn(server).run();
+    Ok(lifetime.limit(server).then(move |_| {
+        if let Err(e) = fs::remove_file(&socket_path) {
+            warn!("cannot remove {}: {}", socket_path.display(), e);
+        }
+        Ok(())
+    }))
+}
+
+
+/// Interface listeners bound to their addresses, not serving yet
+pub struct Server {
+    listeners: Vec<Box<dyn Future<Item = (), Error = ()> + Send>>,
+    drain: Drain,
+}
+
+/// Shuts down listeners of the running server
+pub struct Drain {
+    shutdown: oneshot::Sender<()>,
+    connections: UnboundedReceiver<()>,
+}
+
+impl Drain {
+    /// Stop accepting connections, resolves when all clients are disconnected
+    pub fn start(self) -> impl Future<Item = (), Error = ()> {
+        let _ = self.shutdown.send(());
+        self.connections.for_each(|_| Ok(()))
+    }
+}
+
+impl Server {
+    /// Bind interface and optional HTTP, Unix socket and admin listeners sharing the same storage.
+    pub fn bind(
+        config: &InterfaceConfig,
+        admin: Option<AdminConfig>,
+        store: storage::SharedStorage,
+    ) -> Result<Server> {
+        let tls = match config.tls {
+            Some(ref tls) => Some(tls::load_acceptor(tls)?),
+            None => None,
+        };
+        let access = Access {
+            token: config.auth_token.clone(),
+            control: None,
+            reload: None,
+        };
+
+        let (shutdown, shutdown_rx) = oneshot::channel();
+        let (alive, connections) = mpsc::unbounded();
+        let lifetime = Lifetime {
+            shutdown: shutdown_rx.shared(),
+            _alive: alive,
+        };
+
+        let mut listeners: Vec<Box<dyn Future<Item = (), Error = ()> + Send>> = vec![];
+        if let Some(ref addr) = config.addr {
+            listeners.push(Box::new(listen(addr, Protocol::Lines, store.clone(), access.clone(), tls.clone(), &lifetime)?));
+        }
+        if let Some(ref http_addr) = config.http_addr {
+            listeners.push(Box::new(listen(http_addr, Protocol::Http, store.clone(), access.clone(), tls.clone(), &lifetime)?));
+        }
+        if let Some(ref unix_socket) = config.unix_socket {
+            listeners.push(Box::new(listen_unix(unix_socket, store.clone(), access.clone(), &lifetime)?));
+        }
+        if let Some(admin) = admin {
+            let admin_access = Access {
+                control: admin.control,
+                reload: Some(admin.reload),
+                ..access
+            };
+            listeners.push(Box::new(listen(&admin.addr, Protocol::Lines, store, admin_access, tls, &lifetime)?));
+        }
+
+        Ok(Server {
+            listeners,
+            drain: Drain { shutdown, connections },
+        })
+    }
 
-    Ok(())
+    /// Spawn listeners on the runtime, returned drain shuts them down gracefully.
+    /// Connections are closed immediately when runtime is dropped.
+    pub fn spawn(self, runtime: &mut Runtime) -> Drain {
+        for listener in self.listeners {
+            runtime.spawn(listener);
+        }
+        self.drain
+    }
 }
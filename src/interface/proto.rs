@@ -3,9 +3,13 @@
 //! Encoded messages are delimited with newline symbols
 //!
 
+use std::borrow::Cow;
 use std::net::IpAddr;
 
-use agent::{NodeInfo, NodeCoordinates, NodeList};
+use serde_json::{self, Value};
+
+use agent::{NodeInfo, NodeCoordinates, NodeList, ReloadReport};
+use status::StatusReport;
 use storage::Node;
 
 /* Error reasons */
@@ -13,35 +17,424 @@ pub const REASON_BAD_REQUEST: &str = "bad request";
 pub const REASON_BAD_NODE_ADDR: &str = "bad node address";
 pub const REASON_NODE_NOT_FOUND: &str = "node not found";
 pub const REASON_NO_INFORMATION: &str = "no information";
+pub const REASON_AMBIGUOUS_REFERENCE: &str = "ambiguous reference point";
+pub const REASON_TOO_MANY_NODES: &str = "too many nodes";
+pub const REASON_NOT_SUPPORTED: &str = "action not supported";
+pub const REASON_ADMIN_DISABLED: &str = "admin actions disabled";
+pub const REASON_BAD_PERIOD: &str = "bad probe period";
+pub const REASON_AUTH_REQUIRED: &str = "authentication required";
+pub const REASON_AUTH_FAILED: &str = "authentication failed";
+pub const REASON_BATCH_TOO_LARGE: &str = "batch too large";
+pub const REASON_NOT_IN_BATCH: &str = "action not allowed in batch";
+pub const REASON_UNKNOWN_ACTION: &str = "unknown action";
+pub const REASON_UNKNOWN_ENDPOINT: &str = "unknown endpoint";
+pub const REASON_METHOD_NOT_ALLOWED: &str = "method not allowed";
+pub const REASON_BAD_FILTER: &str = "bad filter";
+pub const REASON_BAD_CURSOR: &str = "bad or expired cursor";
+pub const REASON_RELOAD_FAILED: &str = "configuration reload failed";
+
+/* Error codes, stable across versions */
+// malformed or invalid requests
+pub const ERROR_BAD_REQUEST: u16 = 100;
+pub const ERROR_UNKNOWN_ACTION: u16 = 101;
+pub const ERROR_BAD_NODE_ADDR: u16 = 102;
+pub const ERROR_AMBIGUOUS_REFERENCE: u16 = 103;
+pub const ERROR_TOO_MANY_NODES: u16 = 104;
+pub const ERROR_BAD_PERIOD: u16 = 105;
+pub const ERROR_BATCH_TOO_LARGE: u16 = 106;
+pub const ERROR_NOT_IN_BATCH: u16 = 107;
+pub const ERROR_UNKNOWN_ENDPOINT: u16 = 108;
+pub const ERROR_METHOD_NOT_ALLOWED: u16 = 109;
+pub const ERROR_BAD_FILTER: u16 = 110;
+pub const ERROR_BAD_CURSOR: u16 = 111;
+// requested information is absent
+pub const ERROR_NODE_NOT_FOUND: u16 = 200;
+pub const ERROR_NO_INFORMATION: u16 = 201;
+// access control
+pub const ERROR_AUTH_REQUIRED: u16 = 300;
+pub const ERROR_AUTH_FAILED: u16 = 301;
+pub const ERROR_ADMIN_DISABLED: u16 = 302;
+// agent is not able to process request
+pub const ERROR_NOT_SUPPORTED: u16 = 400;
+pub const ERROR_RELOAD_FAILED: u16 = 401;
+pub const ERROR_INTERNAL: u16 = 500;
+
+/// Stable code of the failure reason
+pub fn error_code(reason: &str) -> u16 {
+    match reason {
+        REASON_BAD_REQUEST => ERROR_BAD_REQUEST,
+        REASON_UNKNOWN_ACTION => ERROR_UNKNOWN_ACTION,
+        REASON_BAD_NODE_ADDR => ERROR_BAD_NODE_ADDR,
+        REASON_AMBIGUOUS_REFERENCE => ERROR_AMBIGUOUS_REFERENCE,
+        REASON_TOO_MANY_NODES => ERROR_TOO_MANY_NODES,
+        REASON_BAD_PERIOD => ERROR_BAD_PERIOD,
+        REASON_BATCH_TOO_LARGE => ERROR_BATCH_TOO_LARGE,
+        REASON_NOT_IN_BATCH => ERROR_NOT_IN_BATCH,
+        REASON_UNKNOWN_ENDPOINT => ERROR_UNKNOWN_ENDPOINT,
+        REASON_METHOD_NOT_ALLOWED => ERROR_METHOD_NOT_ALLOWED,
+        REASON_BAD_FILTER => ERROR_BAD_FILTER,
+        REASON_BAD_CURSOR => ERROR_BAD_CURSOR,
+        REASON_NODE_NOT_FOUND => ERROR_NODE_NOT_FOUND,
+        REASON_NO_INFORMATION => ERROR_NO_INFORMATION,
+        REASON_AUTH_REQUIRED => ERROR_AUTH_REQUIRED,
+        REASON_AUTH_FAILED => ERROR_AUTH_FAILED,
+        REASON_ADMIN_DISABLED => ERROR_ADMIN_DISABLED,
+        REASON_NOT_SUPPORTED => ERROR_NOT_SUPPORTED,
+        REASON_RELOAD_FAILED => ERROR_RELOAD_FAILED,
+        _ => ERROR_INTERNAL,
+    }
+}
 
 /* Messages */
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, Serialize)]
 #[serde(tag = "action")]
 #[serde(rename_all = "snake_case")]
 pub enum Request {
     GetLocation,
-    GetFullMap,
+    GetFullMap(FullMapParams),
     GetNodeInfo { node_addr: String },
     GetRecentNodes { max_nodes: Option<usize> },
+    GetNearestNodes {
+        k: usize,
+        node_addr: Option<String>,
+        coordinates: Option<Point>,
+        max_pos_err: Option<f32>,
+        max_age: Option<u64>,
+    },
+    EstimateRtt {
+        node_a: String,
+        node_b: Option<String>,
+    },
+    DistanceMatrix { nodes: Vec<String> },
+    RankCandidates {
+        candidates: Vec<String>,
+        #[serde(default)]
+        unknown: UnknownPlacement,
+    },
+    GetStatus,
+    Subscribe(SubscribeParams),
+    Auth { token: String },
+    // requests are decoded one by one, so each could fail separately
+    Batch { requests: Vec<Value> },
+
+    // administrative actions
+    ProbeNow { node_addr: Option<String> },
+    AddPeer { node_addr: String },
+    RemoveNode { node_addr: String },
+    ResetLocation,
+    SetProbePeriod { period_ms: u64 },
+    ReloadConfig,
+}
+
+// action names listed once, match keeps them exhaustive
+macro_rules! actions {
+    ($($request:pat => $name:expr,)*) => {
+        /// Names of all supported actions
+        pub const ACTIONS: &[&str] = &[$($name),*];
+
+        impl Request {
+            /// Action name as it appears in requests
+            pub fn action(&self) -> &'static str {
+                match *self {
+                    $($request => $name,)*
+                }
+            }
+        }
+    };
+}
+
+actions! {
+    Request::GetLocation => "get_location",
+    Request::GetFullMap(_) => "get_full_map",
+    Request::GetNodeInfo { .. } => "get_node_info",
+    Request::GetRecentNodes { .. } => "get_recent_nodes",
+    Request::GetNearestNodes { .. } => "get_nearest_nodes",
+    Request::EstimateRtt { .. } => "estimate_rtt",
+    Request::DistanceMatrix { .. } => "distance_matrix",
+    Request::RankCandidates { .. } => "rank_candidates",
+    Request::GetStatus => "get_status",
+    Request::Subscribe(_) => "subscribe",
+    Request::Auth { .. } => "auth",
+    Request::Batch { .. } => "batch",
+    Request::ProbeNow { .. } => "probe_now",
+    Request::AddPeer { .. } => "add_peer",
+    Request::RemoveNode { .. } => "remove_node",
+    Request::ResetLocation => "reset_location",
+    Request::SetProbePeriod { .. } => "set_probe_period",
+    Request::ReloadConfig => "reload_config",
 }
 
-#[derive(Debug, Serialize)]
+impl Request {
+    /// Decode request, describing what is wrong with the malformed one
+    pub fn decode(message: Value) -> Result<Request, Failure> {
+        if let Some(action) = message.get("action").and_then(|a| a.as_str()) {
+            if !ACTIONS.contains(&action) {
+                return Err(Failure::new(REASON_UNKNOWN_ACTION).with_details(ErrorDetails {
+                    action: Some(action.to_string()),
+                    ..ErrorDetails::default()
+                }));
+            }
+        }
+
+        serde_json::from_value(message)
+            .map_err(|e| Failure::new(REASON_BAD_REQUEST).with_details(ErrorDetails::from(&e)))
+    }
+
+    /// Whether action changes agent's state
+    pub fn is_admin(&self) -> bool {
+        matches!(
+            *self,
+            Request::ProbeNow { .. }
+                | Request::AddPeer { .. }
+                | Request::RemoveNode { .. }
+                | Request::ResetLocation
+                | Request::SetProbePeriod { .. }
+                | Request::ReloadConfig
+        )
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 #[serde(rename_all = "snake_case")]
 #[serde(tag = "type")]
 pub enum Response {
     Location { loc: NodeCoordinates },
-    FullMap { nodes: NodeList },
+    FullMap {
+        nodes: NodeList,
+        // present if there are more nodes to read
+        #[serde(skip_serializing_if = "Option::is_none")]
+        next_cursor: Option<String>,
+    },
     NodeInfo { info: NodeInfoFull },
     RecentNodes { nodes: NodeList },
+    NearestNodes { nodes: Vec<NodeEstimate> },
+    RttEstimate {
+        rtt: f32,
+        pos_err_a: f32,
+        pos_err_b: f32,
+    },
+    // values are in order of requested nodes, null for unknown ones
+    DistanceMatrix {
+        pos_err: Vec<Option<f32>>,
+        rtt: Vec<Vec<Option<f32>>>,
+    },
+    RankedCandidates { candidates: Vec<RankedCandidate> },
+    Status { status: StatusReport },
+    Subscribed { interval_ms: u64 },
+    Authenticated,
+    // administrative action accepted
+    Done,
+    Reloaded { report: ReloadReport },
+    Event {
+        #[serde(flatten)]
+        event: Event,
+    },
+    Batch { responses: Vec<Reply> },
 
     // general unsuccessful response
-    Failure { reason: &'static str },
+    Failure(Failure),
+}
+
+impl Response {
+    pub fn failure(reason: &'static str) -> Self {
+        Response::Failure(Failure::new(reason))
+    }
+}
+
+/// Unsuccessful result of the request
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Failure {
+    // stable code for programmatic handling
+    pub code: u16,
+    // human readable description
+    pub reason: Cow<'static, str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub details: Option<Box<ErrorDetails>>,
+}
+
+impl Failure {
+    pub fn new(reason: &'static str) -> Self {
+        Failure {
+            code: error_code(reason),
+            reason: Cow::Borrowed(reason),
+            details: None,
+        }
+    }
+
+    pub fn with_details(mut self, details: ErrorDetails) -> Self {
+        self.details = Some(Box::new(details));
+        self
+    }
+
+    /// Node address which couldn't be parsed
+    pub fn bad_node_addr(node_addr: &str) -> Self {
+        Failure::new(REASON_BAD_NODE_ADDR).with_details(ErrorDetails {
+            value: Some(node_addr.to_string()),
+            ..ErrorDetails::default()
+        })
+    }
+}
+
+impl From<&'static str> for Failure {
+    fn from(reason: &'static str) -> Self {
+        Failure::new(reason)
+    }
+}
+
+impl From<Failure> for Response {
+    fn from(failure: Failure) -> Self {
+        Response::Failure(failure)
+    }
+}
+
+/// Optional information about the cause of failure
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ErrorDetails {
+    // position of malformed JSON
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub line: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub column: Option<usize>,
+    // requested action which is not known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub action: Option<String>,
+    // offending value, e.g. node address
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    // message of the decoder
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cause: Option<String>,
+}
+
+impl From<&serde_json::Error> for ErrorDetails {
+    fn from(e: &serde_json::Error) -> Self {
+        // errors of decoding from value have no position
+        let position = |n: usize| if e.line() > 0 { Some(n) } else { None };
+
+        ErrorDetails {
+            line: position(e.line()),
+            column: position(e.column()),
+            cause: Some(e.to_string()),
+            ..ErrorDetails::default()
+        }
+    }
+}
+
+/// Response carrying correlation id of the request, if any
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Reply {
+    #[serde(flatten)]
+    pub response: Response,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
 }
 
 /* Protocol specific structures */
 
-#[derive(Debug, Serialize)]
+/// Filters, order and pagination of the full map, all optional
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct FullMapParams {
+    pub name_prefix: Option<String>,
+    pub name_regex: Option<String>,
+    // IP address or network in CIDR notation
+    pub network: Option<String>,
+    pub max_pos_err: Option<f32>,
+    // UNIX time in seconds
+    pub updated_since: Option<u64>,
+    pub sort: Option<SortOrder>,
+    // maximum number of nodes in response, all by default
+    pub limit: Option<usize>,
+    // continuation of previous request, other parameters are ignored
+    pub cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum SortOrder {
+    // by name, then by address
+    Name,
+    // closest to the local node first
+    Distance,
+    // most recently updated first
+    Freshness,
+    // most accurate first
+    Error,
+}
+
+/// Parameters of subscription, all optional
+#[derive(Debug, Default, Deserialize, Serialize)]
+pub struct SubscribeParams {
+    // event types to report, all by default
+    pub events: Option<Vec<EventKind>>,
+    // report only changes of these nodes
+    pub nodes: Option<Vec<String>>,
+    // minimal shift of coordinates to be reported
+    pub min_change: Option<f32>,
+    // node is expired if not updated for given number of seconds
+    pub expire_after: Option<u64>,
+    // how often changes are checked
+    pub interval_ms: Option<u64>,
+    // maximum number of events waiting to be sent
+    pub buffer: Option<usize>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    LocationChanged,
+    NodeJoined,
+    NodeUpdated,
+    NodeExpired,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "event")]
+pub enum Event {
+    LocationChanged { loc: NodeCoordinates },
+    NodeJoined { node: NodeInfoFull },
+    NodeUpdated { node: NodeInfoFull, shift: f32 },
+    NodeExpired { node: NodeInfoFull },
+
+    // changes postponed due to slow client
+    EventsDropped { count: usize },
+}
+
+impl Event {
+    /// Kind of event used in filters, None for events always reported
+    pub fn kind(&self) -> Option<EventKind> {
+        match *self {
+            Event::LocationChanged { .. } => Some(EventKind::LocationChanged),
+            Event::NodeJoined { .. } => Some(EventKind::NodeJoined),
+            Event::NodeUpdated { .. } => Some(EventKind::NodeUpdated),
+            Event::NodeExpired { .. } => Some(EventKind::NodeExpired),
+            Event::EventsDropped { .. } => None,
+        }
+    }
+}
+
+/// Where to put candidates unknown to the local node
+#[derive(Debug, Clone, Copy, PartialEq, Default, Deserialize, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum UnknownPlacement {
+    First,
+    #[default]
+    Last,
+}
+
+/// Candidate with RTT estimated from the local node
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RankedCandidate {
+    pub addr: String,
+    pub known: bool,
+    pub rtt: Option<f32>,
+    pub pos_err: Option<f32>,
+    // seconds since the last update of node coordinates
+    pub age: Option<u64>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
 pub struct NodeInfoFull {
     pub ip: IpAddr,
     pub port: u16,
@@ -50,6 +443,34 @@ pub struct NodeInfoFull {
     pub updated_at: u64,
 }
 
+/// Arbitrary point in the coordinate space
+#[derive(Debug, Deserialize, Serialize)]
+pub struct Point {
+    pub x1: f32,
+    pub x2: f32,
+    #[serde(default)]
+    pub height: f32,
+}
+
+impl From<Point> for NodeCoordinates {
+    fn from(point: Point) -> Self {
+        NodeCoordinates {
+            x1: point.x1,
+            x2: point.x2,
+            height: point.height,
+            ..NodeCoordinates::empty()
+        }
+    }
+}
+
+/// Node information with estimated RTT in seconds
+#[derive(Debug, Serialize, Deserialize)]
+pub struct NodeEstimate {
+    #[serde(flatten)]
+    pub info: NodeInfoFull,
+    pub rtt: f32,
+}
+
 impl From<Node> for NodeInfoFull {
     fn from(node_info: Node) -> Self {
         NodeInfoFull {
@@ -61,3 +482,52 @@ impl From<Node> for NodeInfoFull {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decode_str(message: &str) -> Result<Request, Failure> {
+        Request::decode(serde_json::from_str(message).unwrap())
+    }
+
+    #[test]
+    fn decoding_failures() {
+        let failure = decode_str(r#"{"action": "get_everything"}"#).unwrap_err();
+        assert_eq!(failure.code, ERROR_UNKNOWN_ACTION);
+        assert_eq!(failure.details.unwrap().action, Some("get_everything".to_string()));
+
+        let failure = decode_str(r#"{"action": "get_node_info"}"#).unwrap_err();
+        assert_eq!(failure.code, ERROR_BAD_REQUEST);
+        let details = failure.details.unwrap();
+        assert_eq!(details.cause, Some("missing field `node_addr`".to_string()));
+        assert_eq!(details.line, None);
+
+        assert!(decode_str(r#"{"action": "get_location", "id": 1}"#).is_ok());
+    }
+
+    #[test]
+    fn action_names_decoded() {
+        for &action in ACTIONS {
+            let cause = decode_str(&json!({ "action": action }).to_string())
+                .err()
+                .and_then(|f| f.details)
+                .and_then(|d| d.cause)
+                .unwrap_or_default();
+            assert!(!cause.starts_with("unknown variant"), "{}: {}", action, cause);
+        }
+    }
+
+    #[test]
+    fn failure_encoding() {
+        let encoded = serde_json::to_value(Response::failure(REASON_NO_INFORMATION)).unwrap();
+        assert_eq!(
+            encoded,
+            json!({"type": "failure", "code": 201, "reason": "no information"})
+        );
+
+        let encoded = serde_json::to_value(Response::from(Failure::bad_node_addr("x"))).unwrap();
+        assert_eq!(encoded["details"], json!({"value": "x"}));
+        assert_eq!(error_code("unexpected"), ERROR_INTERNAL);
+    }
+}
@@ -0,0 +1,430 @@
+/// Filtering, sorting and pagination of the full map
+///
+/// Matching nodes are selected from a single storage snapshot.
+/// If response is limited, selection is kept by the connection and the rest
+/// of it could be read with returned cursor, so that pages are consistent.
+/// Cursors are random tokens valid only within the connection they were
+/// issued on, each connection keeps a few latest selections for limited time.
+/// HTTP requests usually come over separate connections, so HTTP responses
+/// are just limited and have no cursor.
+///
+use std::cmp::Ordering;
+use std::collections::VecDeque;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use rand::{self, Rng};
+use regex::Regex;
+
+use agent::{vivaldi, NodeCoordinates};
+use storage::{positioned, Node, Selection, StorageView};
+use super::proto::{ErrorDetails, Failure, FullMapParams, Response, SortOrder};
+use super::proto::{REASON_BAD_CURSOR, REASON_BAD_FILTER};
+
+// every kept selection pins a snapshot, so only a few are kept per connection
+const MAX_KEPT_SELECTIONS: usize = 4;
+const SELECTION_TTL_SEC: u64 = 60;
+
+/// IP network in CIDR notation, single address is a network of its own
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Network {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl Network {
+    pub fn parse(network: &str) -> Option<Network> {
+        let mut parts = network.splitn(2, '/');
+        let addr: IpAddr = parts.next()?.parse().ok()?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+
+        let prefix_len = match parts.next() {
+            Some(len) => len.parse().ok().filter(|&len| len <= max_len)?,
+            None => max_len,
+        };
+
+        Some(Network { addr, prefix_len })
+    }
+
+    pub fn contains(&self, ip: &IpAddr) -> bool {
+        let (net, ip, bits) = match (self.addr, *ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net) as u128, u32::from(ip) as u128, 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+
+        let host_bits = bits - u32::from(self.prefix_len);
+        (net ^ ip).checked_shr(host_bits).unwrap_or(0) == 0
+    }
+}
+
+/// Compiled filters of the request
+struct Filter {
+    name_prefix: Option<String>,
+    name_regex: Option<Regex>,
+    network: Option<Network>,
+    max_pos_err: Option<f32>,
+    updated_since: Option<u64>,
+}
+
+impl Filter {
+    fn new(params: &mut FullMapParams) -> Result<Filter, Failure> {
+        let bad_filter = |value: &str, cause: Option<String>| {
+            Failure::new(REASON_BAD_FILTER).with_details(ErrorDetails {
+                value: Some(value.to_string()),
+                cause,
+                ..ErrorDetails::default()
+            })
+        };
+
+        let name_regex = match params.name_regex {
+            Some(ref regex) => {
+                Some(Regex::new(regex).map_err(|e| bad_filter(regex, Some(e.to_string())))?)
+            }
+            None => None,
+        };
+
+        let network = match params.network {
+            Some(ref network) => Some(Network::parse(network).ok_or_else(|| bad_filter(network, None))?),
+            None => None,
+        };
+
+        Ok(Filter {
+            name_prefix: params.name_prefix.take(),
+            name_regex,
+            network,
+            max_pos_err: params.max_pos_err,
+            updated_since: params.updated_since,
+        })
+    }
+
+    fn matches(&self, node: &Node) -> bool {
+        let name = &node.info.name;
+
+        self.name_prefix.as_ref().is_none_or(|p| name.starts_with(p.as_str()))
+            && self.name_regex.as_ref().is_none_or(|r| r.is_match(name))
+            && self.network.is_none_or(|n| n.contains(&node.info.ip))
+            && self.max_pos_err.is_none_or(|e| node.info.location.pos_err <= e)
+            && self.updated_since.is_none_or(|t| node.last_updated_sec >= t)
+    }
+}
+
+fn compare(a: &Node, b: &Node, sort: Option<SortOrder>, local: &NodeCoordinates) -> Ordering {
+    let distance = |n: &Node| vivaldi::node_distance(local, &n.info.location);
+    let by_float = |x: f32, y: f32| x.partial_cmp(&y).unwrap_or(Ordering::Equal);
+
+    let order = match sort {
+        Some(SortOrder::Name) => a.info.name.cmp(&b.info.name),
+        // nodes without coordinates have no distance, they go last
+        Some(SortOrder::Distance) => positioned(b)
+            .cmp(&positioned(a))
+            .then_with(|| by_float(distance(a), distance(b))),
+        Some(SortOrder::Freshness) => b.last_updated_sec.cmp(&a.last_updated_sec),
+        Some(SortOrder::Error) => by_float(a.info.location.pos_err, b.info.location.pos_err),
+        None => Ordering::Equal,
+    };
+
+    // address makes order stable
+    order.then_with(|| a.addr().cmp(&b.addr()))
+}
+
+/// Select matching nodes of the current snapshot in requested order
+fn select(store: &dyn StorageView, mut params: FullMapParams) -> Result<Selection, Failure> {
+    let filter = Filter::new(&mut params)?;
+    let snapshot = store.snapshot();
+    let local = store.get_location();
+
+    let mut indices: Vec<usize> = snapshot
+        .nodes
+        .iter()
+        .enumerate()
+        .filter(|&(_, n)| filter.matches(n))
+        .map(|(i, _)| i)
+        .collect();
+    indices.sort_by(|&a, &b| compare(&snapshot.nodes[a], &snapshot.nodes[b], params.sort, &local));
+
+    Ok(Selection::new(snapshot, indices))
+}
+
+/// Selections kept by connection for paginated reads, oldest first
+#[derive(Debug)]
+pub struct Cursors {
+    kept: VecDeque<(String, Instant, Arc<Selection>)>,
+    // no selections are kept if zero
+    capacity: usize,
+}
+
+impl Default for Cursors {
+    fn default() -> Self {
+        Cursors {
+            kept: VecDeque::new(),
+            capacity: MAX_KEPT_SELECTIONS,
+        }
+    }
+}
+
+impl Cursors {
+    /// Cursors of connection which can't continue reading, limited
+    /// responses don't refer to the rest of selection
+    pub fn disabled() -> Self {
+        Cursors {
+            kept: VecDeque::new(),
+            capacity: 0,
+        }
+    }
+
+    fn expire(&mut self) {
+        let ttl = Duration::from_secs(SELECTION_TTL_SEC);
+        while self
+            .kept
+            .front()
+            .is_some_and(|&(_, kept_at, _)| kept_at.elapsed() > ttl)
+        {
+            self.kept.pop_front();
+        }
+    }
+
+    /// Keep selection for a while, so that it could be read in parts.
+    /// Return unguessable token to retrieve it later, None if disabled.
+    fn keep(&mut self, selection: Arc<Selection>) -> Option<String> {
+        if self.capacity == 0 {
+            return None;
+        }
+
+        self.expire();
+        if self.kept.len() >= self.capacity {
+            self.kept.pop_front();
+        }
+
+        let mut rng = rand::thread_rng();
+        let token = format!("{:016x}{:016x}", rng.gen::<u64>(), rng.gen::<u64>());
+        self.kept.push_back((token.clone(), Instant::now(), selection));
+        Some(token)
+    }
+
+    /// Return previously kept selection, None if it's unknown or expired
+    fn get(&mut self, token: &str) -> Option<Arc<Selection>> {
+        self.expire();
+        self.kept
+            .iter()
+            .find(|(kept, _, _)| kept == token)
+            .map(|(_, _, selection)| selection.clone())
+    }
+}
+
+fn parse_cursor(cursor: &str) -> Option<(&str, usize)> {
+    let mut parts = cursor.splitn(2, ':');
+    let token = parts.next()?;
+    let offset = parts.next()?.parse().ok()?;
+    Some((token, offset))
+}
+
+/// Process `get_full_map` request
+pub fn full_map(params: FullMapParams, store: &dyn StorageView, cursors: &mut Cursors) -> Response {
+    let limit = params.limit;
+
+    let (token, selection, offset) = match params.cursor {
+        Some(ref cursor) => {
+            let kept = parse_cursor(cursor).and_then(|(token, offset)| {
+                cursors
+                    .get(token)
+                    .map(|s| (Some(token.to_string()), s, offset))
+            });
+            match kept {
+                Some(kept) => kept,
+                None => {
+                    let failure = Failure::new(REASON_BAD_CURSOR).with_details(ErrorDetails {
+                        value: Some(cursor.clone()),
+                        ..ErrorDetails::default()
+                    });
+                    return Response::Failure(failure);
+                }
+            }
+        }
+        None => match select(store, params) {
+            Ok(selection) => (None, Arc::new(selection), 0),
+            Err(failure) => return Response::Failure(failure),
+        },
+    };
+
+    let limit = limit.unwrap_or(usize::MAX).max(1);
+    let nodes = selection
+        .iter_from(offset)
+        .take(limit)
+        .map(|n| n.info.clone())
+        .collect();
+
+    let next = offset.saturating_add(limit);
+    let next_cursor = if next < selection.len() {
+        token
+            .or_else(|| cursors.keep(selection.clone()))
+            .map(|token| format!("{}:{}", token, next))
+    } else {
+        None
+    };
+
+    Response::FullMap { nodes, next_cursor }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agent::{NodeInfo, NodeList};
+    use storage::Storage;
+
+    fn store() -> Storage {
+        let store = Storage::new();
+        for (i, name) in ["web-1", "db-1", "web-2", "web-3", "db-2"].iter().enumerate() {
+            let mut node = NodeInfo::new(format!("10.0.{}.1", i).parse().unwrap(), 5001, name.to_string());
+            node.location.x1 = (5 - i) as f32;
+            node.location.pos_err = 0.1 * i as f32;
+            node.location.iteration = 1;
+            store.add_node(node);
+        }
+        store
+    }
+
+    fn names(nodes: &NodeList) -> Vec<&str> {
+        nodes.iter().map(|n| n.name.as_str()).collect()
+    }
+
+    fn full_map_page(
+        store: &Storage,
+        params: FullMapParams,
+        cursors: &mut Cursors,
+    ) -> (NodeList, Option<String>) {
+        match full_map(params, store, cursors) {
+            Response::FullMap { nodes, next_cursor } => (nodes, next_cursor),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn network_matching() {
+        let net = Network::parse("10.1.0.0/16").unwrap();
+        assert!(net.contains(&"10.1.200.3".parse().unwrap()));
+        assert!(!net.contains(&"10.2.0.1".parse().unwrap()));
+        assert!(!net.contains(&"::1".parse().unwrap()));
+
+        assert!(Network::parse("0.0.0.0/0").unwrap().contains(&"8.8.8.8".parse().unwrap()));
+        assert!(Network::parse("fd00::/8").unwrap().contains(&"fd12::1".parse().unwrap()));
+        assert!(Network::parse("10.0.0.1").unwrap().contains(&"10.0.0.1".parse().unwrap()));
+        assert_eq!(Network::parse("10.0.0.0/33"), None);
+        assert_eq!(Network::parse("10.0.0/8"), None);
+    }
+
+    #[test]
+    fn filtered_and_sorted() {
+        let store = store();
+
+        let params = FullMapParams {
+            name_regex: Some("^web-[12]$".to_string()),
+            sort: Some(SortOrder::Name),
+            ..FullMapParams::default()
+        };
+        assert_eq!(names(&full_map_page(&store, params, &mut Cursors::default()).0), vec!["web-1", "web-2"]);
+
+        // node at the origin without computed coordinates goes last
+        store.add_node(NodeInfo::new("10.0.9.1".parse().unwrap(), 5001, "db-0".to_string()));
+        let params = FullMapParams {
+            name_prefix: Some("db".to_string()),
+            sort: Some(SortOrder::Distance),
+            ..FullMapParams::default()
+        };
+        assert_eq!(names(&full_map_page(&store, params, &mut Cursors::default()).0), vec!["db-2", "db-1", "db-0"]);
+
+        let params = FullMapParams {
+            network: Some("10.0.2.0/23".to_string()),
+            max_pos_err: Some(0.25),
+            ..FullMapParams::default()
+        };
+        assert_eq!(names(&full_map_page(&store, params, &mut Cursors::default()).0), vec!["web-2"]);
+
+        let params = FullMapParams {
+            name_regex: Some("(".to_string()),
+            ..FullMapParams::default()
+        };
+        match full_map(params, &store, &mut Cursors::default()) {
+            Response::Failure(failure) => assert_eq!(failure.reason, REASON_BAD_FILTER),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn paginated() {
+        let store = store();
+        let params = FullMapParams {
+            sort: Some(SortOrder::Error),
+            limit: Some(2),
+            ..FullMapParams::default()
+        };
+
+        let mut cursors = Cursors::default();
+        let (first, cursor) = full_map_page(&store, params, &mut cursors);
+        assert_eq!(names(&first), vec!["web-1", "db-1"]);
+
+        // changes of the table are not visible on the next pages
+        store.remove_node("10.0.2.1:5001".parse().unwrap());
+
+        // cursor is not valid for other connections
+        let foreign = FullMapParams {
+            cursor: cursor.clone(),
+            ..FullMapParams::default()
+        };
+        match full_map(foreign, &store, &mut Cursors::default()) {
+            Response::Failure(failure) => assert_eq!(failure.reason, REASON_BAD_CURSOR),
+            other => panic!("unexpected response: {:?}", other),
+        }
+
+        let mut pages = vec![];
+        let mut cursor = cursor;
+        while let Some(c) = cursor {
+            let params = FullMapParams {
+                cursor: Some(c),
+                limit: Some(2),
+                ..FullMapParams::default()
+            };
+            let (nodes, next) = full_map_page(&store, params, &mut cursors);
+            pages.push(names(&nodes).iter().map(|n| n.to_string()).collect::<Vec<_>>());
+            cursor = next;
+        }
+        assert_eq!(pages, vec![vec!["web-2", "web-3"], vec!["db-2"]]);
+
+        let params = FullMapParams {
+            cursor: Some("0:2".to_string()),
+            ..FullMapParams::default()
+        };
+        match full_map(params, &store, &mut cursors) {
+            Response::Failure(failure) => assert_eq!(failure.reason, REASON_BAD_CURSOR),
+            other => panic!("unexpected response: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn kept_selections_bounded() {
+        let store = store();
+        let mut cursors = Cursors::default();
+        let tokens: Vec<String> = (0..MAX_KEPT_SELECTIONS + 1)
+            .filter_map(|_| cursors.keep(Arc::new(select(&store, FullMapParams::default()).unwrap())))
+            .collect();
+
+        assert_eq!(cursors.kept.len(), MAX_KEPT_SELECTIONS);
+        assert!(cursors.get(&tokens[0]).is_none());
+        assert!(cursors.get(&tokens[MAX_KEPT_SELECTIONS]).is_some());
+        assert_ne!(tokens[1], tokens[2]);
+    }
+
+    #[test]
+    fn disabled_cursors() {
+        let store = store();
+        let params = FullMapParams {
+            limit: Some(2),
+            ..FullMapParams::default()
+        };
+
+        let (nodes, cursor) = full_map_page(&store, params, &mut Cursors::disabled());
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(cursor, None);
+    }
+}
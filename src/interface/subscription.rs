@@ -0,0 +1,377 @@
+/// Subscription to changes of coordinates and overlay membership.
+///
+/// Subscription periodically compares storage snapshot with the view
+/// already reported to the client and queues resulting events.
+/// Queue is bounded: if client doesn't keep up, changes that don't fit
+/// stay unreported and are sent later with their latest state, client
+/// is notified about the number of postponed changes.
+///
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use tokio::prelude::*;
+use tokio::timer::Interval;
+use tokio::io;
+
+use agent::NodeCoordinates;
+use storage::{now_sec, Node, SharedStorage};
+use super::proto::{Event, EventKind, NodeInfoFull, SubscribeParams};
+use super::proto::Failure;
+
+const INTERVAL_MS_DEFAULT: u64 = 1000;
+const INTERVAL_MS_MIN: u64 = 100;
+const MIN_CHANGE_DEFAULT: f32 = 0.001;
+const EXPIRE_AFTER_SEC_DEFAULT: u64 = 600;
+const BUFFER_SIZE_DEFAULT: usize = 1024;
+const BUFFER_SIZE_MAX: usize = 65536;
+
+/// Node as it was reported to the client
+struct ReportedNode {
+    node: Node,
+    expired: bool,
+}
+
+pub struct Subscription {
+    events: Option<Vec<EventKind>>,
+    nodes: Option<Vec<SocketAddr>>,
+    min_change: f32,
+    expire_after: u64,
+    interval: Interval,
+    interval_ms: u64,
+
+    reported_location: Option<NodeCoordinates>,
+    reported_nodes: HashMap<SocketAddr, ReportedNode>,
+
+    queue: VecDeque<Event>,
+    capacity: usize,
+    dropped: usize,
+    // subjects of changes not queued yet, None stands for local node
+    postponed: HashSet<Option<SocketAddr>>,
+}
+
+impl Subscription {
+    pub fn new(params: SubscribeParams) -> Result<Self, Failure> {
+        let nodes = match params.nodes {
+            Some(nodes) => Some(
+                nodes
+                    .iter()
+                    .map(|a| a.parse().map_err(|_| Failure::bad_node_addr(a)))
+                    .collect::<Result<Vec<SocketAddr>, _>>()?,
+            ),
+            None => None,
+        };
+
+        let interval_ms = params
+            .interval_ms
+            .unwrap_or(INTERVAL_MS_DEFAULT)
+            .max(INTERVAL_MS_MIN);
+        let capacity = params
+            .buffer
+            .unwrap_or(BUFFER_SIZE_DEFAULT)
+            .clamp(1, BUFFER_SIZE_MAX);
+
+        Ok(Subscription {
+            events: params.events,
+            nodes,
+            min_change: params.min_change.unwrap_or(MIN_CHANGE_DEFAULT),
+            expire_after: params.expire_after.unwrap_or(EXPIRE_AFTER_SEC_DEFAULT),
+            interval: Interval::new(Instant::now(), Duration::from_millis(interval_ms)),
+            interval_ms,
+            reported_location: None,
+            reported_nodes: HashMap::new(),
+            queue: VecDeque::with_capacity(capacity),
+            capacity,
+            dropped: 0,
+            postponed: HashSet::new(),
+        })
+    }
+
+    pub fn interval_ms(&self) -> u64 {
+        self.interval_ms
+    }
+
+    /// Check for changes on every tick of the timer.
+    /// Always returns NotReady, registering wakeup at the next tick.
+    pub fn poll_changes(&mut self, store: &SharedStorage) -> Poll<(), io::Error> {
+        while let Async::Ready(Some(_)) = self
+            .interval
+            .poll()
+            .map_err(|e| io::Error::other(e.to_string()))?
+        {
+            self.collect_changes(store);
+        }
+
+        Ok(Async::NotReady)
+    }
+
+    /// Take next event to be sent to client
+    pub fn pop_front(&mut self) -> Option<Event> {
+        self.queue.pop_front()
+    }
+
+    fn wanted(&self, event: &Event) -> bool {
+        match (event.kind(), self.events.as_ref()) {
+            (Some(kind), Some(events)) => events.contains(&kind),
+            _ => true,
+        }
+    }
+
+    /// Queue event about change of the subject, unless client isn't interested in it.
+    /// Return false if queue is full, so that change is reported later.
+    fn push_change(&mut self, subject: Option<SocketAddr>, event: Event) -> bool {
+        if !self.wanted(&event) {
+            return true;
+        }
+
+        if self.queue.len() < self.capacity {
+            self.queue.push_back(event);
+            self.postponed.remove(&subject);
+            true
+        } else {
+            // change is counted once however long it waits
+            if self.postponed.insert(subject) {
+                self.dropped += 1;
+            }
+            false
+        }
+    }
+
+    fn collect_changes(&mut self, store: &SharedStorage) {
+        // report lost events as soon as there is free space for it
+        if self.dropped > 0 && self.queue.len() < self.capacity {
+            self.queue.push_back(Event::EventsDropped { count: self.dropped });
+            self.dropped = 0;
+        }
+
+        // local node
+        let location = store.get_location();
+        let moved = self.reported_location
+            .as_ref()
+            .is_none_or(|r| changed(r, &location, self.min_change).is_some());
+        if moved {
+            let event = Event::LocationChanged {
+                loc: location.clone(),
+            };
+            if self.push_change(None, event) {
+                self.reported_location = Some(location);
+            }
+        }
+
+        // other nodes
+        let snapshot = store.snapshot();
+        let expired_before = now_sec().saturating_sub(self.expire_after);
+        let mut present = HashSet::new();
+
+        for node in &snapshot.nodes {
+            let addr = node.addr();
+            if !self.nodes.as_ref().is_none_or(|n| n.contains(&addr)) {
+                continue;
+            }
+            present.insert(addr);
+
+            let expired = node.last_updated_sec < expired_before;
+            let event = match self.reported_nodes.get(&addr) {
+                None if expired => None,
+                None => Some(Event::NodeJoined {
+                    node: NodeInfoFull::from(node.clone()),
+                }),
+                Some(reported) if reported.expired && !expired => Some(Event::NodeJoined {
+                    node: NodeInfoFull::from(node.clone()),
+                }),
+                Some(reported) if !reported.expired && expired => Some(Event::NodeExpired {
+                    node: NodeInfoFull::from(node.clone()),
+                }),
+                Some(reported) if !expired => {
+                    match changed(&reported.node.info.location, &node.info.location, self.min_change) {
+                        Some(shift) => Some(Event::NodeUpdated {
+                            node: NodeInfoFull::from(node.clone()),
+                            shift,
+                        }),
+                        None => continue,
+                    }
+                }
+                Some(_) => continue,
+            };
+
+            if let Some(event) = event {
+                // reported state is kept, so that change is found again
+                if !self.push_change(Some(addr), event) {
+                    continue;
+                }
+            }
+
+            self.reported_nodes.insert(
+                addr,
+                ReportedNode {
+                    node: node.clone(),
+                    expired,
+                },
+            );
+        }
+
+        // nodes removed from the table expire as well
+        let removed: Vec<SocketAddr> = self
+            .reported_nodes
+            .keys()
+            .filter(|addr| !present.contains(addr))
+            .cloned()
+            .collect();
+        for addr in removed {
+            let event = match self.reported_nodes[&addr] {
+                ReportedNode { expired: true, .. } => None,
+                ReportedNode { ref node, .. } => Some(Event::NodeExpired {
+                    node: NodeInfoFull::from(node.clone()),
+                }),
+            };
+
+            if let Some(event) = event {
+                if !self.push_change(Some(addr), event) {
+                    continue;
+                }
+            }
+            self.reported_nodes.remove(&addr);
+        }
+
+        // nodes removed before being reported have nothing to wait for
+        let reported = &self.reported_nodes;
+        self.postponed
+            .retain(|subject| subject.is_none_or(|addr| present.contains(&addr) || reported.contains_key(&addr)));
+    }
+}
+
+/// Return distance between reported and current position of the node,
+/// if position was recomputed since then and moved far enough.
+fn changed(reported: &NodeCoordinates, current: &NodeCoordinates, min_change: f32) -> Option<f32> {
+    if reported.iteration == current.iteration {
+        return None;
+    }
+
+    let planar = ((reported.x1 - current.x1).powi(2) + (reported.x2 - current.x2).powi(2)).sqrt();
+    let shift = planar + (reported.height - current.height).abs();
+
+    if shift >= min_change {
+        Some(shift)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agent::NodeInfo;
+    use storage::{Node, Storage};
+    use std::sync::Arc;
+
+    fn node(port: u16, x1: f32, iteration: u64) -> NodeInfo {
+        let mut info = NodeInfo::new("10.0.0.1".parse().unwrap(), port, String::new());
+        info.location.x1 = x1;
+        info.location.iteration = iteration;
+        info
+    }
+
+    fn kinds(subscription: &mut Subscription) -> Vec<Option<EventKind>> {
+        let mut kinds = Vec::new();
+        while let Some(event) = subscription.pop_front() {
+            kinds.push(event.kind());
+        }
+        kinds
+    }
+
+    #[test]
+    fn membership_events() {
+        let store = Arc::new(Storage::new());
+        let mut subscription = Subscription::new(SubscribeParams {
+            events: Some(vec![EventKind::NodeJoined, EventKind::NodeUpdated]),
+            min_change: Some(0.5),
+            ..Default::default()
+        }).unwrap();
+
+        store.add_node(node(1, 0.0, 1));
+        subscription.collect_changes(&store);
+        assert_eq!(kinds(&mut subscription), vec![Some(EventKind::NodeJoined)]);
+
+        // shift below threshold is not reported
+        store.add_node(node(1, 0.1, 2));
+        subscription.collect_changes(&store);
+        assert_eq!(kinds(&mut subscription), vec![]);
+
+        store.add_node(node(1, 1.0, 3));
+        subscription.collect_changes(&store);
+        assert_eq!(kinds(&mut subscription), vec![Some(EventKind::NodeUpdated)]);
+    }
+
+    #[test]
+    fn expired_node() {
+        let store = Arc::new(Storage::new());
+        let mut subscription = Subscription::new(SubscribeParams {
+            expire_after: Some(100),
+            ..Default::default()
+        }).unwrap();
+
+        store.add_node(node(1, 0.0, 1));
+        subscription.collect_changes(&store);
+        assert_eq!(
+            kinds(&mut subscription),
+            vec![Some(EventKind::LocationChanged), Some(EventKind::NodeJoined)]
+        );
+
+        store.insert_node(Node {
+            info: node(1, 0.0, 1),
+            last_updated_sec: now_sec() - 1000,
+        });
+        subscription.collect_changes(&store);
+        assert_eq!(kinds(&mut subscription), vec![Some(EventKind::NodeExpired)]);
+    }
+
+    #[test]
+    fn slow_client_notified() {
+        let store = Arc::new(Storage::new());
+        let mut subscription = Subscription::new(SubscribeParams {
+            events: Some(vec![EventKind::NodeJoined]),
+            buffer: Some(2),
+            ..Default::default()
+        }).unwrap();
+
+        (1..6).for_each(|i| store.add_node(node(i, 0.0, 1)));
+        subscription.collect_changes(&store);
+        assert_eq!(subscription.queue.len(), 2);
+        assert_eq!(subscription.dropped, 3);
+
+        subscription.pop_front();
+        subscription.collect_changes(&store);
+        assert_eq!(
+            kinds(&mut subscription),
+            vec![Some(EventKind::NodeJoined), None]
+        );
+        assert_eq!(subscription.dropped, 0);
+
+        // postponed changes are delivered once client catches up
+        subscription.collect_changes(&store);
+        assert_eq!(kinds(&mut subscription).len(), 2);
+        subscription.collect_changes(&store);
+        assert_eq!(kinds(&mut subscription), vec![Some(EventKind::NodeJoined)]);
+        subscription.collect_changes(&store);
+        assert_eq!(kinds(&mut subscription), vec![]);
+    }
+
+    #[test]
+    fn removed_node_expired() {
+        let store = Arc::new(Storage::new());
+        let mut subscription = Subscription::new(SubscribeParams {
+            events: Some(vec![EventKind::NodeJoined, EventKind::NodeExpired]),
+            ..Default::default()
+        }).unwrap();
+
+        store.add_node(node(1, 0.0, 1));
+        subscription.collect_changes(&store);
+        assert_eq!(kinds(&mut subscription), vec![Some(EventKind::NodeJoined)]);
+
+        store.remove_node("10.0.0.1:1".parse().unwrap());
+        subscription.collect_changes(&store);
+        assert_eq!(kinds(&mut subscription), vec![Some(EventKind::NodeExpired)]);
+        subscription.collect_changes(&store);
+        assert_eq!(kinds(&mut subscription), vec![]);
+    }
+}
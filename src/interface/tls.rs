@@ -0,0 +1,73 @@
+/// TLS transport for interface connections
+///
+/// Loads certificate and key for the acceptor, which wraps accepted
+/// TCP streams into TLS ones, so that clients and HTTP connections
+/// work on top of them as usual.
+///
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use rustls::{self, NoClientAuth, ServerConfig};
+use rustls::internal::pemfile;
+use tokio_rustls::TlsAcceptor;
+
+use error::{Error, Result};
+
+/// Paths of PEM-encoded certificate chain and private key
+#[derive(Debug, Clone, PartialEq)]
+pub struct TlsConfig {
+    pub cert_path: PathBuf,
+    pub key_path: PathBuf,
+}
+
+fn invalid_data(msg: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn load_certs(path: &Path) -> io::Result<Vec<rustls::Certificate>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let certs = pemfile::certs(&mut reader)
+        .map_err(|_| invalid_data("cannot parse certificate file".to_string()))?;
+
+    if certs.is_empty() {
+        return Err(invalid_data("no certificates found".to_string()));
+    }
+    Ok(certs)
+}
+
+fn load_key(path: &Path) -> io::Result<rustls::PrivateKey> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut keys = pemfile::pkcs8_private_keys(&mut reader)
+        .map_err(|_| invalid_data("cannot parse key file".to_string()))?;
+
+    if keys.is_empty() {
+        let mut reader = BufReader::new(File::open(path)?);
+        keys = pemfile::rsa_private_keys(&mut reader)
+            .map_err(|_| invalid_data("cannot parse key file".to_string()))?;
+    }
+
+    keys.pop()
+        .ok_or_else(|| invalid_data("no private key found".to_string()))
+}
+
+/// Load certificate and key, creating acceptor of TLS connections
+pub fn load_acceptor(config: &TlsConfig) -> Result<TlsAcceptor> {
+    let interface_error = |what, path: &Path| {
+        let path = path.to_path_buf();
+        move |source| Error::Interface { what, path, source }
+    };
+    let certs = load_certs(&config.cert_path)
+        .map_err(interface_error("TLS certificate", &config.cert_path))?;
+    let key = load_key(&config.key_path)
+        .map_err(interface_error("TLS key", &config.key_path))?;
+
+    let mut server_config = ServerConfig::new(NoClientAuth::new());
+    server_config
+        .set_single_cert(certs, key)
+        .map_err(|e| invalid_data(format!("bad certificate or key: {}", e)))
+        .map_err(interface_error("TLS certificate", &config.cert_path))?;
+
+    Ok(TlsAcceptor::from(Arc::new(server_config)))
+}
@@ -0,0 +1,197 @@
+/// Unix domain socket listener
+///
+/// Local consumers could reach interface without TCP,
+/// access is controlled with permissions of the socket file.
+///
+use std::ffi::OsString;
+use std::fs::{self, DirBuilder};
+use std::io;
+use std::os::unix::fs::{chown, DirBuilderExt, FileTypeExt, PermissionsExt};
+use std::os::unix::net::UnixStream;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use rand::{self, Rng};
+use tokio::net::UnixListener;
+
+use error::{self, Error};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct UnixSocketConfig {
+    pub path: PathBuf,
+    // permission bits of the socket file, e.g. 0o660
+    pub mode: Option<u32>,
+    pub owner: Option<u32>,
+    pub group: Option<u32>,
+}
+
+/// Parse octal permission bits like `660` or `0o660`
+pub fn parse_mode(mode: &str) -> Option<u32> {
+    let digits = mode.trim_start_matches("0o");
+    match u32::from_str_radix(digits, 8) {
+        Ok(mode) if mode <= 0o7777 => Some(mode),
+        _ => None,
+    }
+}
+
+/// Parse numeric owner in `uid`, `uid:gid` or `:gid` form
+pub fn parse_owner(owner: &str) -> Option<(Option<u32>, Option<u32>)> {
+    let mut parts = owner.splitn(2, ':');
+    let parse = |id: Option<&str>| -> Result<Option<u32>, ()> {
+        match id {
+            None | Some("") => Ok(None),
+            Some(id) => id.parse().map(Some).map_err(|_| ()),
+        }
+    };
+
+    let uid = parse(parts.next()).ok()?;
+    let gid = parse(parts.next()).ok()?;
+    if uid.is_none() && gid.is_none() {
+        return None;
+    }
+
+    Some((uid, gid))
+}
+
+/// Bind listener, replacing stale socket file left by previous run.
+/// Socket still accepting connections is left to its owner.
+///
+/// Socket is created in a directory accessible only to the agent and
+/// moved to its path after permissions and owner are set, so that it's
+/// never reachable with default ones.
+pub fn bind(config: &UnixSocketConfig) -> error::Result<UnixListener> {
+    let interface_error = |source| Error::Interface {
+        what: "unix socket",
+        path: config.path.clone(),
+        source,
+    };
+
+    if let Ok(meta) = fs::symlink_metadata(&config.path) {
+        if !meta.file_type().is_socket() {
+            return Err(interface_error(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "exists and is not a socket",
+            )));
+        }
+        check_stale(&config.path).map_err(|source| Error::Bind {
+            addr: config.path.display().to_string(),
+            source,
+        })?;
+    }
+
+    let private_dir = private_dir_path(&config.path).map_err(interface_error)?;
+    DirBuilder::new()
+        .mode(0o700)
+        .create(&private_dir)
+        .map_err(interface_error)?;
+
+    let result = bind_private(config, &private_dir);
+    let _ = fs::remove_dir_all(&private_dir);
+    result
+}
+
+/// Fail unless nobody listens on the existing socket
+fn check_stale(path: &Path) -> io::Result<()> {
+    match UnixStream::connect(path) {
+        Ok(_) => Err(io::Error::new(io::ErrorKind::AddrInUse, "socket is in use")),
+        Err(ref e) if e.kind() == io::ErrorKind::ConnectionRefused => Ok(()),
+        // removed meanwhile
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+// sibling of the socket, so that it could be renamed within file system,
+// unique name isn't blocked by directory left after crash
+fn private_dir_path(path: &Path) -> io::Result<PathBuf> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "not a file path"))?;
+    let mut dir_name = OsString::from(".");
+    dir_name.push(name);
+    dir_name.push(format!(".{}.{:016x}.tmp", process::id(), rand::thread_rng().gen::<u64>()));
+    Ok(path.with_file_name(dir_name))
+}
+
+fn bind_private(config: &UnixSocketConfig, private_dir: &Path) -> error::Result<UnixListener> {
+    let interface_error = |source| Error::Interface {
+        what: "unix socket",
+        path: config.path.clone(),
+        source,
+    };
+
+    let tmp_path = private_dir.join("s");
+    let listener = UnixListener::bind(&tmp_path).map_err(|source| Error::Bind {
+        addr: config.path.display().to_string(),
+        source,
+    })?;
+
+    if let Some(mode) = config.mode {
+        fs::set_permissions(&tmp_path, fs::Permissions::from_mode(mode)).map_err(interface_error)?;
+    }
+    if config.owner.is_some() || config.group.is_some() {
+        chown(&tmp_path, config.owner, config.group).map_err(interface_error)?;
+    }
+
+    // replaces stale socket file checked before
+    fs::rename(&tmp_path, &config.path).map_err(interface_error)?;
+    Ok(listener)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mode_parsing() {
+        assert_eq!(parse_mode("660"), Some(0o660));
+        assert_eq!(parse_mode("0o600"), Some(0o600));
+        assert_eq!(parse_mode("0660"), Some(0o660));
+        assert_eq!(parse_mode("888"), None);
+        assert_eq!(parse_mode("17777"), None);
+    }
+
+    #[test]
+    fn owner_parsing() {
+        assert_eq!(parse_owner("1000"), Some((Some(1000), None)));
+        assert_eq!(parse_owner("1000:50"), Some((Some(1000), Some(50))));
+        assert_eq!(parse_owner(":50"), Some((None, Some(50))));
+        assert_eq!(parse_owner("root"), None);
+        assert_eq!(parse_owner(":"), None);
+    }
+
+    #[test]
+    fn socket_created_with_mode() {
+        let path = ::std::env::temp_dir().join(format!("netloc-test-{}.sock", ::std::process::id()));
+        let config = UnixSocketConfig {
+            path: path.clone(),
+            mode: Some(0o600),
+            owner: None,
+            group: None,
+        };
+
+        let listener = bind(&config).unwrap();
+        let meta = fs::symlink_metadata(&path).unwrap();
+        assert!(meta.file_type().is_socket());
+        assert_eq!(meta.permissions().mode() & 0o7777, 0o600);
+
+        // private directory is removed
+        let prefix = format!(".{}.", path.file_name().unwrap().to_str().unwrap());
+        let leftovers = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .filter(|entry| entry.as_ref().unwrap().file_name().to_str().unwrap().starts_with(&prefix))
+            .count();
+        assert_eq!(leftovers, 0);
+
+        // socket in use is not replaced
+        match bind(&config) {
+            Err(Error::Bind { source, .. }) => assert_eq!(source.kind(), io::ErrorKind::AddrInUse),
+            other => panic!("unexpected result: {:?}", other.map(|_| ())),
+        }
+
+        // stale socket is replaced
+        drop(listener);
+        let _listener = bind(&config).unwrap();
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -6,15 +6,29 @@ extern crate rand;
 #[macro_use]
 extern crate serde_derive;
 extern crate serde;
+#[macro_use]
 extern crate serde_json;
 
 #[macro_use]
 extern crate futures;
 extern crate tokio;
 extern crate tokio_io;
+extern crate tokio_signal;
 extern crate bytes;
+extern crate rustls;
+extern crate tokio_rustls;
+extern crate regex;
+extern crate toml;
 
 pub mod agent;
 pub mod interface;
 pub mod storage;
+pub mod spatial;
+pub mod persist;
+pub mod metrics;
+pub mod status;
 pub mod arg_validator;
+pub mod config;
+pub mod error;
+
+pub use error::{Error, Result};
@@ -0,0 +1,361 @@
+//! Runtime metrics of the agent.
+//!
+//! Counters are updated by the transmitter, receiver, storage and
+//! interface, and exported in Prometheus text format by `render`.
+//!
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::net::SocketAddr;
+use std::sync::{Mutex, PoisonError};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use storage::Storage;
+
+/// Upper bounds of RTT histogram buckets, in seconds
+const RTT_BUCKETS: [f64; 12] = [
+    0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5,
+];
+
+/// Limit number of peers tracked for loss statistics
+const MAX_TRACKED_PEERS: usize = 4096;
+
+fn as_secs_f64(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct PeerProbes {
+    sent: u64,
+    answered: u64,
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    // non-cumulative counts, last one is for +Inf
+    buckets: [AtomicU64; RTT_BUCKETS.len() + 1],
+    sum_ns: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, value: Duration) {
+        let secs = as_secs_f64(value);
+        let idx = RTT_BUCKETS
+            .iter()
+            .position(|&b| secs <= b)
+            .unwrap_or(RTT_BUCKETS.len());
+
+        self.buckets[idx].fetch_add(1, Ordering::Relaxed);
+        self.sum_ns.fetch_add(value.as_nanos() as u64, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Metrics {
+    probes_sent: AtomicU64,
+    requests_received: AtomicU64,
+    responses_received: AtomicU64,
+    decode_errors: AtomicU64,
+    rtt: Histogram,
+    peers: Mutex<HashMap<SocketAddr, PeerProbes>>,
+    interface_requests: Mutex<BTreeMap<&'static str, u64>>,
+    lock_wait_ns: AtomicU64,
+    lock_acquisitions: AtomicU64,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Probe request sent to peer
+    pub fn probe_sent(&self, peer: SocketAddr) {
+        self.probes_sent.fetch_add(1, Ordering::Relaxed);
+
+        let mut peers = self.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        if peers.len() < MAX_TRACKED_PEERS || peers.contains_key(&peer) {
+            peers.entry(peer).or_default().sent += 1;
+        }
+    }
+
+    /// Foreign probe request received
+    pub fn request_received(&self) {
+        self.requests_received.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Response for the local probe received
+    pub fn response_received(&self, peer: SocketAddr, rtt: Option<Duration>) {
+        self.responses_received.fetch_add(1, Ordering::Relaxed);
+
+        if let Some(rtt) = rtt {
+            self.rtt.observe(rtt);
+        }
+
+        if let Some(probes) = self.peers.lock().unwrap_or_else(PoisonError::into_inner).get_mut(&peer) {
+            probes.answered += 1;
+        }
+    }
+
+    /// Malformed or unexpected message received
+    pub fn decode_error(&self) {
+        self.decode_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Interface request processed
+    pub fn interface_request(&self, action: &'static str) {
+        *self.interface_requests
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(action)
+            .or_insert(0) += 1;
+    }
+
+    /// Time spent waiting for storage lock
+    pub fn lock_waited(&self, wait: Duration) {
+        self.lock_wait_ns.fetch_add(wait.as_nanos() as u64, Ordering::Relaxed);
+        self.lock_acquisitions.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn single<T: ::std::fmt::Display>(out: &mut String, name: &str, kind: &str, help: &str, value: T) {
+    header(out, name, kind, help);
+    let _ = writeln!(out, "{} {}", name, value);
+}
+
+/// Export metrics of the storage owner in Prometheus text format
+pub fn render(store: &Storage) -> String {
+    let m = store.metrics();
+    let location = store.get_location();
+    let mut out = String::new();
+
+    single(
+        &mut out,
+        "netloc_probes_sent_total",
+        "counter",
+        "Probe requests sent.",
+        m.probes_sent.load(Ordering::Relaxed),
+    );
+
+    header(
+        &mut out,
+        "netloc_probes_received_total",
+        "counter",
+        "Probe messages received.",
+    );
+    let _ = writeln!(
+        out,
+        "netloc_probes_received_total{{type=\"request\"}} {}",
+        m.requests_received.load(Ordering::Relaxed)
+    );
+    let _ = writeln!(
+        out,
+        "netloc_probes_received_total{{type=\"response\"}} {}",
+        m.responses_received.load(Ordering::Relaxed)
+    );
+
+    single(
+        &mut out,
+        "netloc_decode_errors_total",
+        "counter",
+        "Malformed or unexpected messages received.",
+        m.decode_errors.load(Ordering::Relaxed),
+    );
+
+    // histogram buckets are cumulative
+    header(
+        &mut out,
+        "netloc_rtt_seconds",
+        "histogram",
+        "Measured round-trip time of probes.",
+    );
+    let mut count = 0;
+    for (i, bucket) in m.rtt.buckets.iter().enumerate() {
+        count += bucket.load(Ordering::Relaxed);
+        match RTT_BUCKETS.get(i) {
+            Some(le) => {
+                let _ = writeln!(out, "netloc_rtt_seconds_bucket{{le=\"{}\"}} {}", le, count);
+            }
+            None => {
+                let _ = writeln!(out, "netloc_rtt_seconds_bucket{{le=\"+Inf\"}} {}", count);
+            }
+        }
+    }
+    let _ = writeln!(
+        out,
+        "netloc_rtt_seconds_sum {}",
+        m.rtt.sum_ns.load(Ordering::Relaxed) as f64 / 1_000_000_000.0
+    );
+    let _ = writeln!(out, "netloc_rtt_seconds_count {}", count);
+
+    single(
+        &mut out,
+        "netloc_position_error",
+        "gauge",
+        "Position error of the local node.",
+        location.pos_err,
+    );
+    single(
+        &mut out,
+        "netloc_iteration",
+        "gauge",
+        "Number of local coordinates updates.",
+        location.iteration,
+    );
+    single(
+        &mut out,
+        "netloc_known_nodes",
+        "gauge",
+        "Number of nodes in the local view.",
+        store.len(),
+    );
+
+    {
+        let peers = m.peers.lock().unwrap_or_else(PoisonError::into_inner);
+        let mut peers: Vec<(&SocketAddr, &PeerProbes)> = peers.iter().collect();
+        peers.sort_by_key(|&(addr, _)| *addr);
+
+        header(
+            &mut out,
+            "netloc_peer_probes_sent_total",
+            "counter",
+            "Probe requests sent to peer.",
+        );
+        for (addr, probes) in &peers {
+            let _ = writeln!(out, "netloc_peer_probes_sent_total{{peer=\"{}\"}} {}", addr, probes.sent);
+        }
+
+        header(
+            &mut out,
+            "netloc_peer_probes_answered_total",
+            "counter",
+            "Probe responses received from peer.",
+        );
+        for (addr, probes) in &peers {
+            let _ = writeln!(
+                out,
+                "netloc_peer_probes_answered_total{{peer=\"{}\"}} {}",
+                addr, probes.answered
+            );
+        }
+
+        header(
+            &mut out,
+            "netloc_peer_loss_ratio",
+            "gauge",
+            "Fraction of probes to peer left without response.",
+        );
+        for (addr, probes) in &peers {
+            let loss = if probes.sent > 0 {
+                1.0 - (probes.answered as f64 / probes.sent as f64).min(1.0)
+            } else {
+                0.0
+            };
+            let _ = writeln!(out, "netloc_peer_loss_ratio{{peer=\"{}\"}} {}", addr, loss);
+        }
+    }
+
+    header(
+        &mut out,
+        "netloc_interface_requests_total",
+        "counter",
+        "Interface requests by action.",
+    );
+    for (action, count) in m.interface_requests.lock().unwrap_or_else(PoisonError::into_inner).iter() {
+        let _ = writeln!(
+            out,
+            "netloc_interface_requests_total{{action=\"{}\"}} {}",
+            action, count
+        );
+    }
+
+    single(
+        &mut out,
+        "netloc_storage_lock_wait_seconds_total",
+        "counter",
+        "Time spent waiting for storage locks.",
+        m.lock_wait_ns.load(Ordering::Relaxed) as f64 / 1_000_000_000.0,
+    );
+    single(
+        &mut out,
+        "netloc_storage_lock_acquisitions_total",
+        "counter",
+        "Number of storage lock acquisitions.",
+        m.lock_acquisitions.load(Ordering::Relaxed),
+    );
+
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::str::FromStr;
+
+    fn line<'a>(text: &'a str, prefix: &str) -> &'a str {
+        text.lines()
+            .find(|l| l.starts_with(prefix))
+            .unwrap_or_else(|| panic!("no metric {}", prefix))
+    }
+
+    #[test]
+    fn peer_loss() {
+        let store = Storage::new();
+        let peer = SocketAddr::from_str("10.0.0.1:5001").unwrap();
+
+        for _ in 0..4 {
+            store.metrics().probe_sent(peer);
+        }
+        store.metrics().response_received(peer, Some(Duration::from_millis(3)));
+
+        let text = render(&store);
+        assert_eq!(line(&text, "netloc_probes_sent_total "), "netloc_probes_sent_total 4");
+        assert_eq!(
+            line(&text, "netloc_peer_loss_ratio{"),
+            "netloc_peer_loss_ratio{peer=\"10.0.0.1:5001\"} 0.75"
+        );
+    }
+
+    #[test]
+    fn rtt_histogram() {
+        let store = Storage::new();
+        let peer = SocketAddr::from_str("10.0.0.1:5001").unwrap();
+
+        store.metrics().response_received(peer, Some(Duration::from_millis(3)));
+        store.metrics().response_received(peer, Some(Duration::from_millis(30)));
+        store.metrics().response_received(peer, Some(Duration::from_secs(10)));
+
+        let text = render(&store);
+        assert_eq!(
+            line(&text, "netloc_rtt_seconds_bucket{le=\"0.005\"}"),
+            "netloc_rtt_seconds_bucket{le=\"0.005\"} 1"
+        );
+        assert_eq!(
+            line(&text, "netloc_rtt_seconds_bucket{le=\"0.05\"}"),
+            "netloc_rtt_seconds_bucket{le=\"0.05\"} 2"
+        );
+        assert_eq!(
+            line(&text, "netloc_rtt_seconds_bucket{le=\"+Inf\"}"),
+            "netloc_rtt_seconds_bucket{le=\"+Inf\"} 3"
+        );
+        assert_eq!(line(&text, "netloc_rtt_seconds_count"), "netloc_rtt_seconds_count 3");
+    }
+
+    #[test]
+    fn interface_requests() {
+        let store = Storage::new();
+        store.metrics().interface_request("get_location");
+        store.metrics().interface_request("get_location");
+
+        let text = render(&store);
+        assert_eq!(
+            line(&text, "netloc_interface_requests_total{"),
+            "netloc_interface_requests_total{action=\"get_location\"} 2"
+        );
+    }
+}
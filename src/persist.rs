@@ -0,0 +1,306 @@
+//! Persist local node state across restarts.
+//!
+//! Agent periodically dumps its coordinates and node table
+//! into the JSON-encoded state file and restores it at startup,
+//! so it wouldn't have to converge from scratch.
+//!
+
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde_json;
+use tokio::prelude::*;
+use tokio::timer::Interval;
+
+use agent::NodeCoordinates;
+use error::{Error, Result};
+use storage::{now_sec, Node, SharedStorage, Storage};
+
+/// Increment on every incompatible change of the state layout.
+pub const STATE_FORMAT_VERSION: u32 = 1;
+
+pub const STATE_SAVE_PERIOD_DEFAULT: u64 = 60;
+pub const STATE_MAX_AGE_DEFAULT: u64 = 3600;
+
+#[derive(Debug, Serialize, Deserialize)]
+struct StateSnapshot {
+    version: u32,
+    saved_at: u64,
+    location: NodeCoordinates,
+    nodes: Vec<Node>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct PersistConfig {
+    pub path: PathBuf,
+    pub save_period: Duration,
+    pub max_age: Duration,
+}
+
+fn storage_error(path: &Path) -> impl Fn(io::Error) -> Error + '_ {
+    move |source| Error::Storage {
+        path: path.to_path_buf(),
+        source,
+    }
+}
+
+/// Write storage state to file.
+///
+/// Snapshot goes to temporary file first and then renamed,
+/// so that crash during write never corrupts previous state.
+pub fn save(store: &Storage, path: &Path) -> Result<()> {
+    write_snapshot(store, path).map_err(storage_error(path))
+}
+
+fn write_snapshot(store: &Storage, path: &Path) -> io::Result<()> {
+    let snapshot = StateSnapshot {
+        version: STATE_FORMAT_VERSION,
+        saved_at: now_sec(),
+        location: store.get_location(),
+        nodes: store.snapshot().nodes.clone(),
+    };
+
+    let tmp_path = path.with_extension("tmp");
+    let mut writer = BufWriter::new(File::create(&tmp_path)?);
+    serde_json::to_writer(&mut writer, &snapshot)?;
+
+    // failed final write must not replace previous state
+    let file = writer.into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+
+    fs::rename(&tmp_path, path)
+}
+
+/// Restore state from file into storage.
+///
+/// Node records updated earlier than `max_age` ago are discarded.
+/// Local location is restored only if the whole snapshot is fresh enough.
+/// Return number of restored nodes.
+pub fn restore(store: &Storage, path: &Path, max_age: Duration) -> Result<usize> {
+    let snapshot = read_snapshot(path).map_err(storage_error(path))?;
+
+    let oldest_allowed = now_sec().saturating_sub(max_age.as_secs());
+
+    if snapshot.saved_at >= oldest_allowed {
+        store.set_location(snapshot.location);
+    }
+
+    let mut restored = 0;
+    for node in snapshot.nodes {
+        if node.last_updated_sec >= oldest_allowed {
+            store.insert_node(node);
+            restored += 1;
+        }
+    }
+
+    Ok(restored)
+}
+
+fn read_snapshot(path: &Path) -> io::Result<StateSnapshot> {
+    let reader = BufReader::new(File::open(path)?);
+    let snapshot: StateSnapshot = serde_json::from_reader(reader)?;
+
+    if snapshot.version != STATE_FORMAT_VERSION {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unsupported state format version: {}", snapshot.version),
+        ));
+    }
+
+    Ok(snapshot)
+}
+
+/// Try to restore storage, starting from scratch on any failure.
+pub fn restore_or_empty(config: &PersistConfig) -> Storage {
+    let store = Storage::new();
+
+    if !config.path.exists() {
+        return store;
+    }
+
+    match restore(&store, &config.path, config.max_age) {
+        Ok(restored) => info!(
+            "state restored from {}: {} nodes",
+            config.path.display(),
+            restored
+        ),
+        Err(e) => {
+            warn!("cannot restore {}, starting from scratch", e);
+            return Storage::new();
+        }
+    }
+
+    store
+}
+
+/// Thread writing state, so that serialization and disk I/O
+/// don't stall the runtime. Joined on drop, after the save in progress.
+struct SaverThread {
+    requests: Option<SyncSender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl SaverThread {
+    fn spawn(store: SharedStorage, path: PathBuf) -> io::Result<Self> {
+        let (tx, rx) = mpsc::sync_channel(1);
+        let thread = thread::Builder::new()
+            .name("netloc-saver".to_string())
+            .spawn(move || {
+                for () in rx {
+                    if let Err(e) = save(&store, &path) {
+                        error!("cannot save {}", e);
+                    }
+                }
+            })?;
+
+        Ok(SaverThread {
+            requests: Some(tx),
+            thread: Some(thread),
+        })
+    }
+
+    /// Request save, at most one is queued while the previous one runs
+    fn request(&self) {
+        if let Some(ref requests) = self.requests {
+            if let Err(TrySendError::Full(())) = requests.try_send(()) {
+                debug!("state save is still in progress, skipped");
+            }
+        }
+    }
+}
+
+impl Drop for SaverThread {
+    fn drop(&mut self) {
+        // final save must not race with the periodic one
+        self.requests.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Task saving state every period in the background thread, runs until cancelled
+pub fn saver(store: SharedStorage, config: PersistConfig) -> io::Result<impl Future<Item = (), Error = ()>> {
+    let saver = SaverThread::spawn(store, config.path)?;
+
+    Ok(Interval::new(Instant::now() + config.save_period, config.save_period)
+        .map_err(|e| error!("saver timer failure: {}", e))
+        .for_each(move |_| {
+            saver.request();
+            Ok(())
+        }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agent::NodeInfo;
+    use std::env;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    fn state_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("netloc-state-{}-{}.json", name, now_sec()))
+    }
+
+    #[test]
+    fn save_and_restore() {
+        let path = state_path("roundtrip");
+        let location = NodeCoordinates {
+            x1: 1.5,
+            x2: -2.5,
+            height: 0.1,
+            pos_err: 0.2,
+            iteration: 42,
+        };
+
+        let s = Storage::new();
+        s.set_location(location.clone());
+        s.add_node(NodeInfo::new(
+            IpAddr::from_str("1.2.3.4").unwrap(),
+            11001,
+            "test_node".to_string(),
+        ));
+        save(&s, &path).unwrap();
+
+        let restored = Storage::new();
+        assert_eq!(restore(&restored, &path, Duration::new(60, 0)).unwrap(), 1);
+        assert_eq!(restored.get_location(), location);
+        assert_eq!(restored.get_all_nodes(), s.get_all_nodes());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn outdated_nodes_discarded() {
+        let path = state_path("outdated");
+
+        let s = Storage::new();
+        s.insert_node(Node {
+            info: NodeInfo::new(IpAddr::from_str("1.2.3.4").unwrap(), 11001, String::new()),
+            last_updated_sec: now_sec() - 1000,
+        });
+        s.add_node(NodeInfo::new(
+            IpAddr::from_str("1.2.3.5").unwrap(),
+            11001,
+            String::new(),
+        ));
+        save(&s, &path).unwrap();
+
+        let restored = Storage::new();
+        assert_eq!(restore(&restored, &path, Duration::new(100, 0)).unwrap(), 1);
+        assert_eq!(restored.get_all_nodes()[0].ip, IpAddr::from_str("1.2.3.5").unwrap());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn unknown_version_rejected() {
+        let path = state_path("version");
+        fs::write(
+            &path,
+            r#"{"version":0,"saved_at":0,"location":{"x1":0.0,"x2":0.0,"height":0.0,"pos_err":1.0,"iteration":0},"nodes":[]}"#,
+        ).unwrap();
+
+        let restored = Storage::new();
+        assert!(restore(&restored, &path, Duration::new(100, 0)).is_err());
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn saved_in_background() {
+        use std::sync::Arc;
+        use tokio::runtime::current_thread::Runtime;
+        use tokio::timer::Delay;
+
+        let path = state_path("background");
+        let store = Arc::new(Storage::new());
+        store.set_location(NodeCoordinates {
+            iteration: 5,
+            ..NodeCoordinates::empty()
+        });
+        let config = PersistConfig {
+            path: path.clone(),
+            save_period: Duration::from_millis(10),
+            max_age: Duration::new(100, 0),
+        };
+
+        let mut runtime = Runtime::new().unwrap();
+        let deadline = Delay::new(Instant::now() + Duration::from_millis(100)).map_err(|_| ());
+        let task = saver(store, config).unwrap();
+        let _ = runtime.block_on(task.select(deadline).map_err(|_| ()));
+        // saver thread is joined when the task is dropped
+        drop(runtime);
+
+        let restored = Storage::new();
+        restore(&restored, &path, Duration::new(100, 0)).unwrap();
+        assert_eq!(restored.get_location().iteration, 5);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,363 @@
+//! Spatial index over node coordinates.
+//!
+//! Two-dimensional k-d tree adapted to the height-vector metric:
+//! `D(p, q) = |p.x - q.x| + p.h + q.h`, so each subtree keeps
+//! minimal height of its points to bound the distance from below.
+//!
+//! Tree is implicit: points are reordered in place, so that median
+//! of every range `[lo, hi)` is the root of the corresponding subtree.
+//!
+
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+
+use agent::{vivaldi, NodeCoordinates};
+
+const NUM_AXES: usize = 2;
+
+#[derive(Debug, Clone)]
+struct IndexedPoint {
+    coord: NodeCoordinates,
+    id: usize,
+}
+
+impl IndexedPoint {
+    fn axis(&self, axis: usize) -> f32 {
+        match axis {
+            0 => self.coord.x1,
+            _ => self.coord.x2,
+        }
+    }
+}
+
+/// Candidate found during search, ordered by distance
+#[derive(Debug, Clone, Copy, PartialEq)]
+struct Candidate {
+    distance: f32,
+    id: usize,
+}
+
+impl Eq for Candidate {}
+
+impl PartialOrd for Candidate {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Candidate {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.distance
+            .partial_cmp(&other.distance)
+            .unwrap_or(Ordering::Equal)
+            .then(self.id.cmp(&other.id))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct SpatialIndex {
+    points: Vec<IndexedPoint>,
+    // minimal height in subtree, indexed by position of subtree root
+    min_height: Vec<f32>,
+}
+
+fn median(lo: usize, hi: usize) -> usize {
+    lo + (hi - lo) / 2
+}
+
+fn coord_of(query: &NodeCoordinates, axis: usize) -> f32 {
+    match axis {
+        0 => query.x1,
+        _ => query.x2,
+    }
+}
+
+impl SpatialIndex {
+    /// Build index over coordinates, identifiers are positions in the source iterator.
+    pub fn build<'a, I>(coordinates: I) -> Self
+    where
+        I: IntoIterator<Item = &'a NodeCoordinates>,
+    {
+        let points: Vec<IndexedPoint> = coordinates
+            .into_iter()
+            .enumerate()
+            .map(|(id, coord)| IndexedPoint {
+                coord: coord.clone(),
+                id,
+            })
+            .collect();
+
+        let mut index = SpatialIndex {
+            min_height: vec![0.0; points.len()],
+            points,
+        };
+
+        let len = index.points.len();
+        index.build_range(0, len, 0);
+        index
+    }
+
+    pub fn len(&self) -> usize {
+        self.points.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.points.is_empty()
+    }
+
+    fn build_range(&mut self, lo: usize, hi: usize, depth: usize) -> f32 {
+        if lo >= hi {
+            return f32::INFINITY;
+        }
+
+        let axis = depth % NUM_AXES;
+        let mid = median(lo, hi);
+        self.points[lo..hi].select_nth_unstable_by(mid - lo, |a, b| {
+            a.axis(axis)
+                .partial_cmp(&b.axis(axis))
+                .unwrap_or(Ordering::Equal)
+        });
+
+        let min_left = self.build_range(lo, mid, depth + 1);
+        let min_right = self.build_range(mid + 1, hi, depth + 1);
+        let min_height = self.points[mid]
+            .coord
+            .height
+            .min(min_left)
+            .min(min_right);
+
+        self.min_height[mid] = min_height;
+        min_height
+    }
+
+    /// Lowest possible distance from query to any point of subtree `[lo, hi)`,
+    /// given planar distance to its region.
+    fn lower_bound(&self, query: &NodeCoordinates, lo: usize, hi: usize, planar: f32) -> f32 {
+        if lo >= hi {
+            return f32::INFINITY;
+        }
+
+        planar + query.height + self.min_height[median(lo, hi)]
+    }
+
+    /// Find `k` points nearest to query among those satisfying the filter.
+    /// Return pairs of distance and point identifier, closest first.
+    pub fn nearest<F>(&self, query: &NodeCoordinates, k: usize, filter: F) -> Vec<(f32, usize)>
+    where
+        F: Fn(usize) -> bool,
+    {
+        // k comes from clients, there can't be more results than points
+        let k = k.min(self.points.len());
+        let mut found = BinaryHeap::with_capacity(k + 1);
+        if k > 0 {
+            self.search_nearest(query, k, &filter, 0, self.points.len(), 0, &mut found);
+        }
+
+        found
+            .into_sorted_vec()
+            .into_iter()
+            .map(|c| (c.distance, c.id))
+            .collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn search_nearest<F>(
+        &self,
+        query: &NodeCoordinates,
+        k: usize,
+        filter: &F,
+        lo: usize,
+        hi: usize,
+        depth: usize,
+        found: &mut BinaryHeap<Candidate>,
+    ) where
+        F: Fn(usize) -> bool,
+    {
+        if lo >= hi {
+            return;
+        }
+
+        let worst = |found: &BinaryHeap<Candidate>| {
+            if found.len() < k {
+                f32::INFINITY
+            } else {
+                found.peek().map_or(f32::INFINITY, |c| c.distance)
+            }
+        };
+
+        if self.lower_bound(query, lo, hi, 0.0) > worst(found) {
+            return;
+        }
+
+        let mid = median(lo, hi);
+        let point = &self.points[mid];
+
+        if filter(point.id) {
+            let distance = vivaldi::node_distance(query, &point.coord);
+            if distance < worst(found) {
+                found.push(Candidate {
+                    distance,
+                    id: point.id,
+                });
+                if found.len() > k {
+                    found.pop();
+                }
+            }
+        }
+
+        let axis = depth % NUM_AXES;
+        let diff = coord_of(query, axis) - point.axis(axis);
+        let (near, far) = if diff < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+
+        self.search_nearest(query, k, filter, near.0, near.1, depth + 1, found);
+
+        if self.lower_bound(query, far.0, far.1, diff.abs()) <= worst(found) {
+            self.search_nearest(query, k, filter, far.0, far.1, depth + 1, found);
+        }
+    }
+
+    /// Find all points within given distance from query satisfying the filter.
+    /// Return pairs of distance and point identifier, closest first.
+    pub fn within_radius<F>(&self, query: &NodeCoordinates, radius: f32, filter: F) -> Vec<(f32, usize)>
+    where
+        F: Fn(usize) -> bool,
+    {
+        let mut found = Vec::new();
+        self.search_radius(query, radius, &filter, 0, self.points.len(), 0, &mut found);
+
+        found.sort();
+        found.into_iter().map(|c| (c.distance, c.id)).collect()
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    fn search_radius<F>(
+        &self,
+        query: &NodeCoordinates,
+        radius: f32,
+        filter: &F,
+        lo: usize,
+        hi: usize,
+        depth: usize,
+        found: &mut Vec<Candidate>,
+    ) where
+        F: Fn(usize) -> bool,
+    {
+        if lo >= hi || self.lower_bound(query, lo, hi, 0.0) > radius {
+            return;
+        }
+
+        let mid = median(lo, hi);
+        let point = &self.points[mid];
+
+        if filter(point.id) {
+            let distance = vivaldi::node_distance(query, &point.coord);
+            if distance <= radius {
+                found.push(Candidate {
+                    distance,
+                    id: point.id,
+                });
+            }
+        }
+
+        let axis = depth % NUM_AXES;
+        let diff = coord_of(query, axis) - point.axis(axis);
+        let (near, far) = if diff < 0.0 {
+            ((lo, mid), (mid + 1, hi))
+        } else {
+            ((mid + 1, hi), (lo, mid))
+        };
+
+        self.search_radius(query, radius, filter, near.0, near.1, depth + 1, found);
+
+        if self.lower_bound(query, far.0, far.1, diff.abs()) <= radius {
+            self.search_radius(query, radius, filter, far.0, far.1, depth + 1, found);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::{Isaac64Rng, Rng};
+
+    fn random_coordinates(rng: &mut Isaac64Rng, n: usize) -> Vec<NodeCoordinates> {
+        (0..n)
+            .map(|_| NodeCoordinates {
+                x1: rng.gen_range(-10.0, 10.0),
+                x2: rng.gen_range(-10.0, 10.0),
+                height: rng.gen_range(0.0, 1.0),
+                pos_err: 0.5,
+                iteration: 1,
+            })
+            .collect()
+    }
+
+    fn brute_force(points: &[NodeCoordinates], query: &NodeCoordinates) -> Vec<(f32, usize)> {
+        let mut all: Vec<(f32, usize)> = points
+            .iter()
+            .enumerate()
+            .map(|(id, p)| (vivaldi::node_distance(query, p), id))
+            .collect();
+        all.sort_by(|a, b| a.partial_cmp(b).unwrap());
+        all
+    }
+
+    #[test]
+    fn empty_index() {
+        let index = SpatialIndex::build(&[]);
+        let query = NodeCoordinates::empty();
+
+        assert!(index.is_empty());
+        assert_eq!(index.nearest(&query, 3, |_| true), vec![]);
+        assert_eq!(index.nearest(&query, usize::MAX, |_| true), vec![]);
+        assert_eq!(index.within_radius(&query, 100.0, |_| true), vec![]);
+    }
+
+    #[test]
+    fn nearest_matches_brute_force() {
+        let mut rng = Isaac64Rng::new_unseeded();
+        let points = random_coordinates(&mut rng, 500);
+        let index = SpatialIndex::build(&points);
+
+        for query in random_coordinates(&mut rng, 50) {
+            let expected: Vec<(f32, usize)> =
+                brute_force(&points, &query).into_iter().take(7).collect();
+            assert_eq!(index.nearest(&query, 7, |_| true), expected);
+        }
+    }
+
+    #[test]
+    fn radius_matches_brute_force() {
+        let mut rng = Isaac64Rng::new_unseeded();
+        let points = random_coordinates(&mut rng, 500);
+        let index = SpatialIndex::build(&points);
+
+        for query in random_coordinates(&mut rng, 50) {
+            let expected: Vec<(f32, usize)> = brute_force(&points, &query)
+                .into_iter()
+                .filter(|&(d, _)| d <= 4.0)
+                .collect();
+            assert_eq!(index.within_radius(&query, 4.0, |_| true), expected);
+        }
+    }
+
+    #[test]
+    fn nearest_filtered() {
+        let mut rng = Isaac64Rng::new_unseeded();
+        let points = random_coordinates(&mut rng, 200);
+        let index = SpatialIndex::build(&points);
+        let query = NodeCoordinates::empty();
+
+        let expected: Vec<(f32, usize)> = brute_force(&points, &query)
+            .into_iter()
+            .filter(|&(_, id)| id % 2 == 0)
+            .take(5)
+            .collect();
+        assert_eq!(index.nearest(&query, 5, |id| id % 2 == 0), expected);
+        assert_eq!(index.nearest(&query, usize::MAX, |_| true).len(), points.len());
+    }
+}
@@ -0,0 +1,238 @@
+//! Health and diagnostics of the node.
+//!
+//! Description of the node is set once on startup, the rest is
+//! updated by the transmitter, receiver and storage. Snapshot of
+//! the status is reported to interface clients by `get_status`.
+//!
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, Instant};
+
+use storage::StorageView;
+
+/// Probe without response for this time is considered lost
+const PROBE_TIMEOUT_SEC: u64 = 10;
+/// Limit number of probes tracked as in flight
+const MAX_PROBES_IN_FLIGHT: usize = 4096;
+/// Local position error of converged coordinates
+const CONVERGED_POS_ERR: f32 = 0.2;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum NodeKind {
+    Agent,
+    Landmark,
+}
+
+/// Verdict on coordinates of the local node
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Convergence {
+    // no probes answered yet
+    NotStarted,
+    Converging,
+    Converged,
+}
+
+impl Convergence {
+    fn of(pos_err: f32, iteration: u64) -> Self {
+        if pos_err <= CONVERGED_POS_ERR {
+            Convergence::Converged
+        } else if iteration == 0 {
+            Convergence::NotStarted
+        } else {
+            Convergence::Converging
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct NodeSetup {
+    kind: NodeKind,
+    udp_addr: SocketAddr,
+    landmark_addr: Option<SocketAddr>,
+}
+
+#[derive(Debug)]
+pub struct Status {
+    started_at: Instant,
+    setup: RwLock<Option<NodeSetup>>,
+    // zero if node doesn't send probes
+    probe_period_ms: AtomicU64,
+    landmark_answered_at: Mutex<Option<Instant>>,
+    location_updated_at: Mutex<Option<Instant>>,
+    in_flight: Mutex<HashMap<SocketAddr, Instant>>,
+}
+
+/// Status of the node at some moment, times are in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StatusReport {
+    pub version: String,
+    pub node_type: Option<NodeKind>,
+    pub uptime: u64,
+    pub udp_addr: Option<SocketAddr>,
+    pub landmark_addr: Option<SocketAddr>,
+    // time since the last response of landmark
+    pub landmark_answered: Option<u64>,
+    pub probe_period_ms: Option<u64>,
+    pub known_nodes: usize,
+    pub probes_in_flight: usize,
+    // time since the last update of local coordinates
+    pub location_updated: Option<u64>,
+    pub pos_err: f32,
+    pub convergence: Convergence,
+}
+
+impl Default for Status {
+    fn default() -> Self {
+        Status {
+            started_at: Instant::now(),
+            setup: RwLock::new(None),
+            probe_period_ms: AtomicU64::new(0),
+            landmark_answered_at: Mutex::new(None),
+            location_updated_at: Mutex::new(None),
+            in_flight: Mutex::new(HashMap::new()),
+        }
+    }
+}
+
+impl Status {
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// Describe the node on startup
+    pub fn set_node(&self, kind: NodeKind, udp_addr: SocketAddr, landmark_addr: Option<SocketAddr>) {
+        *self.setup.write().unwrap_or_else(PoisonError::into_inner) = Some(NodeSetup {
+            kind,
+            udp_addr,
+            landmark_addr,
+        });
+    }
+
+    pub fn set_probe_period(&self, period: Duration) {
+        self.probe_period_ms
+            .store(period.as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Probe request sent to peer
+    pub fn probe_sent(&self, peer: SocketAddr) {
+        let timeout = Duration::from_secs(PROBE_TIMEOUT_SEC);
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(PoisonError::into_inner);
+
+        if in_flight.len() >= MAX_PROBES_IN_FLIGHT {
+            in_flight.retain(|_, sent_at| sent_at.elapsed() < timeout);
+        }
+        if in_flight.len() < MAX_PROBES_IN_FLIGHT {
+            in_flight.insert(peer, Instant::now());
+        }
+    }
+
+    /// Response for the local probe received
+    pub fn response_received(&self, peer: SocketAddr) {
+        self.in_flight.lock().unwrap_or_else(PoisonError::into_inner).remove(&peer);
+
+        let landmark = self.setup
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .as_ref()
+            .and_then(|s| s.landmark_addr);
+        if landmark == Some(peer) {
+            *self.landmark_answered_at.lock().unwrap_or_else(PoisonError::into_inner) = Some(Instant::now());
+        }
+    }
+
+    /// Coordinates of the local node changed
+    pub fn location_updated(&self) {
+        *self.location_updated_at.lock().unwrap_or_else(PoisonError::into_inner) = Some(Instant::now());
+    }
+
+    fn probes_in_flight(&self) -> usize {
+        let timeout = Duration::from_secs(PROBE_TIMEOUT_SEC);
+        self.in_flight
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .filter(|sent_at| sent_at.elapsed() < timeout)
+            .count()
+    }
+}
+
+fn secs_since(t: Option<Instant>) -> Option<u64> {
+    t.map(|t| t.elapsed().as_secs())
+}
+
+/// Collect status of the storage owner
+pub fn report(store: &dyn StorageView) -> StatusReport {
+    let status = store.status();
+    let setup = status.setup.read().unwrap_or_else(PoisonError::into_inner).clone();
+    let location = store.get_location();
+    let probe_period_ms = status.probe_period_ms.load(Ordering::Relaxed);
+
+    StatusReport {
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        node_type: setup.as_ref().map(|s| s.kind),
+        uptime: status.started_at.elapsed().as_secs(),
+        udp_addr: setup.as_ref().map(|s| s.udp_addr),
+        landmark_addr: setup.as_ref().and_then(|s| s.landmark_addr),
+        landmark_answered: secs_since(*status.landmark_answered_at.lock().unwrap_or_else(PoisonError::into_inner)),
+        probe_period_ms: if probe_period_ms > 0 { Some(probe_period_ms) } else { None },
+        known_nodes: store.len(),
+        probes_in_flight: status.probes_in_flight(),
+        location_updated: secs_since(*status.location_updated_at.lock().unwrap_or_else(PoisonError::into_inner)),
+        pos_err: location.pos_err,
+        convergence: Convergence::of(location.pos_err, location.iteration),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use agent::NodeCoordinates;
+    use storage::Storage;
+
+    #[test]
+    fn probes_in_flight() {
+        let status = Status::new();
+        let landmark: SocketAddr = "10.0.0.1:3738".parse().unwrap();
+        let peer: SocketAddr = "10.0.0.2:3737".parse().unwrap();
+        status.set_node(NodeKind::Agent, "10.0.0.3:3737".parse().unwrap(), Some(landmark));
+
+        status.probe_sent(landmark);
+        status.probe_sent(peer);
+        assert_eq!(status.probes_in_flight(), 2);
+
+        status.response_received(peer);
+        assert_eq!(status.probes_in_flight(), 1);
+        assert!(status.landmark_answered_at.lock().unwrap().is_none());
+
+        status.response_received(landmark);
+        assert_eq!(status.probes_in_flight(), 0);
+        assert!(status.landmark_answered_at.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn convergence() {
+        let store = Storage::new();
+        assert_eq!(report(&store).convergence, Convergence::NotStarted);
+        assert_eq!(report(&store).location_updated, None);
+
+        store.set_location(NodeCoordinates {
+            pos_err: 0.5,
+            iteration: 3,
+            ..NodeCoordinates::empty()
+        });
+        let status = report(&store);
+        assert_eq!(status.convergence, Convergence::Converging);
+        assert_eq!(status.location_updated, Some(0));
+
+        store.set_location(NodeCoordinates {
+            pos_err: 0.0,
+            ..NodeCoordinates::empty()
+        });
+        assert_eq!(report(&store).convergence, Convergence::Converged);
+    }
+}
@@ -1,23 +1,58 @@
 /// Store and share all network coordinates info.
-
+///
+/// Node table is split into shards, each guarded by its own lock,
+/// so UDP processing mostly touches a single shard. Readers interested
+/// in the whole table (interface, gossip) use immutable snapshot,
+/// rebuilt lazily only after table modification. Spatial index of the
+/// snapshot is rebuilt at bounded rate and complemented by linear search
+/// over recently changed nodes.
+
+use std::cmp;
 use std::net::SocketAddr;
-use std::sync::{Arc, Mutex};
-use std::collections::HashSet;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet};
+use std::collections::hash_map::DefaultHasher;
 use std::hash::{Hash, Hasher};
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use rand::{seq, Isaac64Rng, Rng};
 
-use agent::{vivaldi, NodeCoordinates, NodeInfo, NodeList};
-
-pub type SharedStorage = Arc<Mutex<Storage>>;
+use agent::{vivaldi, NodeCoordinates, NodeInfo, NodeList, GOSSIP_MAX_NEIGHBOURS_IN_MSG};
+use metrics::Metrics;
+use status::Status;
+use spatial::SpatialIndex;
+
+pub type SharedStorage = Arc<Storage>;
+
+const NUM_SHARDS: usize = 16;
+// probe handling may use slightly outdated view of the table
+const GOSSIP_SNAPSHOT_MAX_AGE_MS: u64 = 1000;
+// spatial index is rebuilt at most once per period,
+// unless more than 1/N of nodes changed since the last build
+const INDEX_REBUILD_PERIOD_MS: u64 = 1000;
+const INDEX_MAX_CHANGED_FRACTION: usize = 8;
+
+/// Current UNIX time in seconds
+pub fn now_sec() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|t| t.as_secs())
+        .unwrap_or(0)
+}
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Node {
     pub info: NodeInfo,
     pub last_updated_sec: u64,
 }
 
+impl Node {
+    pub fn addr(&self) -> SocketAddr {
+        SocketAddr::new(self.info.ip, self.info.port)
+    }
+}
+
 impl Hash for Node {
     fn hash<H: Hasher>(&self, state: &mut H) {
         self.info.ip.hash(state);
@@ -33,24 +68,325 @@ impl PartialEq for Node {
 
 impl Eq for Node {}
 
+/// Spatial index over node table as it was at the moment of build
+#[derive(Debug, Default)]
+struct IndexedNodes {
+    built_at: Option<Instant>,
+    nodes: Vec<Node>,
+    ids: HashMap<SocketAddr, usize>,
+    index: SpatialIndex,
+}
+
+impl IndexedNodes {
+    fn new(nodes: Vec<Node>) -> Self {
+        IndexedNodes {
+            built_at: Some(Instant::now()),
+            index: SpatialIndex::build(nodes.iter().map(|n| &n.info.location)),
+            ids: nodes.iter().enumerate().map(|(id, n)| (n.addr(), id)).collect(),
+            nodes,
+        }
+    }
+
+    fn outdated(&self, num_changed: usize, num_nodes: usize) -> bool {
+        let period = Duration::from_millis(INDEX_REBUILD_PERIOD_MS);
+        num_changed > 0
+            && (self.built_at.is_none_or(|t| t.elapsed() >= period)
+                || num_changed * INDEX_MAX_CHANGED_FRACTION > num_nodes)
+    }
+}
+
+/// Immutable copy of the node table.
+///
+/// Shards are copied one by one, so modifications made meanwhile may be
+/// seen in some shards only. Version is read before copying, hence such
+/// snapshot is considered outdated and rebuilt on the next request.
+///
+/// Spatial index is shared by subsequent snapshots and rebuilt at bounded
+/// rate, nodes changed since its build are searched linearly.
+#[derive(Debug)]
+pub struct Snapshot {
+    version: usize,
+    built_at: Option<Instant>,
+    pub nodes: Vec<Node>,
+    indexed: Arc<IndexedNodes>,
+    // position in `nodes` of every indexed node left unchanged
+    unchanged: Vec<Option<usize>>,
+    // positions in `nodes` of nodes added or changed since index build
+    changed: Vec<usize>,
+}
+
+impl Snapshot {
+    fn empty() -> Self {
+        Snapshot {
+            version: 0,
+            built_at: None,
+            nodes: Vec::new(),
+            indexed: Arc::new(IndexedNodes::default()),
+            unchanged: Vec::new(),
+            changed: Vec::new(),
+        }
+    }
+
+    fn new(version: usize, nodes: Vec<Node>, indexed: &Arc<IndexedNodes>) -> Self {
+        let mut unchanged = vec![None; indexed.nodes.len()];
+        let mut changed = Vec::new();
+
+        for (pos, node) in nodes.iter().enumerate() {
+            match indexed.ids.get(&node.addr()) {
+                Some(&id)
+                    if indexed.nodes[id].info == node.info
+                        && indexed.nodes[id].last_updated_sec == node.last_updated_sec =>
+                {
+                    unchanged[id] = Some(pos)
+                }
+                _ => changed.push(pos),
+            }
+        }
+
+        if !indexed.outdated(changed.len(), nodes.len()) {
+            return Snapshot {
+                version,
+                built_at: Some(Instant::now()),
+                nodes,
+                indexed: indexed.clone(),
+                unchanged,
+                changed,
+            };
+        }
+
+        Snapshot {
+            version,
+            built_at: Some(Instant::now()),
+            unchanged: (0..nodes.len()).map(Some).collect(),
+            changed: Vec::new(),
+            indexed: Arc::new(IndexedNodes::new(nodes.clone())),
+            nodes,
+        }
+    }
+
+    /// Find node by its network address
+    pub fn find_node(&self, addr: SocketAddr) -> Option<&Node> {
+        let unchanged = self.indexed.ids.get(&addr).and_then(|&id| self.unchanged[id]);
+        match unchanged {
+            Some(pos) => Some(&self.nodes[pos]),
+            None => self.changed.iter().map(|&pos| &self.nodes[pos]).find(|n| n.addr() == addr),
+        }
+    }
+
+    /// Return 'max_nodes' most recently updated nodes, sorted by last update time.
+    pub fn most_recent(&self, max_nodes: usize) -> Option<NodeList> {
+        if self.nodes.is_empty() {
+            return None;
+        }
+
+        let mut nptr: Vec<&Node> = self.nodes.iter().collect();
+        nptr.sort_by_key(|n| cmp::Reverse(n.last_updated_sec));
+        Some(
+            nptr.iter()
+                .map(|&n| n.info.clone())
+                .take(max_nodes)
+                .collect(),
+        )
+    }
+
+    /// Changed nodes satisfying the filter with their distance from the point
+    fn changed_nodes<F>(&self, point: &NodeCoordinates, filter: &F) -> Vec<(f32, &Node)>
+    where
+        F: Fn(&Node) -> bool,
+    {
+        self.changed
+            .iter()
+            .map(|&pos| &self.nodes[pos])
+            .filter(|n| filter(n))
+            .map(|n| (vivaldi::node_distance(point, &n.info.location), n))
+            .collect()
+    }
+
+    /// Find `k` nodes nearest to the point among those satisfying the filter.
+    /// Return nodes with estimated distance, closest first.
+    /// Nodes which have not computed coordinates yet are never found.
+    pub fn nearest<F>(&self, point: &NodeCoordinates, k: usize, filter: F) -> Vec<(f32, &Node)>
+    where
+        F: Fn(&Node) -> bool,
+    {
+        let filter = |n: &Node| positioned(n) && filter(n);
+        let current = |id: usize| self.unchanged[id].map(|pos| &self.nodes[pos]);
+
+        let mut found: Vec<(f32, &Node)> = self
+            .indexed
+            .index
+            .nearest(point, k, |id| current(id).is_some_and(&filter))
+            .into_iter()
+            .filter_map(|(d, id)| current(id).map(|n| (d, n)))
+            .chain(self.changed_nodes(point, &filter))
+            .collect();
+
+        sort_by_distance(&mut found);
+        found.truncate(k);
+        found
+    }
+
+    /// Find all nodes within given distance from the point satisfying the filter.
+    /// Return nodes with estimated distance, closest first.
+    /// Nodes which have not computed coordinates yet are never found.
+    pub fn within_radius<F>(&self, point: &NodeCoordinates, radius: f32, filter: F) -> Vec<(f32, &Node)>
+    where
+        F: Fn(&Node) -> bool,
+    {
+        let filter = |n: &Node| positioned(n) && filter(n);
+        let current = |id: usize| self.unchanged[id].map(|pos| &self.nodes[pos]);
+
+        let mut found: Vec<(f32, &Node)> = self
+            .indexed
+            .index
+            .within_radius(point, radius, |id| current(id).is_some_and(&filter))
+            .into_iter()
+            .filter_map(|(d, id)| current(id).map(|n| (d, n)))
+            .chain(self.changed_nodes(point, &filter).into_iter().filter(|&(d, _)| d <= radius))
+            .collect();
+
+        sort_by_distance(&mut found);
+        found
+    }
+}
+
+/// Whether node has computed its coordinates, e.g. it's not just added by address
+pub fn positioned(node: &Node) -> bool {
+    node.info.location.iteration > 0
+}
+
+// address makes order of equally distant nodes stable
+fn sort_by_distance(found: &mut [(f32, &Node)]) {
+    found.sort_by(|a, b| {
+        a.0.partial_cmp(&b.0)
+            .unwrap_or(cmp::Ordering::Equal)
+            .then_with(|| a.1.addr().cmp(&b.1.addr()))
+    });
+}
+
+/// Ordered subset of snapshot nodes, e.g. result of filtering
+#[derive(Debug)]
+pub struct Selection {
+    snapshot: Arc<Snapshot>,
+    indices: Vec<usize>,
+}
+
+impl Selection {
+    pub fn new(snapshot: Arc<Snapshot>, indices: Vec<usize>) -> Self {
+        Selection { snapshot, indices }
+    }
+
+    pub fn len(&self) -> usize {
+        self.indices.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.indices.is_empty()
+    }
+
+    /// Nodes of selection starting from `offset`
+    pub fn iter_from(&self, offset: usize) -> impl Iterator<Item = &Node> {
+        self.indices
+            .iter()
+            .skip(offset)
+            .map(move |&i| &self.snapshot.nodes[i])
+    }
+}
+
 pub struct Storage {
-    location: NodeCoordinates,
-    nodes: HashSet<Node>,
-    rng: Isaac64Rng,
+    location: RwLock<NodeCoordinates>,
+    shards: Vec<RwLock<HashSet<Node>>>,
+    snapshot: RwLock<Arc<Snapshot>>,
+    // incremented on every table modification
+    version: AtomicUsize,
+    rng: Mutex<Isaac64Rng>,
+    vivaldi: RwLock<vivaldi::Params>,
+    // number of neighbours sent in probes
+    gossip_size: AtomicUsize,
+    // shared with frozen copies
+    metrics: Arc<Metrics>,
+    status: Arc<Status>,
+}
+
+impl Default for Storage {
+    fn default() -> Self {
+        Storage::new()
+    }
 }
 
 impl Storage {
     /// Create empty storage
     pub fn new() -> Self {
         Storage {
-            location: NodeCoordinates::empty(),
-            nodes: HashSet::new(),
-            rng: Isaac64Rng::new_unseeded(),
+            location: RwLock::new(NodeCoordinates::empty()),
+            shards: (0..NUM_SHARDS).map(|_| RwLock::new(HashSet::new())).collect(),
+            snapshot: RwLock::new(Arc::new(Snapshot::empty())),
+            version: AtomicUsize::new(0),
+            rng: Mutex::new(Isaac64Rng::new_unseeded()),
+            vivaldi: RwLock::new(vivaldi::Params::default()),
+            gossip_size: AtomicUsize::new(GOSSIP_MAX_NEIGHBOURS_IN_MSG),
+            metrics: Arc::new(Metrics::new()),
+            status: Arc::new(Status::new()),
         }
     }
 
+    pub fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    pub fn vivaldi_params(&self) -> vivaldi::Params {
+        *self.read(&self.vivaldi)
+    }
+
+    pub fn set_vivaldi_params(&self, params: vivaldi::Params) {
+        *self.write(&self.vivaldi) = params;
+    }
+
+    /// Number of random neighbours to share in probe messages
+    pub fn gossip_size(&self) -> usize {
+        self.gossip_size.load(Ordering::Relaxed)
+    }
+
+    pub fn set_gossip_size(&self, size: usize) {
+        self.gossip_size.store(size, Ordering::Relaxed);
+    }
+
+    // lock acquisition accounting time spent waiting for it,
+    // poisoned lock is taken over as records are never left half-updated
+
+    fn read<'a, T>(&self, lock: &'a RwLock<T>) -> RwLockReadGuard<'a, T> {
+        let started = Instant::now();
+        let guard = lock.read().unwrap_or_else(PoisonError::into_inner);
+        self.metrics.lock_waited(started.elapsed());
+        guard
+    }
+
+    fn write<'a, T>(&self, lock: &'a RwLock<T>) -> RwLockWriteGuard<'a, T> {
+        let started = Instant::now();
+        let guard = lock.write().unwrap_or_else(PoisonError::into_inner);
+        self.metrics.lock_waited(started.elapsed());
+        guard
+    }
+
+    fn lock<'a, T>(&self, lock: &'a Mutex<T>) -> MutexGuard<'a, T> {
+        let started = Instant::now();
+        let guard = lock.lock().unwrap_or_else(PoisonError::into_inner);
+        self.metrics.lock_waited(started.elapsed());
+        guard
+    }
+
+    fn shard(&self, node: &Node) -> &RwLock<HashSet<Node>> {
+        let mut hasher = DefaultHasher::new();
+        node.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % NUM_SHARDS]
+    }
+
     /// Add new or replace existing node's information
-    pub fn add_node(&mut self, info: NodeInfo) {
+    pub fn add_node(&self, info: NodeInfo) {
         // skip bad node info
         // add info.ip.is_documentation() check after stabilization
         if info.ip.is_unspecified() || info.ip.is_multicast() {
@@ -62,34 +398,137 @@ impl Storage {
 
         let record = Node {
             info,
-            last_updated_sec: SystemTime::now() // set to current
-                .duration_since(UNIX_EPOCH)
-                .map(|t| t.as_secs())
-                .unwrap_or(0),
+            last_updated_sec: now_sec(), // set to current
         };
 
+        let mut shard = self.write(self.shard(&record));
+
         // do not store stalled location info
-        if let Some(saved) = self.nodes.get(&record) {
+        if let Some(saved) = shard.get(&record) {
             if saved.info.location.iteration >= loc_iteration {
                 return;
             }
         }
 
-        self.nodes.replace(record);
+        shard.replace(record);
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Put node record as is, keeping its update time.
+    /// Used to restore previously saved state.
+    pub fn insert_node(&self, node: Node) {
+        self.write(self.shard(&node)).replace(node);
+        self.version.fetch_add(1, Ordering::Release);
+    }
+
+    /// Read-only view of the current node table and location sharing
+    /// the current snapshot, nodes are not copied. Used to answer several
+    /// requests from the same state.
+    pub fn freeze(&self) -> FrozenStorage {
+        let snapshot = self.snapshot();
+        // as close to the moment of snapshot as possible
+        let location = self.get_location();
+
+        FrozenStorage {
+            snapshot,
+            location,
+            metrics: self.metrics.clone(),
+            status: self.status.clone(),
+        }
+    }
+
+    /// Forget node, return false if it was not known
+    pub fn remove_node(&self, addr: SocketAddr) -> bool {
+        let record = Node {
+            info: NodeInfo::new(addr.ip(), addr.port(), String::new()),
+            last_updated_sec: 0,
+        };
+
+        let removed = self.write(self.shard(&record)).remove(&record);
+        if removed {
+            self.version.fetch_add(1, Ordering::Release);
+        }
+        removed
+    }
+
+    /// Return snapshot of the current node table.
+    ///
+    /// Snapshot is rebuilt only if table was modified since the last call,
+    /// shards are copied one by one, so writers never wait for the whole table.
+    pub fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot_not_older(Duration::new(0, 0))
+    }
+
+    /// Return snapshot, reusing outdated one if it was built less than `max_age` ago.
+    fn snapshot_not_older(&self, max_age: Duration) -> Arc<Snapshot> {
+        let version = self.version.load(Ordering::Acquire);
+        {
+            let current = self.read(&self.snapshot);
+            let recent = current.built_at.is_some_and(|t| t.elapsed() < max_age);
+            if current.version == version || recent {
+                return current.clone();
+            }
+        }
+
+        let mut nodes = Vec::new();
+        for shard in &self.shards {
+            nodes.extend(self.read(shard).iter().cloned());
+        }
+
+        let indexed = self.read(&self.snapshot).indexed.clone();
+        let updated = Arc::new(Snapshot::new(version, nodes, &indexed));
+        let mut current = self.write(&self.snapshot);
+        // concurrent rebuild may have stored a newer snapshot already
+        if current.version >= updated.version {
+            return current.clone();
+        }
+        *current = updated.clone();
+        updated
+    }
+
+    fn gossip_snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot_not_older(Duration::from_millis(GOSSIP_SNAPSHOT_MAX_AGE_MS))
+    }
+
+    /// Find `k` nodes nearest to the point, closest first.
+    pub fn nearest(&self, point: &NodeCoordinates, k: usize) -> Vec<(f32, Node)> {
+        self.snapshot()
+            .nearest(point, k, |_| true)
+            .into_iter()
+            .map(|(d, n)| (d, n.clone()))
+            .collect()
+    }
+
+    /// Find all nodes within given distance from the point, closest first.
+    pub fn within_radius(&self, point: &NodeCoordinates, radius: f32) -> Vec<(f32, Node)> {
+        self.snapshot()
+            .within_radius(point, radius, |_| true)
+            .into_iter()
+            .map(|(d, n)| (d, n.clone()))
+            .collect()
+    }
+
+    /// Number of currently known nodes
+    pub fn len(&self) -> usize {
+        self.shards
+            .iter()
+            .map(|s| self.read(s).len())
+            .sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
     }
 
     /// Try to find random node address in the storage.
     /// Takes additional address to be added to the list of variants.
-    pub fn random_receiver(
-        &mut self,
-        additional: &SocketAddr,
-    ) -> SocketAddr {
-        let idx: usize = self.rng.gen_range(0, self.nodes.len() + 1);
-        match self.nodes.iter().nth(idx) {
-            Some(node) => {
-                SocketAddr::new(node.info.ip, node.info.port)
-            }
-            None => additional.clone(),
+    pub fn random_receiver(&self, additional: &SocketAddr) -> SocketAddr {
+        let snapshot = self.gossip_snapshot();
+        let idx: usize = self.lock(&self.rng).gen_range(0, snapshot.nodes.len() + 1);
+
+        match snapshot.nodes.get(idx) {
+            Some(node) => node.addr(),
+            None => *additional,
         }
     }
 
@@ -98,38 +537,31 @@ impl Storage {
     /// If number of nodes found in the storage is N | N < max_nodes,
     /// than N informational records will be returned.
     /// Return None if result list is empty.
-    pub fn get_random_nodes(
-        &mut self,
-        max_nodes: usize,
-        ignore: &[SocketAddr],
-    ) -> Option<Vec<&NodeInfo>> {
+    pub fn get_random_nodes(&self, max_nodes: usize, ignore: &[SocketAddr]) -> Option<NodeList> {
         // do not even start on bad conditions
-        if self.nodes.is_empty() || max_nodes < 1 {
+        if max_nodes < 1 {
             return None;
         }
 
-        // pointers to all known nodes besides ignored ones
-        let nptr: Vec<&Node> = self.nodes
-            .iter()
-            .filter(|&n| {
-                !ignore.contains(&SocketAddr::new(n.info.ip, n.info.port))
-            })
-            .collect();
+        let snapshot = self.gossip_snapshot();
 
-        let num_values_to_return: usize = if max_nodes < nptr.len() {
-            max_nodes
-        } else {
-            nptr.len()
+        // take enough random nodes to skip all the ignored ones
+        let num_samples = cmp::min(max_nodes + ignore.len(), snapshot.nodes.len());
+        let indices = {
+            let mut rng = self.lock(&self.rng);
+            seq::sample_indices(&mut *rng, snapshot.nodes.len(), num_samples)
         };
 
-        // select random nodes
-        let random_neighbours: Vec<&NodeInfo> =
-            seq::sample_slice_ref(&mut self.rng, &nptr, num_values_to_return)
-                .iter()
-                .map(|&&v| &v.info)
-                .collect();
+        // select random nodes besides ignored ones
+        let random_neighbours: NodeList = indices
+            .into_iter()
+            .map(|i| &snapshot.nodes[i])
+            .filter(|n| !ignore.contains(&n.addr()))
+            .take(max_nodes)
+            .map(|n| n.info.clone())
+            .collect();
 
-        if random_neighbours.len() > 0 {
+        if !random_neighbours.is_empty() {
             Some(random_neighbours)
         } else {
             None
@@ -137,30 +569,13 @@ impl Storage {
     }
 
     /// Return 'max_nodes' most recently updated nodes, sorted by last update time.
-    pub fn get_most_recent(&self, max_nodes: usize) -> Option<Vec<&NodeInfo>> {
-        if self.nodes.is_empty() {
-            return None;
-        }
-
-        let num_values_to_return: usize = if max_nodes < self.nodes.len() {
-            max_nodes
-        } else {
-            self.nodes.len()
-        };
-
-        let mut nptr: Vec<&Node> = self.nodes.iter().collect();
-        nptr.sort_by(|&a, &b| b.last_updated_sec.cmp(&a.last_updated_sec));
-        Some(
-            nptr.iter()
-                .map(|&n| &n.info)
-                .take(num_values_to_return)
-                .collect(),
-        )
+    pub fn get_most_recent(&self, max_nodes: usize) -> Option<NodeList> {
+        self.snapshot().most_recent(max_nodes)
     }
 
     /// Return local node's full view.
     pub fn get_all_nodes(&self) -> NodeList {
-        self.nodes.iter().map(|n| n.info.clone()).collect()
+        self.snapshot().nodes.iter().map(|n| n.info.clone()).collect()
     }
 
     /// Try to find stored information about node based on its network address
@@ -171,28 +586,116 @@ impl Storage {
             last_updated_sec: 0,
         };
 
-        let node = self.nodes.get(&record)?;
-        Some(node.clone())
+        let shard = self.read(self.shard(&record));
+        shard.get(&record).cloned()
     }
 
     /// Return position of local node in RTT-based coordinate space
     pub fn get_location(&self) -> NodeCoordinates {
-        self.location.clone()
+        self.read(&self.location).clone()
     }
 
     /// Update location parameters of local node
-    pub fn set_location(&mut self, location: NodeCoordinates) {
-        self.location = location;
+    pub fn set_location(&self, location: NodeCoordinates) {
+        *self.write(&self.location) = location;
+        self.status.location_updated();
     }
 
-    pub fn update_location(&mut self, received_location: &NodeCoordinates, rtt: Duration) {
+    pub fn update_location(&self, received_location: &NodeCoordinates, rtt: Duration) {
         let rtt_sec = (rtt.as_secs() as f64 + (rtt.subsec_nanos() as f64 / 1_000_000_000.0)) as f32;
 
+        let params = self.vivaldi_params();
+        let mut location = self.write(&self.location);
+        let mut rng = self.lock(&self.rng);
+
         // recompute location
-        let updated_location =
-            vivaldi::compute_location(&self.location, received_location, rtt_sec, &mut self.rng);
+        *location = vivaldi::compute_location(&location, received_location, rtt_sec, &params, &mut *rng);
+        self.status.location_updated();
+    }
+}
+
+/// Read access needed to answer interface requests,
+/// either to live storage or to its frozen copy
+pub trait StorageView {
+    fn metrics(&self) -> &Metrics;
+
+    fn status(&self) -> &Status;
+
+    /// Position of local node
+    fn get_location(&self) -> NodeCoordinates;
+
+    /// Node table to search
+    fn snapshot(&self) -> Arc<Snapshot>;
 
-        self.location = updated_location;
+    /// Stored information about node by its network address
+    fn find_node(&self, addr: SocketAddr) -> Option<Node>;
+
+    /// Number of known nodes
+    fn len(&self) -> usize;
+
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl StorageView for Storage {
+    fn metrics(&self) -> &Metrics {
+        Storage::metrics(self)
+    }
+
+    fn status(&self) -> &Status {
+        Storage::status(self)
+    }
+
+    fn get_location(&self) -> NodeCoordinates {
+        Storage::get_location(self)
+    }
+
+    fn snapshot(&self) -> Arc<Snapshot> {
+        Storage::snapshot(self)
+    }
+
+    fn find_node(&self, addr: SocketAddr) -> Option<Node> {
+        Storage::find_node(self, addr)
+    }
+
+    fn len(&self) -> usize {
+        Storage::len(self)
+    }
+}
+
+/// Node table and location of local node taken at the same moment,
+/// see `Storage::freeze`
+pub struct FrozenStorage {
+    snapshot: Arc<Snapshot>,
+    location: NodeCoordinates,
+    metrics: Arc<Metrics>,
+    status: Arc<Status>,
+}
+
+impl StorageView for FrozenStorage {
+    fn metrics(&self) -> &Metrics {
+        &self.metrics
+    }
+
+    fn status(&self) -> &Status {
+        &self.status
+    }
+
+    fn get_location(&self) -> NodeCoordinates {
+        self.location.clone()
+    }
+
+    fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.clone()
+    }
+
+    fn find_node(&self, addr: SocketAddr) -> Option<Node> {
+        self.snapshot.find_node(addr).cloned()
+    }
+
+    fn len(&self) -> usize {
+        self.snapshot.nodes.len()
     }
 }
 
@@ -206,7 +709,7 @@ mod tests {
 
     #[test]
     fn empty_storage() {
-        let mut s = Storage::new();
+        let s = Storage::new();
 
         assert_eq!(s.get_random_nodes(0, &[]), None);
         assert_eq!(s.get_random_nodes(1, &[]), None);
@@ -215,7 +718,7 @@ mod tests {
 
     #[test]
     fn single_entry() {
-        let mut s = Storage::new();
+        let s = Storage::new();
         let node_ipv4 = NodeInfo::new(
             IpAddr::from_str("1.2.3.4").unwrap(),
             11001,
@@ -224,11 +727,7 @@ mod tests {
 
         s.add_node(node_ipv4.clone());
 
-        let res: Vec<NodeInfo> = s.get_random_nodes(2, &[])
-            .unwrap()
-            .iter()
-            .map(|&n| n.clone())
-            .collect();
+        let res: Vec<NodeInfo> = s.get_random_nodes(2, &[]).unwrap();
 
         assert_eq!(res, vec![node_ipv4]);
         assert_eq!(s.get_all_nodes().len(), 1);
@@ -236,7 +735,7 @@ mod tests {
 
     #[test]
     fn ignored_address() {
-        let mut s = Storage::new();
+        let s = Storage::new();
         let node_ipv4 = NodeInfo::new(
             IpAddr::from_str("1.2.3.4").unwrap(),
             11001,
@@ -254,7 +753,7 @@ mod tests {
 
     #[test]
     fn more_than_one_entry() {
-        let mut s = Storage::new();
+        let s = Storage::new();
         let node_ipv4 = NodeInfo::new(
             IpAddr::from_str("1.2.3.4").unwrap(),
             11001,
@@ -278,7 +777,7 @@ mod tests {
     #[test]
     #[ignore]
     fn recently_updated() {
-        let mut s = Storage::new();
+        let s = Storage::new();
         assert_eq!(s.get_most_recent(0), None);
 
         let node_1 = NodeInfo::new(
@@ -296,13 +795,13 @@ mod tests {
         thread::sleep_ms(1000); // time resolution is 1 sec
         s.add_node(node_2.clone());
 
-        assert_eq!(s.get_most_recent(1).unwrap()[0], &node_2);
-        assert_eq!(s.get_most_recent(2).unwrap(), vec![&node_2, &node_1]);
+        assert_eq!(s.get_most_recent(1).unwrap()[0], node_2);
+        assert_eq!(s.get_most_recent(2).unwrap(), vec![node_2, node_1]);
     }
 
     #[test]
     fn node_location() {
-        let mut s = Storage::new();
+        let s = Storage::new();
         let coord = NodeCoordinates {
             x1: 12.45,
             x2: 76.001,
@@ -314,4 +813,129 @@ mod tests {
         s.set_location(coord.clone());
         assert_eq!(s.get_location(), coord);
     }
+
+    #[test]
+    fn snapshot_refreshed_on_update() {
+        let s = Storage::new();
+        let node = NodeInfo::new(
+            IpAddr::from_str("1.2.3.4").unwrap(),
+            11001,
+            "test_node_v4".to_string(),
+        );
+
+        let before = s.snapshot();
+        assert!(Arc::ptr_eq(&before, &s.snapshot()));
+
+        s.add_node(node.clone());
+        let after = s.snapshot();
+        assert_eq!(before.nodes.len(), 0);
+        assert_eq!(after.nodes.len(), 1);
+
+        // stalled info doesn't change the table
+        s.add_node(node);
+        assert!(Arc::ptr_eq(&after, &s.snapshot()));
+    }
+
+    #[test]
+    fn frozen_copy() {
+        let s = Storage::new();
+        s.add_node(NodeInfo::new(IpAddr::from_str("1.2.3.4").unwrap(), 11001, String::new()));
+        let location = NodeCoordinates {
+            x1: 1.0,
+            ..NodeCoordinates::empty()
+        };
+        s.set_location(location.clone());
+
+        let frozen = s.freeze();
+        s.add_node(NodeInfo::new(IpAddr::from_str("1.2.3.5").unwrap(), 11001, String::new()));
+        s.set_location(NodeCoordinates::empty());
+
+        assert_eq!(frozen.snapshot().nodes.len(), 1);
+        assert_eq!(frozen.len(), 1);
+        assert_eq!(frozen.get_location(), location);
+        assert!(frozen
+            .find_node(SocketAddr::from_str("1.2.3.4:11001").unwrap())
+            .is_some());
+        assert!(frozen
+            .find_node(SocketAddr::from_str("1.2.3.5:11001").unwrap())
+            .is_none());
+        assert_eq!(s.get_all_nodes().len(), 2);
+    }
+
+    #[test]
+    fn removed_node() {
+        let s = Storage::new();
+        let info = NodeInfo::new(IpAddr::from_str("1.2.3.4").unwrap(), 11001, String::new());
+        let addr = SocketAddr::new(info.ip, info.port);
+        s.add_node(info);
+        assert_eq!(s.get_all_nodes().len(), 1);
+
+        assert!(s.remove_node(addr));
+        assert!(!s.remove_node(addr));
+        assert!(s.find_node(addr).is_none());
+        assert!(s.get_all_nodes().is_empty());
+    }
+
+    #[test]
+    fn nearest_nodes_follow_updates() {
+        let s = Storage::new();
+        let origin = NodeCoordinates::empty();
+
+        for i in 1..6 {
+            let mut node = NodeInfo::new(IpAddr::from_str("1.2.3.4").unwrap(), i, String::new());
+            node.location.x1 = i as f32;
+            node.location.iteration = 1;
+            s.add_node(node);
+        }
+
+        let ports = |found: Vec<(f32, Node)>| -> Vec<u16> {
+            found.iter().map(|(_, n)| n.info.port).collect()
+        };
+        assert_eq!(ports(s.nearest(&origin, 2)), vec![1, 2]);
+        assert_eq!(ports(s.within_radius(&origin, 3.5)), vec![1, 2, 3]);
+
+        // move the farthest node to the origin
+        let mut moved = NodeInfo::new(IpAddr::from_str("1.2.3.4").unwrap(), 5, String::new());
+        moved.location.iteration = 2;
+        s.add_node(moved);
+        assert_eq!(ports(s.nearest(&origin, 2)), vec![5, 1]);
+    }
+
+    #[test]
+    fn index_reused_for_few_changes() {
+        let s = Storage::new();
+        let origin = NodeCoordinates::empty();
+        let node = |port: u16, x1: f32, iteration: u64| {
+            let mut node = NodeInfo::new(IpAddr::from_str("1.2.3.4").unwrap(), port, String::new());
+            node.location.x1 = x1;
+            node.location.iteration = iteration;
+            node
+        };
+
+        for i in 1..101 {
+            s.add_node(node(i, f32::from(i), 1));
+        }
+        let before = s.snapshot();
+
+        // move one node to the origin, remove the nearest one and add new
+        s.add_node(node(50, 0.0, 2));
+        s.remove_node(SocketAddr::from_str("1.2.3.4:1").unwrap());
+        s.add_node(node(200, 2.5, 1));
+        let after = s.snapshot();
+        assert!(Arc::ptr_eq(&before.indexed, &after.indexed));
+
+        let ports: Vec<u16> = after
+            .nearest(&origin, 3, |_| true)
+            .iter()
+            .map(|(_, n)| n.info.port)
+            .collect();
+        assert_eq!(ports, vec![50, 2, 200]);
+        assert_eq!(after.within_radius(&origin, 2.6, |_| true).len(), 3);
+
+        let find = |addr: &str| after.find_node(SocketAddr::from_str(addr).unwrap());
+        assert_eq!(find("1.2.3.4:50").map(|n| n.info.location.iteration), Some(2));
+        assert!(find("1.2.3.4:1").is_none());
+        assert!(find("1.2.3.4:2").is_some());
+        assert!(find("1.2.3.4:200").is_some());
+    }
 }